@@ -0,0 +1,207 @@
+//!
+//! # 状态/错误码
+//! 连接服务下发给设备的`error`帧与http接口的错误响应共用同一套错误码
+//!
+//! | 错误码 | 原因 | 说明 |
+//! | :----| :---- | :---- |
+//! | 1001 | invalid_json | 帧不是合法的JSON |
+//! | 1002 | unknown_type | 未知或不应由该方发送的消息类型 |
+//! | 1003 | malformed_message | 消息缺少必要字段或字段格式错误 |
+//! | 1004 | invalid_unit_status | 端单元status不合法 |
+//! | 1005 | sn_rejected | SN校验失败 |
+//! | 1006 | rate_limited | 发送频率超限 |
+//! | 1007 | unsupported_version | 不支持的协议版本 |
+//! | 1008 | frame_too_large | 帧长度超出上限，该帧被丢弃 |
+//! | 1009 | invalid_utf8 | 帧不是合法的utf-8，该帧被丢弃 |
+//! | 1010 | invalid_payload | 二进制帧无法解压或解码，该帧被丢弃 |
+//! | 1011 | encryption_required | 该产品必须使用安全层 |
+//! | 1012 | decrypt_failed | 加密帧解密失败或序号重复，该帧被丢弃 |
+//! | 1013 | auth_failed | 设备认证失败 |
+//! | 1014 | session_replaced | 同一sn在新的连接上线，旧连接被关闭 |
+//! | 1015 | server_shutdown | 服务端关闭，设备应稍后重连 |
+//! | 2001 | device_offline | 设备离线 |
+//! | 2002 | no_response | 设备无响应 |
+//! | 2003 | queue_full | 设备下行队列已满 |
+//! | 2004 | ack_mismatch | 设备响应与请求不对应 |
+//! | 2005 | missing_sn | 请求没有sn字段 |
+//! | 2006 | payload_too_large | 请求数据太大 |
+//! | 2007 | invalid_encoding | 请求数据不是utf-8 |
+//! | 2008 | not_found | 查询的记录不存在 |
+//! | 2009 | internal_error | 服务内部错误，如redis不可用 |
+//! | 2010 | invalid_registration | 出厂登记数据不合法 |
+//!
+//! 1xxx为设备与服务端之间的协议错误，2xxx为用户服务请求的错误
+//!
+
+use std::fmt;
+
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    InvalidJson,
+    UnknownType,
+    MalformedMessage,
+    InvalidUnitStatus,
+    SnRejected,
+    RateLimited,
+    UnsupportedVersion,
+    FrameTooLarge,
+    InvalidUtf8,
+    InvalidPayload,
+    EncryptionRequired,
+    DecryptFailed,
+    AuthFailed,
+    SessionReplaced,
+    ServerShutdown,
+    DeviceOffline,
+    NoResponse,
+    QueueFull,
+    AckMismatch,
+    MissingSn,
+    PayloadTooLarge,
+    InvalidEncoding,
+    NotFound,
+    InternalError,
+    InvalidRegistration,
+}
+
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 25] = [
+        ErrorCode::InvalidJson,
+        ErrorCode::UnknownType,
+        ErrorCode::MalformedMessage,
+        ErrorCode::InvalidUnitStatus,
+        ErrorCode::SnRejected,
+        ErrorCode::RateLimited,
+        ErrorCode::UnsupportedVersion,
+        ErrorCode::FrameTooLarge,
+        ErrorCode::InvalidUtf8,
+        ErrorCode::InvalidPayload,
+        ErrorCode::EncryptionRequired,
+        ErrorCode::DecryptFailed,
+        ErrorCode::AuthFailed,
+        ErrorCode::SessionReplaced,
+        ErrorCode::ServerShutdown,
+        ErrorCode::DeviceOffline,
+        ErrorCode::NoResponse,
+        ErrorCode::QueueFull,
+        ErrorCode::AckMismatch,
+        ErrorCode::MissingSn,
+        ErrorCode::PayloadTooLarge,
+        ErrorCode::InvalidEncoding,
+        ErrorCode::NotFound,
+        ErrorCode::InternalError,
+        ErrorCode::InvalidRegistration,
+    ];
+
+    pub fn code(&self) -> u32 {
+        match self {
+            ErrorCode::InvalidJson => 1001,
+            ErrorCode::UnknownType => 1002,
+            ErrorCode::MalformedMessage => 1003,
+            ErrorCode::InvalidUnitStatus => 1004,
+            ErrorCode::SnRejected => 1005,
+            ErrorCode::RateLimited => 1006,
+            ErrorCode::UnsupportedVersion => 1007,
+            ErrorCode::FrameTooLarge => 1008,
+            ErrorCode::InvalidUtf8 => 1009,
+            ErrorCode::InvalidPayload => 1010,
+            ErrorCode::EncryptionRequired => 1011,
+            ErrorCode::DecryptFailed => 1012,
+            ErrorCode::AuthFailed => 1013,
+            ErrorCode::SessionReplaced => 1014,
+            ErrorCode::ServerShutdown => 1015,
+            ErrorCode::DeviceOffline => 2001,
+            ErrorCode::NoResponse => 2002,
+            ErrorCode::QueueFull => 2003,
+            ErrorCode::AckMismatch => 2004,
+            ErrorCode::MissingSn => 2005,
+            ErrorCode::PayloadTooLarge => 2006,
+            ErrorCode::InvalidEncoding => 2007,
+            ErrorCode::NotFound => 2008,
+            ErrorCode::InternalError => 2009,
+            ErrorCode::InvalidRegistration => 2010,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidJson => "invalid_json",
+            ErrorCode::UnknownType => "unknown_type",
+            ErrorCode::MalformedMessage => "malformed_message",
+            ErrorCode::InvalidUnitStatus => "invalid_unit_status",
+            ErrorCode::SnRejected => "sn_rejected",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::UnsupportedVersion => "unsupported_version",
+            ErrorCode::FrameTooLarge => "frame_too_large",
+            ErrorCode::InvalidUtf8 => "invalid_utf8",
+            ErrorCode::InvalidPayload => "invalid_payload",
+            ErrorCode::EncryptionRequired => "encryption_required",
+            ErrorCode::DecryptFailed => "decrypt_failed",
+            ErrorCode::AuthFailed => "auth_failed",
+            ErrorCode::SessionReplaced => "session_replaced",
+            ErrorCode::ServerShutdown => "server_shutdown",
+            ErrorCode::DeviceOffline => "device_offline",
+            ErrorCode::NoResponse => "no_response",
+            ErrorCode::QueueFull => "queue_full",
+            ErrorCode::AckMismatch => "ack_mismatch",
+            ErrorCode::MissingSn => "missing_sn",
+            ErrorCode::PayloadTooLarge => "payload_too_large",
+            ErrorCode::InvalidEncoding => "invalid_encoding",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::InternalError => "internal_error",
+            ErrorCode::InvalidRegistration => "invalid_registration",
+        }
+    }
+
+    pub fn from_code(code: u32) -> Option<ErrorCode> {
+        ErrorCode::ALL.iter().find(|c| c.code() == code).copied()
+    }
+
+    /// 判断一帧无法解析为CSoD消息的原因
+    pub fn classify(line: &str) -> ErrorCode {
+        match serde_json::from_str::<Value>(line.trim()) {
+            Err(_) => ErrorCode::InvalidJson,
+            Ok(Value::Object(obj)) => match obj.get("type").and_then(|t| t.as_str()) {
+                Some("ping") | Some("pong") | Some("get") | Some("getack") | Some("set") | Some("setack")
+                | Some("event") | Some("eventack") | Some("auth") | Some("error") => ErrorCode::MalformedMessage,
+                _ => ErrorCode::UnknownType,
+            },
+            Ok(_) => ErrorCode::MalformedMessage,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code(), self.reason())
+    }
+}
+
+#[cfg(test)]
+mod code_test {
+    use std::collections::HashSet;
+
+    use super::ErrorCode;
+
+    #[test]
+    fn test_code_unique() {
+        let codes: HashSet<u32> = ErrorCode::ALL.iter().map(|c| c.code()).collect();
+        assert_eq!(codes.len(), ErrorCode::ALL.len());
+        for c in ErrorCode::ALL.iter() {
+            assert_eq!(ErrorCode::from_code(c.code()), Some(*c));
+        }
+        assert_eq!(ErrorCode::from_code(0), None);
+        assert_eq!(ErrorCode::SnRejected.to_string(), "1005 sn_rejected");
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(ErrorCode::classify(r#"{"type": "ping""#), ErrorCode::InvalidJson);
+        assert_eq!(ErrorCode::classify(r#"{"type": "rawdata","sn": "123"}"#), ErrorCode::UnknownType);
+        assert_eq!(ErrorCode::classify(r#"{"sn": "123"}"#), ErrorCode::UnknownType);
+        assert_eq!(ErrorCode::classify(r#"{"type": "ping"}"#), ErrorCode::MalformedMessage);
+        assert_eq!(ErrorCode::classify(r#"[1, 2]"#), ErrorCode::MalformedMessage);
+    }
+}
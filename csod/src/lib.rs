@@ -0,0 +1,11 @@
+//!
+//! # CSoD(C/S of Device)协议公共库
+//! 感知层连接服务与应用层http接口共用的协议模型，协议定义见doc/CSoD.md
+//!
+
+pub mod code;
+pub mod message;
+pub mod unit;
+pub mod sn;
+pub mod registry;
+pub mod sku;
@@ -0,0 +1,650 @@
+//!
+//! # CSoD协议层消息模型
+//! 设备与服务端之间的每一帧JSON只解析一次，得到一个`CsodMessage`，下行消息也由它序列化
+//!
+//! Example
+//! ```
+//! use csod::message::CsodMessage;
+//!
+//! let msg: CsodMessage = r#"{"v":"0","type":"ping","sn":"A0AA1B0001F3E"}"#.parse().unwrap();
+//! assert_eq!(msg.sn().unwrap().as_str(), "A0AA1B0001F3E");
+//! assert_eq!(CsodMessage::pong(msg.version()).to_line(), r#"{"type":"pong","v":"0"}"#);
+//! ```
+//!
+//! get/set由服务端带上消息ID下发，设备在对应的getack/setack中原样返回，用于匹配请求与响应
+//! ```
+//! use csod::message::{CsodMessage, MessageId};
+//!
+//! let mut get: CsodMessage = r#"{"v":"0","type":"get","sn":"A0AA1B0001F3E","010":""}"#.parse().unwrap();
+//! get.set_id(MessageId::new("1a2b"));
+//! let ack: CsodMessage = r#"{"v":"0","type":"getack","sn":"A0AA1B0001F3E","id":"1a2b","010":"01"}"#.parse().unwrap();
+//! assert!(ack.is_ack_for(&get));
+//! ```
+//!
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+use crate::code::ErrorCode;
+use crate::unit::{self, UnitError};
+
+/// 协议版本号，报文中以字符串表示，如`"v":"0"`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Version(pub u32);
+
+/// 服务端支持的最低、最高协议版本
+///
+/// - v0: 初版协议，ping/pong/get/set/event
+/// - v1: 增加error帧，服务端在连接中遇到错误时告知设备
+pub const MIN_VERSION: Version = Version(0);
+pub const MAX_VERSION: Version = Version(1);
+
+impl Version {
+    /// 版本协商: 设备在ping中上报其支持的最高版本，服务端选用双方都支持的最高版本，
+    /// 低于`min`的设备无法协商，返回拒绝原因
+    pub fn negotiate(device: Version, min: Version) -> Result<Version, String> {
+        let min = min.max(MIN_VERSION);
+        if device < min {
+            return Err(format!("version {} not supported, min version {}", device, min));
+        }
+        Ok(device.min(MAX_VERSION))
+    }
+
+    /// 连接建立后是否可以向设备发送error帧，v0固件不认识error帧
+    pub fn supports_error_frame(&self) -> bool {
+        self.0 >= 1
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Serialize for Version {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Version {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // 兼容部分固件将版本号写成数字
+        match Value::deserialize(deserializer)? {
+            Value::String(s) => s.trim().parse::<u32>().map(Version).map_err(de::Error::custom),
+            Value::Number(n) => match n.as_u64() {
+                Some(v) if v <= u32::MAX as u64 => Ok(Version(v as u32)),
+                _ => Err(de::Error::custom(format!("invalid version {}", n))),
+            },
+            other => Err(de::Error::custom(format!("invalid version {}", other))),
+        }
+    }
+}
+
+/// 握手后连接使用的帧编码，设备在ping的`enc`中请求，服务端在pong的`enc`中确认
+///
+/// - json: 以`\n`分隔的JSON文本帧，默认
+/// - cbor/msgpack: 长度前缀的二进制帧，内容为同一消息的CBOR/MessagePack编码，可选deflate压缩
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Cbor,
+    MsgPack,
+}
+
+impl Encoding {
+    pub fn is_binary(&self) -> bool {
+        *self != Encoding::Json
+    }
+
+    /// 编码协商: 设备请求的编码在`allowed`中时选用，否则回退为json；deflate只用于二进制编码
+    pub fn negotiate(requested: Option<Encoding>, deflate: bool, allowed: &[Encoding], allow_deflate: bool) -> (Encoding, bool) {
+        match requested {
+            Some(enc) if enc.is_binary() && allowed.contains(&enc) => (enc, deflate && allow_deflate),
+            _ => (Encoding::Json, false),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Encoding::Json => "json",
+            Encoding::Cbor => "cbor",
+            Encoding::MsgPack => "msgpack",
+        })
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "json" => Ok(Encoding::Json),
+            "cbor" => Ok(Encoding::Cbor),
+            "msgpack" => Ok(Encoding::MsgPack),
+            other => Err(format!("unknown encoding {:?}", other)),
+        }
+    }
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+/// 设备序列号，反序列化时去掉首尾空白
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(transparent)]
+pub struct Sn(String);
+
+impl Sn {
+    pub fn new(sn: &str) -> Sn {
+        Sn(sn.trim().to_string())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Sn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Sn {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let sn = String::deserialize(deserializer)?;
+        Ok(Sn::new(&sn))
+    }
+}
+
+/// 消息ID，用于关联get/set请求与设备返回的ack
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct MessageId(String);
+
+impl MessageId {
+    pub fn new(id: &str) -> MessageId {
+        MessageId(id.to_string())
+    }
+
+    /// 生成进程内唯一的ID: 毫秒时间戳 + 进程号 + 自增计数，均为十六进制
+    pub fn generate() -> MessageId {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+        let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+        MessageId(format!("{:x}{:04x}{:04x}", millis, process::id() & 0xffff, count & 0xffff))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// 端单元地址，3位十六进制字符串，前2位为单元类型，后1位为单元id
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UnitAddr {
+    pub kind: u8,
+    pub id: u8,
+}
+
+impl UnitAddr {
+    pub fn new(kind: u8, id: u8) -> Option<UnitAddr> {
+        if id > 0xf {
+            return None;
+        }
+        Some(UnitAddr { kind, id })
+    }
+}
+
+impl fmt::Display for UnitAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}{:x}", self.kind, self.id)
+    }
+}
+
+impl FromStr for UnitAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 3 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("invalid unit address {:?}", s));
+        }
+        let kind = u8::from_str_radix(&s[..2], 16).map_err(|e| e.to_string())?;
+        let id = u8::from_str_radix(&s[2..], 16).map_err(|e| e.to_string())?;
+        Ok(UnitAddr { kind, id })
+    }
+}
+
+impl Serialize for UnitAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for UnitAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// get/getack/set/setack/event 消息体: 版本、sn、可选的消息ID与事件序号、以及若干`端单元地址: status`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UnitMessage {
+    #[serde(default)]
+    pub v: Version,
+    pub sn: Sn,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<MessageId>,
+    /// event的序号，由设备按上报顺序递增，服务端据此回复eventack并去重
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    #[serde(flatten)]
+    pub units: BTreeMap<UnitAddr, Value>,
+}
+
+impl UnitMessage {
+    pub fn new(v: Version, sn: Sn) -> UnitMessage {
+        UnitMessage { v, sn, id: None, seq: None, units: BTreeMap::new() }
+    }
+}
+
+/// CSoD协议层消息，`type`字段决定消息类型
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum CsodMessage {
+    /// `enc`/`deflate`为设备请求的帧编码，不带时为json；`psk_nonce`为设备请求安全层时的随机数(base64)
+    Ping {
+        #[serde(default)]
+        v: Version,
+        sn: Sn,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        enc: Option<Encoding>,
+        #[serde(default, skip_serializing_if = "is_false")]
+        deflate: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        psk_nonce: Option<String>,
+    },
+    /// 设备请求过编码时，`enc`/`deflate`为服务端选定的帧编码，pong之后双方按该编码收发；
+    /// 建立安全层时`psk_nonce`为服务端的随机数，pong之后的帧均加密；
+    /// 启用设备认证时`challenge`为服务端的随机数，设备需回复`auth`
+    Pong {
+        #[serde(default)]
+        v: Version,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        enc: Option<Encoding>,
+        #[serde(default, skip_serializing_if = "is_false")]
+        deflate: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        psk_nonce: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        challenge: Option<String>,
+    },
+    /// 设备对握手challenge的应答，`mac`为HMAC-SHA256(认证密钥, challenge || sn)的base64，认证密钥由设备密钥以HKDF派生
+    Auth {
+        #[serde(default)]
+        v: Version,
+        sn: Sn,
+        mac: String,
+    },
+    Get(UnitMessage),
+    GetAck(UnitMessage),
+    Set(UnitMessage),
+    SetAck(UnitMessage),
+    Event(UnitMessage),
+    /// 服务端确认带序号的event已入队
+    EventAck {
+        #[serde(default)]
+        v: Version,
+        sn: Sn,
+        seq: u64,
+    },
+    /// 服务端告知设备出错，`code`见`csod::code`
+    Error {
+        #[serde(default)]
+        v: Version,
+        code: u32,
+        msg: String,
+    },
+}
+
+impl CsodMessage {
+    /// 解析一帧JSON，帧尾的`\n`可以保留
+    pub fn parse(line: &str) -> Result<CsodMessage, serde_json::Error> {
+        serde_json::from_str(line.trim())
+    }
+
+    /// 序列化为一帧JSON，不含帧尾`\n`
+    pub fn to_line(&self) -> String {
+        serde_json::to_string(self).expect("csod message is always serializable")
+    }
+
+    pub fn pong(v: Version) -> CsodMessage {
+        CsodMessage::Pong { v, enc: None, deflate: false, psk_nonce: None, challenge: None }
+    }
+
+    /// 握手的pong，带上协商的帧编码
+    pub fn pong_with_encoding(v: Version, enc: Encoding, deflate: bool) -> CsodMessage {
+        CsodMessage::Pong { v, enc: Some(enc), deflate, psk_nonce: None, challenge: None }
+    }
+
+    /// 握手的pong带上服务端nonce，pong以外的消息忽略
+    pub fn set_psk_nonce(&mut self, nonce: String) {
+        if let CsodMessage::Pong { psk_nonce, .. } = self {
+            *psk_nonce = Some(nonce);
+        }
+    }
+
+    /// 握手的pong带上认证challenge，pong以外的消息忽略
+    pub fn set_challenge(&mut self, nonce: String) {
+        if let CsodMessage::Pong { challenge, .. } = self {
+            *challenge = Some(nonce);
+        }
+    }
+
+    pub fn event_ack(v: Version, sn: Sn, seq: u64) -> CsodMessage {
+        CsodMessage::EventAck { v, sn, seq }
+    }
+
+    pub fn error(v: Version, code: ErrorCode, msg: &str) -> CsodMessage {
+        CsodMessage::Error { v, code: code.code(), msg: msg.to_string() }
+    }
+
+    pub fn version(&self) -> Version {
+        match self {
+            CsodMessage::Ping { v, .. }
+            | CsodMessage::Pong { v, .. }
+            | CsodMessage::EventAck { v, .. }
+            | CsodMessage::Auth { v, .. }
+            | CsodMessage::Error { v, .. } => *v,
+            CsodMessage::Get(m)
+            | CsodMessage::GetAck(m)
+            | CsodMessage::Set(m)
+            | CsodMessage::SetAck(m)
+            | CsodMessage::Event(m) => m.v,
+        }
+    }
+
+    /// pong消息没有sn
+    pub fn sn(&self) -> Option<&Sn> {
+        match self {
+            CsodMessage::Ping { sn, .. } | CsodMessage::EventAck { sn, .. } | CsodMessage::Auth { sn, .. } => Some(sn),
+            CsodMessage::Pong { .. } | CsodMessage::Error { .. } => None,
+            CsodMessage::Get(m)
+            | CsodMessage::GetAck(m)
+            | CsodMessage::Set(m)
+            | CsodMessage::SetAck(m)
+            | CsodMessage::Event(m) => Some(&m.sn),
+        }
+    }
+
+    /// ping/pong没有消息ID
+    pub fn id(&self) -> Option<&MessageId> {
+        self.unit_message().and_then(|m| m.id.as_ref())
+    }
+
+    /// event的序号，eventack为其确认的序号
+    pub fn seq(&self) -> Option<u64> {
+        match self {
+            CsodMessage::EventAck { seq, .. } => Some(*seq),
+            _ => self.unit_message().and_then(|m| m.seq),
+        }
+    }
+
+    /// 按协商的版本改写消息的版本号
+    pub fn set_version(&mut self, version: Version) {
+        match self {
+            CsodMessage::Ping { v, .. }
+            | CsodMessage::Pong { v, .. }
+            | CsodMessage::EventAck { v, .. }
+            | CsodMessage::Auth { v, .. }
+            | CsodMessage::Error { v, .. } => *v = version,
+            CsodMessage::Get(m)
+            | CsodMessage::GetAck(m)
+            | CsodMessage::Set(m)
+            | CsodMessage::SetAck(m)
+            | CsodMessage::Event(m) => m.v = version,
+        }
+    }
+
+    /// 设置消息ID，ping/pong忽略
+    pub fn set_id(&mut self, id: MessageId) {
+        match self {
+            CsodMessage::Get(m)
+            | CsodMessage::GetAck(m)
+            | CsodMessage::Set(m)
+            | CsodMessage::SetAck(m)
+            | CsodMessage::Event(m) => m.id = Some(id),
+            _ => {}
+        }
+    }
+
+    fn unit_message(&self) -> Option<&UnitMessage> {
+        match self {
+            CsodMessage::Get(m)
+            | CsodMessage::GetAck(m)
+            | CsodMessage::Set(m)
+            | CsodMessage::SetAck(m)
+            | CsodMessage::Event(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    pub fn units(&self) -> Option<&BTreeMap<UnitAddr, Value>> {
+        self.unit_message().map(|m| &m.units)
+    }
+
+    /// 按端单元类型校验status，get消息的status必须为空
+    pub fn validate_units(&self) -> Result<(), UnitError> {
+        match self {
+            CsodMessage::Ping { .. }
+            | CsodMessage::Pong { .. }
+            | CsodMessage::EventAck { .. }
+            | CsodMessage::Auth { .. }
+            | CsodMessage::Error { .. } => Ok(()),
+            CsodMessage::Get(m) => unit::check_get(&m.units),
+            CsodMessage::GetAck(m)
+            | CsodMessage::Set(m)
+            | CsodMessage::SetAck(m)
+            | CsodMessage::Event(m) => unit::decode_all(&m.units).map(|_| ()),
+        }
+    }
+
+    /// 设备对get/set的响应
+    pub fn is_ack(&self) -> bool {
+        matches!(self, CsodMessage::GetAck(_) | CsodMessage::SetAck(_))
+    }
+
+    /// 是否为`req`的响应: getack对应get，setack对应set，且sn与消息ID一致
+    pub fn is_ack_for(&self, req: &CsodMessage) -> bool {
+        let type_match = matches!(
+            (req, self),
+            (CsodMessage::Get(_), CsodMessage::GetAck(_)) | (CsodMessage::Set(_), CsodMessage::SetAck(_))
+        );
+        type_match && req.id().is_some() && self.id() == req.id() && self.sn() == req.sn()
+    }
+
+    /// 服务端可以下发给设备的消息
+    pub fn is_downlink(&self) -> bool {
+        matches!(self, CsodMessage::Pong { .. } | CsodMessage::Get(_) | CsodMessage::Set(_))
+    }
+}
+
+impl FromStr for CsodMessage {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CsodMessage::parse(s)
+    }
+}
+
+#[cfg(test)]
+mod message_test {
+    use serde_json::json;
+
+    use crate::code::ErrorCode;
+
+    use super::{CsodMessage, Encoding, MessageId, Sn, UnitAddr, Version, MAX_VERSION};
+
+    #[test]
+    fn test_parse_ping() {
+        let ok_ping = CsodMessage::parse(r#"{"v":"0","type": "ping","sn": " 123 "}"#);
+        assert_eq!(ok_ping.unwrap(), CsodMessage::Ping { v: Version(0), sn: Sn::new("123"), enc: None, deflate: false, psk_nonce: None });
+        // 老固件不带版本号
+        let no_v = CsodMessage::parse(r#"{"type": "ping","sn": "123"}"#).unwrap();
+        assert_eq!(no_v.version(), Version(0));
+        assert!(CsodMessage::parse(r#"{"type": "ping","what": "error"}"#).is_err());
+        assert!(CsodMessage::parse(r#"{"what": "err"#).is_err());
+        assert!(CsodMessage::parse(r#"{"type": "rawdata","sn": "123"}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_units() {
+        let event = r#"{"v":"0","type":"event","sn":"123543876","010":"01","022":"000a0a"}"#;
+        let msg = CsodMessage::parse(event).unwrap();
+        let units = msg.units().unwrap();
+        assert_eq!(units.get(&UnitAddr::new(0x01, 0).unwrap()), Some(&json!("01")));
+        assert_eq!(units.get(&UnitAddr::new(0x02, 2).unwrap()), Some(&json!("000a0a")));
+        assert_eq!(msg.sn().unwrap().as_str(), "123543876");
+
+        let set = r#"{"v":"0","type":"set","sn":"123543876","040":{"date":"2020-11-12:12-30-00","target":"01"}}"#;
+        assert!(matches!(CsodMessage::parse(set), Ok(CsodMessage::Set(_))));
+        let ack = r#"{"v":"0","type":"setack","sn":"123543876"}"#;
+        assert!(CsodMessage::parse(ack).unwrap().is_ack());
+
+        // 非端单元地址的字段
+        let bad_unit = r#"{"v":"0","type":"event","sn":"123","snfuck":"01"}"#;
+        assert!(CsodMessage::parse(bad_unit).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let get = r#"{"type":"get","v":"0","sn":"123543876","010":"","0a1":""}"#;
+        let msg = CsodMessage::parse(get).unwrap();
+        assert_eq!(msg.to_line(), get);
+        assert_eq!(CsodMessage::parse(&msg.to_line()).unwrap(), msg);
+        assert_eq!(CsodMessage::pong(Version(0)).to_line(), r#"{"type":"pong","v":"0"}"#);
+    }
+
+    #[test]
+    fn test_encoding() {
+        let ping = CsodMessage::parse(r#"{"v":"1","type":"ping","sn":"123","enc":"cbor","deflate":true}"#).unwrap();
+        let (enc, deflate) = match ping {
+            CsodMessage::Ping { enc, deflate, .. } => (enc, deflate),
+            _ => unreachable!(),
+        };
+        assert_eq!(enc, Some(Encoding::Cbor));
+        assert!(deflate);
+
+        let allowed = [Encoding::Cbor, Encoding::MsgPack];
+        assert_eq!(Encoding::negotiate(enc, deflate, &allowed, true), (Encoding::Cbor, true));
+        assert_eq!(Encoding::negotiate(enc, deflate, &allowed, false), (Encoding::Cbor, false));
+        assert_eq!(Encoding::negotiate(enc, deflate, &[Encoding::MsgPack], true), (Encoding::Json, false));
+        assert_eq!(Encoding::negotiate(None, true, &allowed, true), (Encoding::Json, false));
+
+        let pong = CsodMessage::pong_with_encoding(Version(1), Encoding::MsgPack, false);
+        assert_eq!(pong.to_line(), r#"{"type":"pong","v":"1","enc":"msgpack"}"#);
+        assert_eq!("msgpack".parse::<Encoding>(), Ok(Encoding::MsgPack));
+        assert!("xml".parse::<Encoding>().is_err());
+    }
+
+    #[test]
+    fn test_challenge() {
+        let mut pong = CsodMessage::pong(Version(1));
+        pong.set_challenge("AAECAw==".to_string());
+        assert_eq!(pong.to_line(), r#"{"type":"pong","v":"1","challenge":"AAECAw=="}"#);
+        let auth = CsodMessage::parse(r#"{"v":"1","type":"auth","sn":"123","mac":"3q2+7w=="}"#).unwrap();
+        assert!(matches!(&auth, CsodMessage::Auth { mac, .. } if mac == "3q2+7w=="));
+        assert_eq!(auth.sn().unwrap().as_str(), "123");
+        assert_eq!(ErrorCode::classify(r#"{"type":"auth","sn":"123"}"#), ErrorCode::MalformedMessage);
+    }
+
+    #[test]
+    fn test_message_id() {
+        let mut set = CsodMessage::parse(r#"{"v":"0","type":"set","sn":"123","010":"01"}"#).unwrap();
+        let id = MessageId::generate();
+        assert_ne!(id, MessageId::generate());
+        set.set_id(id.clone());
+        assert_eq!(set.id(), Some(&id));
+        // 消息ID不会被当作端单元地址
+        let line = set.to_line();
+        assert_eq!(CsodMessage::parse(&line).unwrap(), set);
+
+        let ack = format!(r#"{{"v":"0","type":"setack","sn":"123","id":"{}","010":"01"}}"#, id);
+        assert!(CsodMessage::parse(&ack).unwrap().is_ack_for(&set));
+        // 类型不匹配
+        let getack = format!(r#"{{"v":"0","type":"getack","sn":"123","id":"{}","010":"01"}}"#, id);
+        assert!(!CsodMessage::parse(&getack).unwrap().is_ack_for(&set));
+        // ID不匹配、或没有ID
+        let other = r#"{"v":"0","type":"setack","sn":"123","id":"other","010":"01"}"#;
+        assert!(!CsodMessage::parse(other).unwrap().is_ack_for(&set));
+        let no_id = r#"{"v":"0","type":"setack","sn":"123","010":"01"}"#;
+        assert!(!CsodMessage::parse(no_id).unwrap().is_ack_for(&set));
+    }
+
+    #[test]
+    fn test_event_seq() {
+        let event = CsodMessage::parse(r#"{"v":"0","type":"event","sn":"123","seq":42,"010":"01"}"#).unwrap();
+        assert_eq!(event.seq(), Some(42));
+        assert_eq!(event.units().unwrap().len(), 1);
+        let ack = CsodMessage::event_ack(event.version(), event.sn().unwrap().clone(), 42);
+        assert_eq!(ack.to_line(), r#"{"type":"eventack","v":"0","sn":"123","seq":42}"#);
+        assert_eq!(CsodMessage::parse(&ack.to_line()).unwrap(), ack);
+        // 老固件不带序号
+        let no_seq = CsodMessage::parse(r#"{"v":"0","type":"event","sn":"123","010":"01"}"#).unwrap();
+        assert_eq!(no_seq.seq(), None);
+        assert!(!no_seq.to_line().contains("seq"));
+    }
+
+    #[test]
+    fn test_error_frame() {
+        let err = CsodMessage::error(Version(0), ErrorCode::SnRejected, "checksum mismatch");
+        assert_eq!(err.to_line(), r#"{"type":"error","v":"0","code":1005,"msg":"checksum mismatch"}"#);
+        assert_eq!(CsodMessage::parse(&err.to_line()).unwrap(), err);
+        assert_eq!(err.sn(), None);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Version::negotiate(Version(0), Version(0)), Ok(Version(0)));
+        assert_eq!(Version::negotiate(Version(1), Version(0)), Ok(Version(1)));
+        // 设备版本比服务端新，按服务端最高版本
+        assert_eq!(Version::negotiate(Version(99), Version(0)), Ok(MAX_VERSION));
+        assert!(Version::negotiate(Version(0), Version(1)).is_err());
+        assert!(!Version(0).supports_error_frame());
+        assert!(Version(1).supports_error_frame());
+
+        let mut get = CsodMessage::parse(r#"{"v":"0","type":"get","sn":"123","010":""}"#).unwrap();
+        get.set_version(Version(1));
+        assert_eq!(get.version(), Version(1));
+    }
+
+    #[test]
+    fn test_unit_addr() {
+        assert_eq!("0A1".parse::<UnitAddr>(), Ok(UnitAddr { kind: 0x0a, id: 1 }));
+        assert_eq!(UnitAddr::new(0x05, 0xf).unwrap().to_string(), "05f");
+        assert!("01".parse::<UnitAddr>().is_err());
+        assert!("0g1".parse::<UnitAddr>().is_err());
+        assert!(UnitAddr::new(0x01, 0x10).is_none());
+    }
+}
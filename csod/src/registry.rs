@@ -0,0 +1,263 @@
+//!
+//! # 出厂登记
+//! 产线烧写完成的设备登记到redis，连接服务可以只允许已登记的SN上线
+//!
+//! - `csod/registry/{sn}`: hash，字段product, batch, mac, sku, flashed_at, registered_at
+//! - `csod/registry`: 有序集合，成员为sn，score为登记时间戳，用于计数
+//! - `csod/sku/{sku}`、`csod/mac/{mac}`: 值为sn，由SKU或MAC反查设备
+//!
+//! 登记文件为产线脚本(test/factory-test/mxft.py)输出的`*-finished.csv`，每行`sn[,mac[,flashed_at]]`，
+//! 没有mac或烧写日期的行使用导入时指定的默认值，SKU按导入时的规则生成
+//!
+//! Example
+//! ```
+//! use chrono::NaiveDate;
+//! use csod::registry;
+//! use csod::sku::SkuRule;
+//!
+//! let date = NaiveDate::from_ymd_opt(2020, 11, 12).unwrap();
+//! let rule = SkuRule::default();
+//! let (records, errors) = registry::parse_finished_csv("A0AAB10001F3E,AA-BB-CC-00-11-22\n", "batch-1", date, &rule);
+//! assert!(errors.is_empty());
+//! assert_eq!(records[0].mac.as_deref(), Some("aa:bb:cc:00:11:22"));
+//! assert_eq!(records[0].sku.as_deref(), Some("A0A-201101-batch-1-00001"));
+//! assert_eq!(records[0].flashed_at, date);
+//! ```
+//!
+
+use std::collections::HashMap;
+use std::fmt;
+
+use chrono::NaiveDate;
+
+use crate::sku::{SkuError, SkuRule};
+use crate::sn::{self, Product, SnError};
+
+pub const NAMESPACE_REGISTRY: &str = "csod/registry";
+pub const NAMESPACE_SKU: &str = "csod/sku";
+pub const NAMESPACE_MAC: &str = "csod/mac";
+
+/// 设备登记记录的key
+pub fn registry_key(sn: &str) -> String {
+    format!("{}/{}", NAMESPACE_REGISTRY, sn)
+}
+
+/// SKU反查sn的key
+pub fn sku_key(sku: &str) -> String {
+    format!("{}/{}", NAMESPACE_SKU, sku)
+}
+
+/// MAC反查sn的key，`mac`须为`parse_mac`的结果
+pub fn mac_key(mac: &str) -> String {
+    format!("{}/{}", NAMESPACE_MAC, mac)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegistryError {
+    Sn(SnError),
+    Sku(SkuError),
+    InvalidMac(String),
+    InvalidDate(String),
+    /// 登记文件中的行格式不对，或redis中的记录缺少字段
+    Malformed(String),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::Sn(e) => write!(f, "invalid sn: {}", e),
+            RegistryError::Sku(e) => write!(f, "{}", e),
+            RegistryError::InvalidMac(m) => write!(f, "invalid mac {:?}", m),
+            RegistryError::InvalidDate(d) => write!(f, "invalid flash date {:?}", d),
+            RegistryError::Malformed(s) => write!(f, "malformed record: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+/// 一台设备的出厂登记
+#[derive(Debug, Clone, PartialEq)]
+pub struct Registration {
+    pub sn: String,
+    pub product: Product,
+    pub batch: String,
+    /// 小写、冒号分隔
+    pub mac: Option<String>,
+    /// 按SKU规则生成，见`assign_sku`
+    pub sku: Option<String>,
+    pub flashed_at: NaiveDate,
+}
+
+impl Registration {
+    /// 产品ID由sn得出，只校验sn结构
+    pub fn new(sn: &str, batch: &str, mac: Option<&str>, flashed_at: NaiveDate) -> Result<Registration, RegistryError> {
+        let parsed = sn::parse(sn).map_err(RegistryError::Sn)?;
+        let mac = match mac {
+            Some(mac) => Some(parse_mac(mac)?),
+            None => None,
+        };
+        Ok(Registration {
+            sn: sn.to_uppercase(),
+            product: parsed.product,
+            batch: batch.to_string(),
+            mac,
+            sku: None,
+            flashed_at,
+        })
+    }
+
+    /// 按规则生成SKU
+    pub fn assign_sku(&mut self, rule: &SkuRule) -> Result<(), RegistryError> {
+        let parsed = sn::parse(&self.sn).map_err(RegistryError::Sn)?;
+        self.sku = Some(rule.generate(&parsed, &self.batch, self.flashed_at).map_err(RegistryError::Sku)?);
+        Ok(())
+    }
+
+    /// 写入redis hash的字段，没有mac或sku时不写该字段
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![
+            ("product", self.product.to_string()),
+            ("batch", self.batch.clone()),
+            ("flashed_at", self.flashed_at.to_string()),
+        ];
+        if let Some(mac) = &self.mac {
+            fields.push(("mac", mac.clone()));
+        }
+        if let Some(sku) = &self.sku {
+            fields.push(("sku", sku.clone()));
+        }
+        fields
+    }
+
+    /// 由redis hash还原
+    pub fn from_fields(sn: &str, fields: &HashMap<String, String>) -> Result<Registration, RegistryError> {
+        let get = |name: &str| fields.get(name).ok_or_else(|| RegistryError::Malformed(format!("missing {}", name)));
+        let flashed_at = parse_date(get("flashed_at")?)?;
+        let mut record = Registration::new(sn, get("batch")?, fields.get("mac").map(|m| m.as_str()), flashed_at)?;
+        record.sku = fields.get("sku").cloned();
+        Ok(record)
+    }
+}
+
+/// 接受`aa:bb:cc:dd:ee:ff`、`aa-bb-cc-dd-ee-ff`与`aabbccddeeff`，统一为小写冒号分隔
+pub fn parse_mac(mac: &str) -> Result<String, RegistryError> {
+    let hex: String = mac.chars().filter(|c| *c != ':' && *c != '-').collect();
+    let separators = mac.len() - hex.len();
+    if hex.len() != 12 || !hex.chars().all(|c| c.is_ascii_hexdigit()) || (separators != 0 && separators != 5) {
+        return Err(RegistryError::InvalidMac(mac.to_string()));
+    }
+    let hex = hex.to_lowercase();
+    let octets: Vec<&str> = (0..6).map(|i| &hex[i * 2..i * 2 + 2]).collect();
+    Ok(octets.join(":"))
+}
+
+/// 烧写日期，`YYYY-MM-DD`
+pub fn parse_date(date: &str) -> Result<NaiveDate, RegistryError> {
+    NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d").map_err(|_| RegistryError::InvalidDate(date.to_string()))
+}
+
+/// 解析登记文件并按`rule`生成SKU，返回解析成功的记录与出错的行(行号从1开始)
+///
+/// 空行、`#`开头的注释行与`sn`表头被忽略，文件内重复的sn以后出现的为准
+pub fn parse_finished_csv(content: &str, batch: &str, default_date: NaiveDate, rule: &SkuRule) -> (Vec<Registration>, Vec<(usize, RegistryError)>) {
+    let mut records: Vec<Registration> = vec![];
+    let mut errors = vec![];
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.eq_ignore_ascii_case("sn") || line.to_lowercase().starts_with("sn,") {
+            continue;
+        }
+        let cols: Vec<&str> = line.split(',').map(|c| c.trim()).collect();
+        if cols.len() > 3 {
+            errors.push((i + 1, RegistryError::Malformed(line.to_string())));
+            continue;
+        }
+        let mac = cols.get(1).filter(|m| !m.is_empty()).copied();
+        let record = match cols.get(2).filter(|d| !d.is_empty()) {
+            Some(date) => parse_date(date).and_then(|date| Registration::new(cols[0], batch, mac, date)),
+            None => Registration::new(cols[0], batch, mac, default_date),
+        }.and_then(|mut record| record.assign_sku(rule).map(|_| record));
+        match record {
+            Ok(record) => {
+                records.retain(|r| r.sn != record.sn);
+                records.push(record);
+            }
+            Err(e) => errors.push((i + 1, e)),
+        }
+    }
+    (records, errors)
+}
+
+#[cfg(test)]
+mod registry_test {
+    use std::collections::HashMap;
+
+    use chrono::NaiveDate;
+
+    use crate::sku::SkuRule;
+    use crate::sn::Product;
+
+    use super::{mac_key, parse_finished_csv, parse_mac, registry_key, sku_key, Registration, RegistryError};
+
+    #[test]
+    fn test_parse_mac() {
+        assert_eq!(parse_mac("AA:BB:CC:00:11:22").unwrap(), "aa:bb:cc:00:11:22");
+        assert_eq!(parse_mac("aa-bb-cc-00-11-22").unwrap(), "aa:bb:cc:00:11:22");
+        assert_eq!(parse_mac("aabbcc001122").unwrap(), "aa:bb:cc:00:11:22");
+        assert!(parse_mac("aa:bb:cc:00:11").is_err());
+        assert!(parse_mac("aa:bb:cc:00:11:zz").is_err());
+        assert!(parse_mac("aabb:cc001122").is_err());
+    }
+
+    #[test]
+    fn test_parse_finished_csv() {
+        let date = NaiveDate::from_ymd_opt(2020, 11, 20).unwrap();
+        let content = "\
+sn,mac,flashed_at
+# 第一批
+A0AAB10001F3E
+a0bab10002abc,aa:bb:cc:00:11:22,2020-11-12
+
+A0AAB10001F3E,aabbcc001133
+short
+A0AAB10003F3E,not a mac
+A0AAB10004F3E,,2020-13-01
+A0AAB10005F3E,,,extra
+";
+        let (records, errors) = parse_finished_csv(content, "A0A-20201112", date, &SkuRule::default());
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].sn, "A0BAB10002ABC");
+        assert_eq!(records[0].product, Product::A0B);
+        assert_eq!(records[0].flashed_at, NaiveDate::from_ymd_opt(2020, 11, 12).unwrap());
+        // 重复的sn以后出现的为准
+        assert_eq!(records[1].sn, "A0AAB10001F3E");
+        assert_eq!(records[1].mac.as_deref(), Some("aa:bb:cc:00:11:33"));
+        assert_eq!(records[1].flashed_at, date);
+        assert_eq!(records[1].batch, "A0A-20201112");
+        assert_eq!(records[1].sku.as_deref(), Some("A0A-201101-A0A-20201112-00001"));
+
+        let lines: Vec<usize> = errors.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![7, 8, 9, 10]);
+        assert!(matches!(errors[0].1, RegistryError::Sn(_)));
+        assert!(matches!(errors[1].1, RegistryError::InvalidMac(_)));
+        assert!(matches!(errors[2].1, RegistryError::InvalidDate(_)));
+        assert!(matches!(errors[3].1, RegistryError::Malformed(_)));
+    }
+
+    #[test]
+    fn test_fields() {
+        let date = NaiveDate::from_ymd_opt(2020, 11, 12).unwrap();
+        let mut record = Registration::new("A0AAB10001F3E", "b1", Some("AABBCC001122"), date).unwrap();
+        record.assign_sku(&"{product}{date}{serial_hex}".parse().unwrap()).unwrap();
+        assert_eq!(registry_key(&record.sn), "csod/registry/A0AAB10001F3E");
+        assert_eq!(sku_key(record.sku.as_deref().unwrap()), "csod/sku/A0A2011010001");
+        assert_eq!(mac_key(record.mac.as_deref().unwrap()), "csod/mac/aa:bb:cc:00:11:22");
+        let fields: HashMap<String, String> = record.fields().into_iter().map(|(k, v)| (k.to_string(), v)).collect();
+        assert_eq!(Registration::from_fields(&record.sn, &fields).unwrap(), record);
+
+        let mut missing = fields.clone();
+        missing.remove("batch");
+        assert!(Registration::from_fields(&record.sn, &missing).is_err());
+    }
+}
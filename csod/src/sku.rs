@@ -0,0 +1,177 @@
+//!
+//! # SKU规则
+//! SKU与SN一一对应，直观保留产品、生产日期、批次等信息，用于库存管理，定义见doc/设备抽象模型.md
+//!
+//! SKU由规则模板生成，模板中可用的占位符:
+//!
+//! | 占位符 | 说明 |
+//! | :---- | :---- |
+//! | `{product}` | 产品ID，如`A0A` |
+//! | `{date}` | SN中的生产日期，`YYMMDD` |
+//! | `{flash_date}` | 烧写日期，`YYMMDD` |
+//! | `{batch}` | 批次 |
+//! | `{serial}` | SN中的流水号，5位十进制 |
+//! | `{serial_hex}` | SN中的流水号，4位十六进制 |
+//!
+//! 产品、生产日期与流水号确定唯一的SN，因此模板必须包含`{product}`、`{date}`与`{serial}`(或`{serial_hex}`)
+//!
+//! Example
+//! ```
+//! use chrono::NaiveDate;
+//! use csod::sku::SkuRule;
+//! use csod::sn;
+//!
+//! let rule: SkuRule = "{product}-{date}-{batch}-{serial}".parse().unwrap();
+//! let sn = sn::parse("A0AAB10001F3E").unwrap();
+//! let flashed_at = NaiveDate::from_ymd_opt(2020, 11, 12).unwrap();
+//! assert_eq!(rule.generate(&sn, "B01", flashed_at).unwrap(), "A0A-201101-B01-00001");
+//! ```
+//!
+
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::NaiveDate;
+
+use crate::sn::SerialNumber;
+
+pub const DEFAULT_SKU_RULE: &str = "{product}-{date}-{batch}-{serial}";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SkuError {
+    /// 模板中未知的占位符或括号不匹配
+    InvalidRule(String),
+    /// 模板缺少确定SN所需的占位符
+    NotUnique(&'static str),
+    /// 生成的SKU含有字母、数字、`-`、`_`、`.`以外的字符
+    InvalidChar(String),
+}
+
+impl fmt::Display for SkuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkuError::InvalidRule(r) => write!(f, "invalid sku rule: {}", r),
+            SkuError::NotUnique(p) => write!(f, "sku rule must contain {{{}}}", p),
+            SkuError::InvalidChar(s) => write!(f, "invalid character in sku {:?}", s),
+        }
+    }
+}
+
+impl std::error::Error for SkuError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Product,
+    Date,
+    FlashDate,
+    Batch,
+    Serial,
+    SerialHex,
+}
+
+/// SKU生成规则
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkuRule {
+    parts: Vec<Part>,
+}
+
+impl Default for SkuRule {
+    fn default() -> Self {
+        DEFAULT_SKU_RULE.parse().expect("default sku rule is valid")
+    }
+}
+
+impl FromStr for SkuRule {
+    type Err = SkuError;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut rest = rule;
+        while !rest.is_empty() {
+            match rest.find('{') {
+                Some(0) => {
+                    let end = rest.find('}').ok_or_else(|| SkuError::InvalidRule(rule.to_string()))?;
+                    parts.push(match &rest[1..end] {
+                        "product" => Part::Product,
+                        "date" => Part::Date,
+                        "flash_date" => Part::FlashDate,
+                        "batch" => Part::Batch,
+                        "serial" => Part::Serial,
+                        "serial_hex" => Part::SerialHex,
+                        other => return Err(SkuError::InvalidRule(format!("unknown placeholder {{{}}}", other))),
+                    });
+                    rest = &rest[end + 1..];
+                }
+                found => {
+                    let end = found.unwrap_or(rest.len());
+                    if rest[..end].contains('}') {
+                        return Err(SkuError::InvalidRule(rule.to_string()));
+                    }
+                    parts.push(Part::Literal(rest[..end].to_string()));
+                    rest = &rest[end..];
+                }
+            }
+        }
+
+        if !parts.contains(&Part::Product) {
+            return Err(SkuError::NotUnique("product"));
+        }
+        if !parts.contains(&Part::Date) {
+            return Err(SkuError::NotUnique("date"));
+        }
+        if !parts.contains(&Part::Serial) && !parts.contains(&Part::SerialHex) {
+            return Err(SkuError::NotUnique("serial"));
+        }
+        Ok(SkuRule { parts })
+    }
+}
+
+impl SkuRule {
+    pub fn generate(&self, sn: &SerialNumber, batch: &str, flashed_at: NaiveDate) -> Result<String, SkuError> {
+        let sku: String = self.parts.iter().map(|part| match part {
+            Part::Literal(s) => s.clone(),
+            Part::Product => sn.product.to_string(),
+            Part::Date => sn.date.format("%y%m%d").to_string(),
+            Part::FlashDate => flashed_at.format("%y%m%d").to_string(),
+            Part::Batch => batch.to_string(),
+            Part::Serial => format!("{:05}", sn.serial),
+            Part::SerialHex => format!("{:04X}", sn.serial),
+        }).collect();
+        if sku.is_empty() || !sku.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+            return Err(SkuError::InvalidChar(sku));
+        }
+        Ok(sku)
+    }
+}
+
+#[cfg(test)]
+mod sku_test {
+    use chrono::NaiveDate;
+
+    use crate::sn;
+
+    use super::{SkuError, SkuRule};
+
+    #[test]
+    fn test_rule() {
+        assert_eq!(SkuRule::default(), "{product}-{date}-{batch}-{serial}".parse().unwrap());
+        assert!("{product}{date}{serial_hex}".parse::<SkuRule>().is_ok());
+        assert_eq!("{product}{date}{batch}".parse::<SkuRule>(), Err(SkuError::NotUnique("serial")));
+        assert_eq!("{product}{serial}".parse::<SkuRule>(), Err(SkuError::NotUnique("date")));
+        assert!(matches!("{product}{date}{sn}{serial}".parse::<SkuRule>(), Err(SkuError::InvalidRule(_))));
+        assert!(matches!("{product}{date}{serial".parse::<SkuRule>(), Err(SkuError::InvalidRule(_))));
+        assert!(matches!("product}{date}{serial}".parse::<SkuRule>(), Err(SkuError::InvalidRule(_))));
+    }
+
+    #[test]
+    fn test_generate() {
+        let sn = sn::parse("A0BAB1FFFFABC").unwrap();
+        let flashed_at = NaiveDate::from_ymd_opt(2020, 11, 12).unwrap();
+        let rule: SkuRule = "SKU.{product}{date}_{flash_date}{serial_hex}".parse().unwrap();
+        assert_eq!(rule.generate(&sn, "b1", flashed_at).unwrap(), "SKU.A0B201101_201112FFFF");
+        assert_eq!(SkuRule::default().generate(&sn, "b1", flashed_at).unwrap(), "A0B-201101-b1-65535");
+        // 批次中的字符不能用于SKU
+        assert!(matches!(SkuRule::default().generate(&sn, "b 1/2", flashed_at), Err(SkuError::InvalidChar(_))));
+    }
+}
@@ -0,0 +1,263 @@
+//!
+//! # SN规范
+//! SN共13位: 3位产品ID + 3位日期标识 + 4位十六进制流水号 + 3位加盐MD5校验，生成规则与doc/generate-salt-sn.py一致
+//! ```text
+//! A0A  A  B  1  0001  F3E
+//! 产品 年 月 日 流水号 校验
+//! ```
+//! 年: 2020起依次为A-Z，月: 十六进制1-C，日: 1-9后接A-V
+//!
+//! Example
+//! ```
+//! use chrono::NaiveDate;
+//! use csod::sn::{self, Product};
+//!
+//! let date = NaiveDate::from_ymd_opt(2020, 11, 12).unwrap();
+//! let s = sn::generate(Product::A0A, date, 1, "anbwscx").unwrap();
+//! let parsed = sn::verify(&s, "anbwscx").unwrap();
+//! assert_eq!(parsed.product, Product::A0A);
+//! assert_eq!(parsed.serial, 1);
+//! ```
+//!
+
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{Datelike, NaiveDate};
+
+pub const SN_LEN: usize = 13;
+
+const FIRST_YEAR: i32 = 2020;
+const MARK_CHARS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// 产品ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Product {
+    /// 一路控制器+手动开关+信号输入+定时功能
+    A0A,
+    /// 一路控制器+手动开关+信号输入+定时功能+温湿度
+    A0B,
+    /// 两路控制器+2路手动开关+定时功能
+    A1C,
+    /// 两路控制器+2路手动开关+信号输入+定时功能+温湿度
+    A1D,
+    /// 四路控制器+4路手动开关+定时功能+温湿度+信号输入
+    A2E,
+}
+
+impl Product {
+    pub const ALL: [Product; 5] = [Product::A0A, Product::A0B, Product::A1C, Product::A1D, Product::A2E];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Product::A0A => "A0A",
+            Product::A0B => "A0B",
+            Product::A1C => "A1C",
+            Product::A1D => "A1D",
+            Product::A2E => "A2E",
+        }
+    }
+}
+
+impl fmt::Display for Product {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Product {
+    type Err = SnError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Product::ALL.iter()
+            .find(|p| p.as_str() == s)
+            .copied()
+            .ok_or_else(|| SnError::UnknownProduct(s.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnError {
+    Length(usize),
+    UnknownProduct(String),
+    InvalidDate(String),
+    InvalidSerial(String),
+    Checksum,
+}
+
+impl SnError {
+    /// 用于日志与计数的简短原因
+    pub fn reason(&self) -> &'static str {
+        match self {
+            SnError::Length(_) => "length",
+            SnError::UnknownProduct(_) => "unknown_product",
+            SnError::InvalidDate(_) => "invalid_date",
+            SnError::InvalidSerial(_) => "invalid_serial",
+            SnError::Checksum => "checksum",
+        }
+    }
+}
+
+impl fmt::Display for SnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnError::Length(n) => write!(f, "sn length {} != {}", n, SN_LEN),
+            SnError::UnknownProduct(p) => write!(f, "unknown product id {:?}", p),
+            SnError::InvalidDate(d) => write!(f, "invalid date {}", d),
+            SnError::InvalidSerial(s) => write!(f, "invalid serial {:?}", s),
+            SnError::Checksum => write!(f, "checksum mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for SnError {}
+
+/// 解析后的SN
+#[derive(Debug, Clone, PartialEq)]
+pub struct SerialNumber {
+    pub product: Product,
+    pub date: NaiveDate,
+    pub serial: u16,
+    pub checksum: String,
+}
+
+impl SerialNumber {
+    /// 不含校验位的前10位
+    pub fn raw(&self) -> String {
+        raw_sn(self.product, self.date, self.serial)
+    }
+}
+
+impl fmt::Display for SerialNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.raw().to_uppercase(), self.checksum)
+    }
+}
+
+fn mark_value(c: char) -> Option<u32> {
+    MARK_CHARS.iter().position(|m| *m as char == c).map(|i| i as u32)
+}
+
+/// 生成3位日期标识，日期超出2020-2045范围返回None
+pub fn date_mark(date: NaiveDate) -> Option<String> {
+    let year = date.year() - FIRST_YEAR;
+    if !(0..26).contains(&year) {
+        return None;
+    }
+    Some(format!(
+        "{}{}{}",
+        MARK_CHARS[10 + year as usize] as char,
+        MARK_CHARS[date.month() as usize] as char,
+        MARK_CHARS[date.day() as usize] as char,
+    ))
+}
+
+/// 解析3位日期标识，校验大小月与闰年
+pub fn parse_date_mark(mark: &str) -> Result<NaiveDate, SnError> {
+    let err = || SnError::InvalidDate(mark.to_string());
+    let chars: Vec<char> = mark.chars().collect();
+    if chars.len() != 3 {
+        return Err(err());
+    }
+    let year = match mark_value(chars[0]) {
+        Some(v) if v >= 10 => FIRST_YEAR + (v - 10) as i32,
+        _ => return Err(err()),
+    };
+    let month = mark_value(chars[1]).ok_or_else(err)?;
+    let day = mark_value(chars[2]).ok_or_else(err)?;
+    NaiveDate::from_ymd_opt(year, month, day).ok_or_else(err)
+}
+
+fn raw_sn(product: Product, date: NaiveDate, serial: u16) -> String {
+    // 与python脚本一致: 流水号以小写十六进制参与校验计算
+    format!("{}{}{:04x}", product, date_mark(date).unwrap_or_default(), serial)
+}
+
+fn checksum(raw: &str, salt: &str) -> String {
+    let digest = format!("{:x}", md5::compute(format!("{}{}", raw, salt)));
+    digest[digest.len() - 3..].to_uppercase()
+}
+
+/// 按SN规范生成加盐SN
+pub fn generate(product: Product, date: NaiveDate, serial: u16, salt: &str) -> Result<String, SnError> {
+    if date_mark(date).is_none() {
+        return Err(SnError::InvalidDate(date.to_string()));
+    }
+    let raw = raw_sn(product, date, serial);
+    Ok(format!("{}{}", raw.to_uppercase(), checksum(&raw, salt)))
+}
+
+/// 拆解SN为产品、日期、流水号，不校验校验位
+pub fn parse(sn: &str) -> Result<SerialNumber, SnError> {
+    if sn.len() != SN_LEN || !sn.is_ascii() {
+        return Err(SnError::Length(sn.chars().count()));
+    }
+    let sn = sn.to_uppercase();
+    let product: Product = sn[0..3].parse()?;
+    let date = parse_date_mark(&sn[3..6])?;
+    let serial_str = &sn[6..10];
+    if !serial_str.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(SnError::InvalidSerial(serial_str.to_string()));
+    }
+    let serial = u16::from_str_radix(serial_str, 16).map_err(|_| SnError::InvalidSerial(serial_str.to_string()))?;
+    Ok(SerialNumber { product, date, serial, checksum: sn[10..].to_string() })
+}
+
+/// 拆解SN并用指定salt校验加盐MD5
+pub fn verify(sn: &str, salt: &str) -> Result<SerialNumber, SnError> {
+    let parsed = parse(sn)?;
+    if checksum(&parsed.raw(), salt) != parsed.checksum {
+        return Err(SnError::Checksum);
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod sn_test {
+    use chrono::NaiveDate;
+
+    use super::{date_mark, generate, parse, parse_date_mark, verify, Product, SnError};
+
+    const SALT: &str = "anbwscx";
+
+    #[test]
+    fn test_date_mark() {
+        let d = NaiveDate::from_ymd_opt(2020, 12, 31).unwrap();
+        assert_eq!(date_mark(d), Some("ACV".to_string()));
+        assert_eq!(parse_date_mark("ACV"), Ok(d));
+        assert_eq!(date_mark(NaiveDate::from_ymd_opt(2021, 1, 9).unwrap()), Some("B19".to_string()));
+        // 2021非闰年，4月没有31日
+        assert!(parse_date_mark("B2T").is_err());
+        assert!(parse_date_mark("B4V").is_err());
+        assert!(parse_date_mark("ADA").is_err());
+        assert!(parse_date_mark("10A").is_err());
+        assert_eq!(parse_date_mark("E2T"), Ok(NaiveDate::from_ymd_opt(2024, 2, 29).unwrap()));
+        assert_eq!(date_mark(NaiveDate::from_ymd_opt(2046, 1, 1).unwrap()), None);
+    }
+
+    #[test]
+    fn test_generate_matches_python() {
+        // 由doc/generate-salt-sn.py生成
+        let d = NaiveDate::from_ymd_opt(2020, 11, 12).unwrap();
+        assert_eq!(generate(Product::A0A, d, 10, SALT).unwrap(), "A0AABC000ACD4");
+        let d = NaiveDate::from_ymd_opt(2021, 2, 28).unwrap();
+        assert_eq!(generate(Product::A2E, d, 0xbeef, SALT).unwrap(), "A2EB2SBEEFEF9");
+    }
+
+    #[test]
+    fn test_verify() {
+        let d = NaiveDate::from_ymd_opt(2021, 2, 28).unwrap();
+        let sn = generate(Product::A2E, d, 0xbeef, SALT).unwrap();
+        let parsed = verify(&sn, SALT).unwrap();
+        assert_eq!(parsed.product, Product::A2E);
+        assert_eq!(parsed.date, d);
+        assert_eq!(parsed.serial, 0xbeef);
+        assert_eq!(parsed.to_string(), sn);
+        assert_eq!(verify(&sn, "other"), Err(SnError::Checksum));
+
+        assert_eq!(parse("A0AABC000A"), Err(SnError::Length(10)));
+        assert_eq!(parse("A3FABC000A123").unwrap_err().reason(), "unknown_product");
+        assert_eq!(parse("A0AABC00GA123").unwrap_err().reason(), "invalid_serial");
+        assert_eq!(parse("A0AA0C000A123").unwrap_err().reason(), "invalid_date");
+    }
+}
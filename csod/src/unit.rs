@@ -0,0 +1,341 @@
+//!
+//! # 端单元状态编解码
+//! 端单元地址与status定义见doc/设备抽象模型.md:
+//! ```text
+//! 开关:       "01${id}", status: {1byte}, 关: 00, 开: 01
+//! 温度:       "02${id}", status: {1byte}{1byte}{1byte} 符号、整数、小数
+//! 湿度:       "03${id}", status: {1byte}{1byte}{1byte} 符号、整数、小数(最大0x63)
+//! 开关定时器: "04${id}", status: {"date":"y-m-d:h-m-s", "target":"${开关状态}"}
+//! 延时开关:   "05${id}", status: {"delay":${time}, "target": "${开关状态}"}
+//! ```
+//!
+//! Example
+//! ```
+//! use csod::message::UnitAddr;
+//! use csod::unit::{decode, UnitStatus};
+//!
+//! let addr: UnitAddr = "022".parse().unwrap();
+//! let status = decode(&addr, &serde_json::json!("000a0a")).unwrap();
+//! assert_eq!(status.to_string(), "Temperature(+10.10)");
+//! ```
+//!
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use chrono::NaiveDateTime;
+use serde_json::{json, Value};
+
+use crate::message::UnitAddr;
+
+/// 定时器date字段格式，如"2020-11-12:12-30-00"
+pub const TIMER_DATE_FORMAT: &str = "%Y-%m-%d:%H-%M-%S";
+
+/// 端单元类型，即端单元地址的前2位
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnitKind {
+    Switch,
+    Temperature,
+    Humidity,
+    Timer,
+    Delay,
+}
+
+impl UnitKind {
+    pub fn from_addr(addr: &UnitAddr) -> Option<UnitKind> {
+        match addr.kind {
+            0x01 => Some(UnitKind::Switch),
+            0x02 => Some(UnitKind::Temperature),
+            0x03 => Some(UnitKind::Humidity),
+            0x04 => Some(UnitKind::Timer),
+            0x05 => Some(UnitKind::Delay),
+            _ => None,
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        match self {
+            UnitKind::Switch => 0x01,
+            UnitKind::Temperature => 0x02,
+            UnitKind::Humidity => 0x03,
+            UnitKind::Timer => 0x04,
+            UnitKind::Delay => 0x05,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UnitError {
+    /// 未定义的端单元类型
+    UnknownUnit(UnitAddr),
+    /// status格式错误
+    Malformed { addr: UnitAddr, reason: String },
+}
+
+impl UnitError {
+    fn malformed(addr: &UnitAddr, reason: String) -> UnitError {
+        UnitError::Malformed { addr: *addr, reason }
+    }
+}
+
+impl fmt::Display for UnitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnitError::UnknownUnit(addr) => write!(f, "unknown unit {}", addr),
+            UnitError::Malformed { addr, reason } => write!(f, "malformed status of unit {}: {}", addr, reason),
+        }
+    }
+}
+
+impl std::error::Error for UnitError {}
+
+/// 开关状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwitchState {
+    Off,
+    On,
+}
+
+impl SwitchState {
+    fn decode(addr: &UnitAddr, s: &str) -> Result<SwitchState, UnitError> {
+        match s {
+            "00" => Ok(SwitchState::Off),
+            "01" => Ok(SwitchState::On),
+            _ => Err(UnitError::malformed(addr, format!("invalid switch state {:?}", s))),
+        }
+    }
+
+    fn encode(&self) -> &'static str {
+        match self {
+            SwitchState::Off => "00",
+            SwitchState::On => "01",
+        }
+    }
+}
+
+/// 温度、湿度读数: 符号、整数部分、两位小数部分
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reading {
+    pub negative: bool,
+    pub int: u8,
+    pub frac: u8,
+}
+
+impl Reading {
+    pub fn new(negative: bool, int: u8, frac: u8) -> Option<Reading> {
+        if frac > 99 {
+            return None;
+        }
+        Some(Reading { negative, int, frac })
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let v = self.int as f64 + self.frac as f64 / 100.0;
+        if self.negative { -v } else { v }
+    }
+
+    fn decode(addr: &UnitAddr, s: &str) -> Result<Reading, UnitError> {
+        if s.len() != 6 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(UnitError::malformed(addr, format!("expect 3 hex bytes, got {:?}", s)));
+        }
+        let byte = |i: usize| u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).unwrap_or(0);
+        let negative = match byte(0) {
+            0x00 => false,
+            0x01 => true,
+            b => return Err(UnitError::malformed(addr, format!("invalid sign byte {:02x}", b))),
+        };
+        Reading::new(negative, byte(1), byte(2))
+            .ok_or_else(|| UnitError::malformed(addr, format!("fraction {:02x} out of range", byte(2))))
+    }
+
+    fn encode(&self) -> String {
+        format!("{:02x}{:02x}{:02x}", self.negative as u8, self.int, self.frac)
+    }
+}
+
+impl fmt::Display for Reading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}.{:02}", if self.negative { "-" } else { "+" }, self.int, self.frac)
+    }
+}
+
+/// 端单元状态
+#[derive(Debug, Clone, PartialEq)]
+pub enum UnitStatus {
+    Switch(SwitchState),
+    Temperature(Reading),
+    Humidity(Reading),
+    Timer { date: NaiveDateTime, target: SwitchState },
+    /// 延时单位为秒
+    Delay { delay: u32, target: SwitchState },
+}
+
+impl UnitStatus {
+    pub fn kind(&self) -> UnitKind {
+        match self {
+            UnitStatus::Switch(_) => UnitKind::Switch,
+            UnitStatus::Temperature(_) => UnitKind::Temperature,
+            UnitStatus::Humidity(_) => UnitKind::Humidity,
+            UnitStatus::Timer { .. } => UnitKind::Timer,
+            UnitStatus::Delay { .. } => UnitKind::Delay,
+        }
+    }
+}
+
+impl fmt::Display for UnitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnitStatus::Switch(s) => write!(f, "Switch({:?})", s),
+            UnitStatus::Temperature(r) => write!(f, "Temperature({})", r),
+            UnitStatus::Humidity(r) => write!(f, "Humidity({})", r),
+            UnitStatus::Timer { date, target } => {
+                write!(f, "Timer({} -> {:?})", date.format(TIMER_DATE_FORMAT), target)
+            }
+            UnitStatus::Delay { delay, target } => write!(f, "Delay({}s -> {:?})", delay, target),
+        }
+    }
+}
+
+fn as_str<'a>(addr: &UnitAddr, status: &'a Value) -> Result<&'a str, UnitError> {
+    status.as_str().ok_or_else(|| UnitError::malformed(addr, format!("expect string, got {}", status)))
+}
+
+fn field<'a>(addr: &UnitAddr, status: &'a Value, name: &str) -> Result<&'a Value, UnitError> {
+    status.get(name).ok_or_else(|| UnitError::malformed(addr, format!("missing field {:?}", name)))
+}
+
+/// 按端单元地址解析status
+pub fn decode(addr: &UnitAddr, status: &Value) -> Result<UnitStatus, UnitError> {
+    let kind = UnitKind::from_addr(addr).ok_or(UnitError::UnknownUnit(*addr))?;
+    match kind {
+        UnitKind::Switch => Ok(UnitStatus::Switch(SwitchState::decode(addr, as_str(addr, status)?)?)),
+        UnitKind::Temperature => Ok(UnitStatus::Temperature(Reading::decode(addr, as_str(addr, status)?)?)),
+        UnitKind::Humidity => {
+            let r = Reading::decode(addr, as_str(addr, status)?)?;
+            if r.negative || r.int > 100 || (r.int == 100 && r.frac > 0) {
+                return Err(UnitError::malformed(addr, format!("humidity {} out of range", r)));
+            }
+            Ok(UnitStatus::Humidity(r))
+        }
+        UnitKind::Timer => {
+            let date = as_str(addr, field(addr, status, "date")?)?;
+            let date = NaiveDateTime::parse_from_str(date, TIMER_DATE_FORMAT)
+                .map_err(|e| UnitError::malformed(addr, format!("invalid date {:?}: {}", date, e)))?;
+            let target = SwitchState::decode(addr, as_str(addr, field(addr, status, "target")?)?)?;
+            Ok(UnitStatus::Timer { date, target })
+        }
+        UnitKind::Delay => {
+            // 文档示例中延时为字符串"100"，也兼容数字
+            let delay = match field(addr, status, "delay")? {
+                Value::String(s) => s.trim().parse::<u32>().ok(),
+                Value::Number(n) => n.as_u64().filter(|v| *v <= u32::MAX as u64).map(|v| v as u32),
+                _ => None,
+            };
+            let delay = delay.ok_or_else(|| UnitError::malformed(addr, "invalid delay".to_string()))?;
+            let target = SwitchState::decode(addr, as_str(addr, field(addr, status, "target")?)?)?;
+            Ok(UnitStatus::Delay { delay, target })
+        }
+    }
+}
+
+/// 将端单元状态编码为status
+pub fn encode(status: &UnitStatus) -> Value {
+    match status {
+        UnitStatus::Switch(s) => json!(s.encode()),
+        UnitStatus::Temperature(r) | UnitStatus::Humidity(r) => json!(r.encode()),
+        UnitStatus::Timer { date, target } => json!({
+            "date": date.format(TIMER_DATE_FORMAT).to_string(),
+            "target": target.encode(),
+        }),
+        UnitStatus::Delay { delay, target } => json!({
+            "delay": delay.to_string(),
+            "target": target.encode(),
+        }),
+    }
+}
+
+/// 解析一条消息内所有端单元的status
+pub fn decode_all(units: &BTreeMap<UnitAddr, Value>) -> Result<BTreeMap<UnitAddr, UnitStatus>, UnitError> {
+    units.iter().map(|(addr, status)| decode(addr, status).map(|s| (*addr, s))).collect()
+}
+
+/// get消息只携带端单元地址，status为空字符串
+pub fn check_get(units: &BTreeMap<UnitAddr, Value>) -> Result<(), UnitError> {
+    for (addr, status) in units {
+        if UnitKind::from_addr(addr).is_none() {
+            return Err(UnitError::UnknownUnit(*addr));
+        }
+        if status != &json!("") {
+            return Err(UnitError::malformed(addr, format!("get expects empty status, got {}", status)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod unit_test {
+    use chrono::NaiveDate;
+    use serde_json::json;
+
+    use super::{decode, encode, Reading, SwitchState, UnitError, UnitStatus};
+    use crate::message::UnitAddr;
+
+    fn addr(s: &str) -> UnitAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_switch() {
+        assert_eq!(decode(&addr("010"), &json!("01")), Ok(UnitStatus::Switch(SwitchState::On)));
+        assert_eq!(decode(&addr("011"), &json!("00")), Ok(UnitStatus::Switch(SwitchState::Off)));
+        assert!(decode(&addr("010"), &json!("02")).is_err());
+        assert!(decode(&addr("010"), &json!(1)).is_err());
+        assert_eq!(encode(&UnitStatus::Switch(SwitchState::On)), json!("01"));
+    }
+
+    #[test]
+    fn test_reading() {
+        let t = decode(&addr("022"), &json!("000a0a")).unwrap();
+        assert_eq!(t.to_string(), "Temperature(+10.10)");
+        let t = decode(&addr("020"), &json!("010105")).unwrap();
+        assert_eq!(t, UnitStatus::Temperature(Reading::new(true, 1, 5).unwrap()));
+        assert_eq!(t.to_string(), "Temperature(-1.05)");
+        assert_eq!(encode(&t), json!("010105"));
+        // 符号位、小数部分、长度错误
+        assert!(decode(&addr("020"), &json!("020a0a")).is_err());
+        assert!(decode(&addr("020"), &json!("000a64")).is_err());
+        assert!(decode(&addr("020"), &json!("000a0")).is_err());
+        assert!(decode(&addr("020"), &json!("000a0z")).is_err());
+
+        let h = decode(&addr("030"), &json!("002d63")).unwrap();
+        assert_eq!(h.to_string(), "Humidity(+45.99)");
+        assert!(decode(&addr("030"), &json!("012d00")).is_err());
+        // 上限100.00
+        assert_eq!(decode(&addr("030"), &json!("006400")).unwrap().to_string(), "Humidity(+100.00)");
+        assert!(decode(&addr("030"), &json!("006401")).is_err());
+        assert!(decode(&addr("030"), &json!("006500")).is_err());
+        assert_eq!(decode(&addr("030"), &json!("006363")).unwrap().to_string(), "Humidity(+99.99)");
+    }
+
+    #[test]
+    fn test_timer_delay() {
+        let status = json!({"date": "2020-11-12:12-30-00", "target": "01"});
+        let timer = decode(&addr("040"), &status).unwrap();
+        let date = NaiveDate::from_ymd_opt(2020, 11, 12).unwrap().and_hms_opt(12, 30, 0).unwrap();
+        assert_eq!(timer, UnitStatus::Timer { date, target: SwitchState::On });
+        assert_eq!(encode(&timer), status);
+        assert!(decode(&addr("040"), &json!({"date": "2020-02-30:12-30-00", "target": "01"})).is_err());
+        assert!(decode(&addr("040"), &json!({"date": "2020-11-12:12-30-00"})).is_err());
+
+        let delay = decode(&addr("050"), &json!({"delay": "100", "target": "01"})).unwrap();
+        assert_eq!(delay, UnitStatus::Delay { delay: 100, target: SwitchState::On });
+        assert_eq!(decode(&addr("050"), &json!({"delay": 100, "target": "01"})), Ok(delay.clone()));
+        assert_eq!(encode(&delay), json!({"delay": "100", "target": "01"}));
+        assert!(decode(&addr("050"), &json!({"delay": -1, "target": "01"})).is_err());
+    }
+
+    #[test]
+    fn test_unknown_unit() {
+        assert_eq!(decode(&addr("0a0"), &json!("01")), Err(UnitError::UnknownUnit(addr("0a0"))));
+    }
+}
@@ -24,6 +24,8 @@ use loge;
 use serde_derive::Deserialize;
 use toml;
 
+use csod::sku::{SkuError, SkuRule};
+
 #[derive(Deserialize)]
 #[derive(Debug)]
 #[derive(Clone)]
@@ -39,6 +41,19 @@ pub struct LogConfig {
 pub struct HttpServiceConfig {
     pub ip: Option<String>,
     pub port: Option<String>,
+    pub downlink_max_depth: Option<usize>,
+    pub offline_max_ttl: Option<u64>,
+    pub sku_rule: Option<String>,
+}
+
+impl HttpServiceConfig {
+    /// 出厂登记时生成SKU的规则，未配置时使用`csod::sku::DEFAULT_SKU_RULE`
+    pub fn sku_rule(&self) -> Result<SkuRule, SkuError> {
+        match &self.sku_rule {
+            Some(rule) => rule.parse(),
+            None => Ok(SkuRule::default()),
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -47,6 +62,7 @@ pub struct HttpServiceConfig {
 pub struct RedisConfig {
     pub ip: Option<String>,
     pub port: Option<String>,
+    pub pool_size: Option<usize>,
 }
 
 #[derive(Deserialize)]
@@ -65,9 +81,7 @@ pub fn log_init(log_cfg: LogConfig) {
     let outfile: Option<String> = log_cfg.outfile;
     let format: Option<String> = log_cfg.format;
     match &level {
-        Some(le) if le == &"none".to_string() => {
-            return;
-        }
+        Some(le) if le == "none" => {}
         _ => {
             if let Some(le) = level {
                 env::set_var("RUST_LOG", le);
@@ -90,7 +104,7 @@ pub fn log_init(log_cfg: LogConfig) {
 pub fn load_config(toml_path: &str, verbose: bool) -> Config {
     let mut file = File::open(toml_path).unwrap();
     let mut file_content = String::new();
-    if let Err(_) = file.read_to_string(&mut file_content) {
+    if file.read_to_string(&mut file_content).is_err() {
         panic!("read config error")
     }
     let config: Config = match toml::from_str(&file_content) {
@@ -116,7 +130,7 @@ pub fn load_config(toml_path: &str, verbose: bool) -> Config {
             "Null".to_string()
         });
 
-        println!("[http service]: \n\tip = {:?}\n\tport = {:?}", if let Some(e) = &config.http_service.ip {
+        println!("[http service]: \n\tip = {:?}\n\tport = {:?}\n\tdownlink_max_depth = {:?}\n\toffline_max_ttl = {:?}\n\tsku_rule = {:?}", if let Some(e) = &config.http_service.ip {
             e.clone()
         } else {
             "Null".to_string()
@@ -124,9 +138,21 @@ pub fn load_config(toml_path: &str, verbose: bool) -> Config {
             e.clone()
         } else {
             "Null".to_string()
+        }, if let Some(e) = &config.http_service.downlink_max_depth {
+            format!("{}", &e)
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.http_service.offline_max_ttl {
+            format!("{}", &e)
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.http_service.sku_rule {
+            e.clone()
+        } else {
+            "Null".to_string()
         });
 
-        println!("[redis]: \n\tip = {:?}\n\tport = {:?}", if let Some(e) = &config.redis.ip {
+        println!("[redis]: \n\tip = {:?}\n\tport = {:?}\n\tpool_size = {:?}", if let Some(e) = &config.redis.ip {
             e.clone()
         } else {
             "Null".to_string()
@@ -134,6 +160,10 @@ pub fn load_config(toml_path: &str, verbose: bool) -> Config {
             e.clone()
         } else {
             "Null".to_string()
+        }, if let Some(e) = &config.redis.pool_size {
+            format!("{}", &e)
+        } else {
+            "Null".to_string()
         });
         println!("===========================================");
     }
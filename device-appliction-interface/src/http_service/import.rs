@@ -0,0 +1,71 @@
+//!
+//! # 导入出厂登记
+//! 将产线脚本输出的`*-finished.csv`导入redis，与`POST /registry/import`相同
+//!
+//! ```sh
+//! device-appliction-interface import sn-A0A-20201112-finished.csv --batch A0A-20201112
+//! ```
+//!
+
+use std::fs;
+use std::path::Path;
+
+use csod::registry;
+use csod::sku::SkuRule;
+
+use crate::common::config;
+use crate::middleware::{
+    query_redis as qr,
+    redis_wrapper::RedisPool,
+};
+
+/// 文件名去掉扩展名与`-finished`后缀
+fn default_batch(path: &str) -> String {
+    let stem = Path::new(path).file_stem().and_then(|s| s.to_str()).unwrap_or(path);
+    stem.trim_end_matches("-finished").to_string()
+}
+
+pub fn run(argv: &[String], rule: SkuRule, redisconf: config::RedisConfig) -> Result<(), String> {
+    let mut file = None;
+    let mut batch = None;
+    let mut date = None;
+
+    let mut it = argv.iter();
+    while let Some(arg) = it.next() {
+        let mut value = || it.next().cloned().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--batch" => batch = Some(value()?),
+            "--date" => date = Some(registry::parse_date(&value()?).map_err(|e| e.to_string())?),
+            _ if file.is_none() && !arg.starts_with("--") => file = Some(arg.clone()),
+            _ => return Err(format!("unknown argument {:?}", arg)),
+        }
+    }
+    let file = file.ok_or_else(|| "missing csv file".to_string())?;
+    let batch = batch.unwrap_or_else(|| default_batch(&file));
+    let date = date.unwrap_or_else(|| chrono::Local::now().date_naive());
+
+    let content = fs::read_to_string(&file).map_err(|e| format!("read {} failed: {}", file, e))?;
+    let (records, errors) = registry::parse_finished_csv(&content, &batch, date, &rule);
+    for (line, e) in errors.iter() {
+        eprintln!("{}:{}: {}", file, line, e);
+    }
+    if records.is_empty() {
+        return Err(format!("no valid record in {}", file));
+    }
+
+    let mut rt = tokio::runtime::Runtime::new().map_err(|e| e.to_string())?;
+    let summary = rt.block_on(async {
+        let pool = RedisPool::new(
+            &redisconf.ip.unwrap_or("127.0.0.1".to_string()),
+            &redisconf.port.unwrap_or("6379".to_string()),
+            1,
+        ).await.map_err(|e| format!("connect redis failed: {:?}", e))?;
+        qr::register_devices(&pool, &records).await
+    })?;
+    for (sn, e) in summary.conflicts.iter() {
+        eprintln!("{}: {}", sn, e);
+    }
+    eprintln!("imported {} sn of batch {} ({} new, {} updated, {} conflicts, {} invalid lines skipped)",
+              summary.added + summary.updated, batch, summary.added, summary.updated, summary.conflicts.len(), errors.len());
+    Ok(())
+}
@@ -13,12 +13,19 @@ use actix_web::{
 use futures::StreamExt;
 #[allow(unused_imports)]
 use log::{error, info, warn};
+use serde::Deserialize;
 use serde_json::json;
 
+use csod::code::ErrorCode;
+use csod::registry;
+use csod::sku::SkuRule;
+use csod::sn;
+
 use crate::common::config;
 use crate::middleware::{
     json_warpper as jw,
     query_redis as qr,
+    redis_wrapper::RedisPool,
 };
 
 #[get("/query/service_version")]
@@ -32,8 +39,8 @@ async fn query_service_version() -> Result<HttpResponse, Error> {
 
 
 #[get("/query/devices_num")]
-async fn query_devices_num() -> Result<HttpResponse, Error> {
-    let rv = qr::get_devices_num().await;
+async fn query_devices_num(pool: web::Data<RedisPool>) -> Result<HttpResponse, Error> {
+    let rv = qr::get_devices_num(&pool).await;
     let resp = if let Ok(e) = rv {
         json!({
             "namespace": "/query/devices_num".to_string(),
@@ -44,7 +51,8 @@ async fn query_devices_num() -> Result<HttpResponse, Error> {
         json!({
             "namespace": "/query/devices_num".to_string(),
             "status": "404",
-            "error": "no value"
+            "error": "no value",
+            "code": ErrorCode::InternalError.code()
         })
     };
 
@@ -52,8 +60,8 @@ async fn query_devices_num() -> Result<HttpResponse, Error> {
 }
 
 #[get("/query/devices_alive_num")]
-async fn query_devices_alive_num() -> Result<HttpResponse, Error> {
-    let rv = qr::get_alive_devices_num().await;
+async fn query_devices_alive_num(pool: web::Data<RedisPool>) -> Result<HttpResponse, Error> {
+    let rv = qr::get_alive_devices_num(&pool).await;
     let resp = if let Ok(e) = rv {
         json!({
             "namespace": "/query/device_alive_num".to_string(),
@@ -64,7 +72,8 @@ async fn query_devices_alive_num() -> Result<HttpResponse, Error> {
         json!({
             "namespace": "/query/device_alive_num".to_string(),
             "status": "404",
-            "error": "no value"
+            "error": "no value",
+            "code": ErrorCode::InternalError.code()
         })
     };
 
@@ -73,11 +82,12 @@ async fn query_devices_alive_num() -> Result<HttpResponse, Error> {
 
 #[get("/query/device_is_alive/{sn}")]
 async fn query_device_is_alive(
+    pool: web::Data<RedisPool>,
     info: web::Path<String>,
 ) -> Result<HttpResponse, Error> {
     let sn = info.to_string();
 
-    let resp = if qr::sn_is_alive(&sn).await {
+    let resp = if qr::sn_is_alive(&pool, &sn).await {
         json!({
             "namespace": "/query/device_is_alive".to_string(),
             "status": "200",
@@ -96,10 +106,85 @@ async fn query_device_is_alive(
     Ok(HttpResponse::Ok().body(resp))
 }
 
+#[get("/query/downlink_depth/{sn}")]
+async fn query_downlink_depth(
+    pool: web::Data<RedisPool>,
+    httpconf: web::Data<config::HttpServiceConfig>,
+    info: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let sn = info.to_string();
+
+    let resp = match qr::get_downlink_depth(&pool, &sn).await {
+        Ok(depth) => json!({
+            "namespace": "/query/downlink_depth".to_string(),
+            "status": "200",
+            "sn": sn,
+            "value": format!("{}", depth),
+            "max": format!("{}", httpconf.downlink_max_depth.unwrap_or(qr::DEFAULT_DOWNLINK_MAX_DEPTH)),
+        }),
+        Err(_) => json!({
+            "namespace": "/query/downlink_depth".to_string(),
+            "status": "404",
+            "sn": sn,
+            "error": "no value",
+            "code": ErrorCode::InternalError.code()
+        }),
+    };
+
+    Ok(HttpResponse::Ok().body(resp))
+}
+
+#[get("/query/push_result/{sn}/{id}")]
+async fn query_push_result(
+    pool: web::Data<RedisPool>,
+    info: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (sn, id) = info.into_inner();
+
+    let resp = match qr::get_command_result(&pool, &sn, &id).await {
+        Ok(Some(result)) => json!({
+            "namespace": "/query/push_result".to_string(),
+            "status": "200",
+            "sn": sn,
+            "id": id,
+            "value": result.status,
+            "expire_at": format!("{}", result.expire_at),
+            "ack": result.ack,
+        }),
+        Ok(None) => json!({
+            "namespace": "/query/push_result".to_string(),
+            "status": "404",
+            "sn": sn,
+            "id": id,
+            "error": "no value",
+            "code": ErrorCode::NotFound.code()
+        }),
+        Err(_) => json!({
+            "namespace": "/query/push_result".to_string(),
+            "status": "404",
+            "sn": sn,
+            "id": id,
+            "error": "no value",
+            "code": ErrorCode::InternalError.code()
+        }),
+    };
+
+    Ok(HttpResponse::Ok().body(resp))
+}
+
 const MAX_SIZE: usize = 262_144;
 
+/// `ttl`: 设备离线时暂存命令的有效期(秒)，不带则设备离线直接返回失败
+#[derive(Deserialize)]
+struct PushParams {
+    ttl: Option<u64>,
+}
+
 #[post("/push/push_msg")]
 async fn push_get(
+    pool: web::Data<RedisPool>,
+    httpconf: web::Data<config::HttpServiceConfig>,
+    params: web::Query<PushParams>,
     mut payload: web::Payload,
 ) -> Result<HttpResponse, Error> {
     // payload is a stream of Bytes objects
@@ -111,7 +196,8 @@ async fn push_get(
             return Err(error::ErrorBadRequest(json!({
                 "namespace": "/push/push_msg",
                 "status": "404",
-                "error": "overflow"
+                "error": "overflow",
+                "code": ErrorCode::PayloadTooLarge.code()
             })));
         }
         body.extend_from_slice(&chunk);
@@ -119,73 +205,645 @@ async fn push_get(
 
     match String::from_utf8(body.to_vec()) {
         Ok(body_string) => {
-            let sn = if let Some(sn) = jw::parse_sn(&body_string) {
-                sn
+            let msg = match jw::parse_downlink(&body_string) {
+                Ok(msg) => msg,
+                Err((code, e)) => {
+                    warn!("invaild request, {}", e);
+                    return Err(error::ErrorBadRequest(json!({
+                        "namespace": "/push/push_msg",
+                        "status": "404",
+                        "error": e,
+                        "code": code.code()
+                    })));
+                }
+            };
+            let sn = if let Some(sn) = msg.sn() {
+                sn.to_string()
             } else {
                 warn!("invaild request, have no sn field");
                 return Err(error::ErrorBadRequest(json!({
                     "namespace": "/push/push_msg",
                     "status": "404",
-                    "error": "have no sn field"
+                    "error": "have no sn field",
+                    "code": ErrorCode::MissingSn.code()
                 })));
             };
             info!("push get device({})", sn);
-            if qr::sn_is_alive(&sn).await == false {
-                return Err(error::ErrorBadRequest(json!({
-                    "namespace": "/push/push_msg",
-                    "status": "404",
-                    "error": "device offline"
-                })));
+            let max_depth = httpconf.downlink_max_depth.unwrap_or(qr::DEFAULT_DOWNLINK_MAX_DEPTH);
+            if !qr::sn_is_alive(&pool, &sn).await {
+                let ttl = match params.ttl {
+                    Some(ttl) if ttl > 0 => ttl.min(httpconf.offline_max_ttl.unwrap_or(qr::DEFAULT_OFFLINE_MAX_TTL)),
+                    _ => {
+                        return Err(error::ErrorBadRequest(json!({
+                            "namespace": "/push/push_msg",
+                            "status": "404",
+                            "error": "device offline",
+                            "code": ErrorCode::DeviceOffline.code()
+                        })));
+                    }
+                };
+                // 设备离线，暂存命令等待设备上线后下发
+                return match qr::store_for_offline(&pool, &sn, msg, ttl, max_depth).await {
+                    Ok(id) => Ok(HttpResponse::Accepted().body(json!({
+                        "namespace": "/push/push_msg",
+                        "status": "202",
+                        "id": id.as_str(),
+                        "value": qr::COMMAND_QUEUED,
+                        "ttl": format!("{}", ttl),
+                    }))),
+                    Err(e @ qr::TransmitError::QueueFull(_)) => Err(error::ErrorBadRequest(json!({
+                        "namespace": "/push/push_msg",
+                        "status": "429",
+                        "error": format!("{}", e),
+                        "code": e.code().code()
+                    }))),
+                    Err(e) => Err(error::ErrorBadRequest(json!({
+                        "namespace": "/push/push_msg",
+                        "status": "408",
+                        "error": format!("store message fail {}", e),
+                        "code": e.code().code()
+                    }))),
+                };
             }
-            match qr::transparent_transmit_wit_ack(&sn, &body_string).await {
+            match qr::transparent_transmit_wit_ack(&pool, &sn, msg, max_depth).await {
                 Ok(Some(resp)) => {
                     let resp = json!({
                                     "namespace": "/push/push_msg",
                                     "status": "200",
                                     "value":  resp
                                 });
-                    return Ok(HttpResponse::Ok().body(resp));
+                    Ok(HttpResponse::Ok().body(resp))
                 }
                 Ok(None) => {
-                    return Err(error::ErrorBadRequest(json!({
+                    Err(error::ErrorBadRequest(json!({
                                     "namespace": "/push/push_msg",
                                     "status": "404",
-                                    "error": "no response"
-                                })));
+                                    "error": "no response",
+                                    "code": ErrorCode::NoResponse.code()
+                                })))
+                }
+                Err(e @ qr::TransmitError::QueueFull(_)) => {
+                    Err(error::ErrorBadRequest(json!({
+                                "namespace": "/push/push_msg",
+                                "status": "429",
+                                "error": format!("{}", e),
+                                "code": e.code().code()
+                            })))
                 }
                 Err(e) => {
-                    return Err(error::ErrorBadRequest(json!({
+                    Err(error::ErrorBadRequest(json!({
                                 "namespace": "/push/push_msg",
                                 "status": "408",
-                                "error": format!("send message fail {}", e)
-                            })));
+                                "error": format!("send message fail {}", e),
+                                "code": e.code().code()
+                            })))
                 }
             }
         }
         _ => {
-            return Err(error::ErrorBadRequest(json!({
+            Err(error::ErrorBadRequest(json!({
                 "namespace": "/push/push_msg",
-                "error": "invalid data"
-                })));
+                "error": "invalid data",
+                "code": ErrorCode::InvalidEncoding.code()
+                })))
+        }
+    }
+}
+
+#[get("/query/registered_num")]
+async fn query_registered_num(pool: web::Data<RedisPool>) -> Result<HttpResponse, Error> {
+    let resp = match qr::get_registered_num(&pool).await {
+        Ok(n) => json!({
+            "namespace": "/query/registered_num",
+            "status": "200",
+            "value": format!("{}", n)
+        }),
+        Err(_) => json!({
+            "namespace": "/query/registered_num",
+            "status": "404",
+            "error": "no value",
+            "code": ErrorCode::InternalError.code()
+        }),
+    };
+
+    Ok(HttpResponse::Ok().body(resp))
+}
+
+#[get("/query/registration/{sn}")]
+async fn query_registration(
+    pool: web::Data<RedisPool>,
+    info: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    // 登记时sn已按SN规范校验并转为大写，不符合规范的sn不会登记
+    let sn = match sn::parse(&info) {
+        Ok(_) => info.to_uppercase(),
+        Err(e) => {
+            return Ok(HttpResponse::Ok().body(json!({
+                "namespace": "/query/registration",
+                "status": "404",
+                "sn": info.as_str(),
+                "error": format!("invalid sn: {}", e),
+                "code": ErrorCode::InvalidRegistration.code()
+            })));
         }
+    };
+
+    let resp = match qr::get_registration(&pool, &sn).await {
+        Ok(Some((record, registered_at))) => json!({
+            "namespace": "/query/registration",
+            "status": "200",
+            "sn": sn,
+            "product": record.product.as_str(),
+            "batch": record.batch,
+            "mac": record.mac,
+            "sku": record.sku,
+            "flashed_at": record.flashed_at.to_string(),
+            "registered_at": format!("{}", registered_at),
+        }),
+        Ok(None) => json!({
+            "namespace": "/query/registration",
+            "status": "404",
+            "sn": sn,
+            "error": "not registered",
+            "code": ErrorCode::NotFound.code()
+        }),
+        Err(_) => json!({
+            "namespace": "/query/registration",
+            "status": "404",
+            "sn": sn,
+            "error": "no value",
+            "code": ErrorCode::InternalError.code()
+        }),
+    };
+
+    Ok(HttpResponse::Ok().body(resp))
+}
+
+/// `batch`: 批次，`date`: 没有烧写日期的行使用的日期，默认为当天
+#[derive(Deserialize)]
+struct ImportParams {
+    batch: String,
+    date: Option<String>,
+}
+
+/// 导入产线的登记文件，body为`*-finished.csv`的内容，出错的行不导入，在`errors`中返回
+#[post("/registry/import")]
+async fn registry_import(
+    pool: web::Data<RedisPool>,
+    rule: web::Data<SkuRule>,
+    params: web::Query<ImportParams>,
+    mut payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if (body.len() + chunk.len()) > MAX_SIZE {
+            return Err(error::ErrorBadRequest(json!({
+                "namespace": "/registry/import",
+                "status": "404",
+                "error": "overflow",
+                "code": ErrorCode::PayloadTooLarge.code()
+            })));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let content = match String::from_utf8(body.to_vec()) {
+        Ok(content) => content,
+        Err(_) => {
+            return Err(error::ErrorBadRequest(json!({
+                "namespace": "/registry/import",
+                "status": "404",
+                "error": "invalid data",
+                "code": ErrorCode::InvalidEncoding.code()
+            })));
+        }
+    };
+    let date = match &params.date {
+        Some(date) => registry::parse_date(date),
+        None => Ok(chrono::Local::now().date_naive()),
+    };
+    let date = match date {
+        Ok(date) => date,
+        Err(e) => {
+            return Err(error::ErrorBadRequest(json!({
+                "namespace": "/registry/import",
+                "status": "404",
+                "error": e.to_string(),
+                "code": ErrorCode::InvalidRegistration.code()
+            })));
+        }
+    };
+
+    let (records, errors) = registry::parse_finished_csv(&content, &params.batch, date, &rule);
+    let mut errors: Vec<_> = errors.iter().map(|(line, e)| json!({"line": line, "error": e.to_string()})).collect();
+    if records.is_empty() && !errors.is_empty() {
+        return Err(error::ErrorBadRequest(json!({
+            "namespace": "/registry/import",
+            "status": "404",
+            "error": "no valid record",
+            "code": ErrorCode::InvalidRegistration.code(),
+            "errors": errors
+        })));
+    }
+    match qr::register_devices(&pool, &records).await {
+        Ok(summary) => {
+            errors.extend(summary.conflicts.iter().map(|(sn, e)| json!({"sn": sn, "error": e})));
+            Ok(HttpResponse::Ok().body(json!({
+                "namespace": "/registry/import",
+                "status": "200",
+                "batch": params.batch,
+                "value": format!("{}", summary.added + summary.updated),
+                "new": format!("{}", summary.added),
+                "errors": errors
+            })))
+        }
+        Err(e) => Err(error::ErrorBadRequest(json!({
+            "namespace": "/registry/import",
+            "status": "408",
+            "error": e,
+            "code": ErrorCode::InternalError.code()
+        }))),
     }
 }
 
+/// 由SKU或MAC查到sn后，返回登记信息与在线状态
+async fn lookup_device(pool: &RedisPool, namespace: &str, key: &str) -> serde_json::Value {
+    let sn = match qr::lookup_sn(pool, key).await {
+        Ok(Some(sn)) => sn,
+        Ok(None) => return json!({
+            "namespace": namespace,
+            "status": "404",
+            "error": "not registered",
+            "code": ErrorCode::NotFound.code()
+        }),
+        Err(_) => return json!({
+            "namespace": namespace,
+            "status": "404",
+            "error": "no value",
+            "code": ErrorCode::InternalError.code()
+        }),
+    };
+
+    match qr::get_registration(pool, &sn).await {
+        Ok(Some((record, _))) => json!({
+            "namespace": namespace,
+            "status": "200",
+            "sn": sn,
+            "sku": record.sku,
+            "mac": record.mac,
+            "product": record.product.as_str(),
+            "batch": record.batch,
+            "flashed_at": record.flashed_at.to_string(),
+            "value": if qr::sn_is_alive(pool, &sn).await { "online" } else { "offline" },
+        }),
+        _ => json!({
+            "namespace": namespace,
+            "status": "404",
+            "sn": sn,
+            "error": "no value",
+            "code": ErrorCode::InternalError.code()
+        }),
+    }
+}
+
+#[get("/query/device_by_mac/{mac}")]
+async fn query_device_by_mac(
+    pool: web::Data<RedisPool>,
+    info: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let resp = match registry::parse_mac(&info) {
+        Ok(mac) => lookup_device(&pool, "/query/device_by_mac", &registry::mac_key(&mac)).await,
+        Err(e) => json!({
+            "namespace": "/query/device_by_mac",
+            "status": "404",
+            "error": e.to_string(),
+            "code": ErrorCode::InvalidRegistration.code()
+        }),
+    };
+
+    Ok(HttpResponse::Ok().body(resp))
+}
+
+#[get("/query/device_by_sku/{sku}")]
+async fn query_device_by_sku(
+    pool: web::Data<RedisPool>,
+    info: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let resp = lookup_device(&pool, "/query/device_by_sku", &registry::sku_key(&info)).await;
+
+    Ok(HttpResponse::Ok().body(resp))
+}
+
+/// `limit`: 最多返回的sn数量，默认100
+#[derive(Deserialize)]
+struct ClonesParams {
+    limit: Option<usize>,
+}
+
+#[get("/query/suspected_clones")]
+async fn query_suspected_clones(
+    pool: web::Data<RedisPool>,
+    params: web::Query<ClonesParams>,
+) -> Result<HttpResponse, Error> {
+    let resp = match qr::get_suspected_clones(&pool, params.limit.unwrap_or(100)).await {
+        Ok((clones, total)) => json!({
+            "namespace": "/query/suspected_clones",
+            "status": "200",
+            "value": clones.iter().map(|(sn, detected_at)| json!({
+                "sn": sn,
+                "detected_at": format!("{}", detected_at),
+            })).collect::<Vec<serde_json::Value>>(),
+            "total": format!("{}", total),
+        }),
+        Err(_) => json!({
+            "namespace": "/query/suspected_clones",
+            "status": "404",
+            "error": "no value",
+            "code": ErrorCode::InternalError.code()
+        }),
+    };
+
+    Ok(HttpResponse::Ok().body(resp))
+}
+
+#[get("/query/suspected_clone/{sn}")]
+async fn query_suspected_clone(
+    pool: web::Data<RedisPool>,
+    info: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let sn = info.to_string();
+
+    let resp = match qr::get_suspected_clone(&pool, &sn).await {
+        Ok(Some(record)) => json!({
+            "namespace": "/query/suspected_clone",
+            "status": "200",
+            "sn": sn,
+            "detected_at": format!("{}", record.detected_at),
+            "flags": format!("{}", record.flags),
+            "addrs": record.addrs,
+            "switches": format!("{}", record.switches),
+            "takeovers": format!("{}", record.takeovers),
+        }),
+        Ok(None) => json!({
+            "namespace": "/query/suspected_clone",
+            "status": "404",
+            "sn": sn,
+            "error": "not suspected",
+            "code": ErrorCode::NotFound.code()
+        }),
+        Err(_) => json!({
+            "namespace": "/query/suspected_clone",
+            "status": "404",
+            "sn": sn,
+            "error": "no value",
+            "code": ErrorCode::InternalError.code()
+        }),
+    };
+
+    Ok(HttpResponse::Ok().body(resp))
+}
+
+#[get("/query/nodes")]
+async fn query_nodes(pool: web::Data<RedisPool>) -> Result<HttpResponse, Error> {
+    let resp = match qr::get_nodes(&pool).await {
+        Ok(nodes) => json!({
+            "namespace": "/query/nodes",
+            "status": "200",
+            "value": nodes.iter().map(|node| json!({
+                "id": node.id,
+                "addr": node.addr,
+                "connections": format!("{}", node.connections),
+                "devices": format!("{}", node.devices),
+                "heartbeat": format!("{}", node.heartbeat),
+                "ttl": format!("{}", node.ttl),
+                "alive": node.alive,
+            })).collect::<Vec<serde_json::Value>>(),
+        }),
+        Err(_) => json!({
+            "namespace": "/query/nodes",
+            "status": "404",
+            "error": "no value",
+            "code": ErrorCode::InternalError.code()
+        }),
+    };
+
+    Ok(HttpResponse::Ok().body(resp))
+}
+
 #[actix_web::main]
-pub async fn launch(httpconf: config::HttpServiceConfig, _redisconf: config::RedisConfig) -> std::io::Result<()> {
+pub async fn launch(httpconf: config::HttpServiceConfig, redisconf: config::RedisConfig) -> std::io::Result<()> {
+    // 所有worker共享的redis连接池
+    let redis_pool = match RedisPool::new(
+        &redisconf.ip.unwrap_or("127.0.0.1".to_string()),
+        &redisconf.port.unwrap_or("6379".to_string()),
+        redisconf.pool_size.unwrap_or(4),
+    ).await {
+        Ok(pool) => web::Data::new(pool),
+        Err(e) => {
+            error!("create redis pool error: {:?}, redis is running ?", e);
+            return Err(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, e));
+        }
+    };
+
+    let bind_addr = format!("{}:{}", httpconf.ip.clone().unwrap_or("0.0.0.0".to_string()),
+                            httpconf.port.clone().unwrap_or("8000".to_string()));
+    let sku_rule = match httpconf.sku_rule() {
+        Ok(rule) => web::Data::new(rule),
+        Err(e) => {
+            error!("load sku rule error: {}", e);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e));
+        }
+    };
+    let httpconf = web::Data::new(httpconf);
+
     HttpServer::new(move || {
-        //let s = RedisSession::new("127.0.0.1:6379", &[0; 32]);
         App::new()
-            //.data(redis_conn)
-            //.wrap(RedisSession::new("127.0.0.1:6379", &[0; 32]))
+            .app_data(redis_pool.clone())
+            .app_data(httpconf.clone())
+            .app_data(sku_rule.clone())
             .service(query_service_version)
             .service(query_devices_num)
             .service(query_devices_alive_num)
             .service(query_device_is_alive)
+            .service(query_downlink_depth)
+            .service(query_push_result)
             .service(push_get)
+            .service(query_registered_num)
+            .service(query_registration)
+            .service(registry_import)
+            .service(query_device_by_mac)
+            .service(query_device_by_sku)
+            .service(query_suspected_clones)
+            .service(query_suspected_clone)
+            .service(query_nodes)
     })
-        .bind(format!("{}:{}", httpconf.ip.unwrap_or("0.0.0.0".to_string()),
-                      httpconf.port.unwrap_or("8000".to_string())))?
+        .bind(bind_addr)?
         .run()
         .await
-}
\ No newline at end of file
+}
+/// handler测试，除标记为ignore的以外都使用没有连接的连接池，只覆盖访问redis之前的参数校验与redis出错时的返回
+#[cfg(test)]
+mod http_service_test {
+    use actix_web::{dev::ServiceResponse, http::StatusCode, test};
+    use serde_json::Value;
+
+    use super::*;
+
+    macro_rules! test_app {
+        ($pool:expr) => {
+            test::init_service(App::new()
+                .app_data(web::Data::new($pool))
+                .app_data(web::Data::new(config::HttpServiceConfig {
+                    ip: None,
+                    port: None,
+                    downlink_max_depth: None,
+                    offline_max_ttl: None,
+                    sku_rule: None,
+                }))
+                .app_data(web::Data::new(SkuRule::default()))
+                .service(query_push_result)
+                .service(push_get)
+                .service(query_registration)
+                .service(registry_import)
+                .service(query_device_by_mac)
+                .service(query_suspected_clones)
+                .service(query_suspected_clone)
+                .service(query_nodes)).await
+        };
+    }
+
+    async fn json_body(resp: ServiceResponse) -> (StatusCode, Value) {
+        let status = resp.status();
+        let body = test::read_body(resp).await;
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    fn code(body: &Value) -> Option<u32> {
+        body["code"].as_u64().map(|c| c as u32)
+    }
+
+    #[actix_rt::test]
+    async fn test_push_validation() {
+        let mut app = test_app!(RedisPool::disconnected());
+        let push = |uri: &str, body: &str| test::TestRequest::post().uri(uri).set_payload(body.to_string()).to_request();
+
+        let cases = [
+            ("not json", ErrorCode::InvalidJson),
+            (r#"{"v":"0","type":"getack","sn":"A0AABC000ACD4","010":"01"}"#, ErrorCode::UnknownType),
+            // get、set消息必须带sn，解析时即报错
+            (r#"{"v":"0","type":"get","010":""}"#, ErrorCode::MalformedMessage),
+            // 连接池没有连接，设备按离线处理，不带ttl时直接返回离线
+            (r#"{"v":"0","type":"get","sn":"A0AABC000ACD4","010":""}"#, ErrorCode::DeviceOffline),
+        ];
+        for (body, expected) in cases.iter() {
+            let (status, resp) = json_body(test::call_service(&mut app, push("/push/push_msg", body)).await).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+            assert_eq!(code(&resp), Some(expected.code()), "{}", body);
+        }
+
+        // 带ttl时暂存命令，写入redis失败
+        let body = r#"{"v":"0","type":"get","sn":"A0AABC000ACD4","010":""}"#;
+        let (_, resp) = json_body(test::call_service(&mut app, push("/push/push_msg?ttl=60", body)).await).await;
+        assert_eq!(resp["status"], "408");
+        assert_eq!(code(&resp), Some(ErrorCode::InternalError.code()));
+
+        let req = test::TestRequest::post().uri("/push/push_msg").set_payload(vec![0xff, 0xfe]).to_request();
+        let (_, resp) = json_body(test::call_service(&mut app, req).await).await;
+        assert_eq!(code(&resp), Some(ErrorCode::InvalidEncoding.code()));
+    }
+
+    #[actix_rt::test]
+    async fn test_push_result() {
+        let mut app = test_app!(RedisPool::disconnected());
+
+        // 不是由服务端生成的消息ID不查询redis，直接返回不存在
+        for id in ["not-an-id", "1a2b%20"].iter() {
+            let req = test::TestRequest::get().uri(&format!("/query/push_result/A0AABC000ACD4/{}", id)).to_request();
+            let (status, resp) = json_body(test::call_service(&mut app, req).await).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(resp["status"], "404");
+            assert_eq!(code(&resp), Some(ErrorCode::NotFound.code()), "{}", id);
+        }
+
+        let req = test::TestRequest::get().uri("/query/push_result/A0AABC000ACD4/175f3a2b1c00120001").to_request();
+        let (_, resp) = json_body(test::call_service(&mut app, req).await).await;
+        assert_eq!(resp["id"], "175f3a2b1c00120001");
+        assert_eq!(code(&resp), Some(ErrorCode::InternalError.code()));
+    }
+
+    #[actix_rt::test]
+    async fn test_registration() {
+        let mut app = test_app!(RedisPool::disconnected());
+
+        for sn in ["BADSN", "X0AABC000ACD4", "A0AABC00ZZCD4"].iter() {
+            let req = test::TestRequest::get().uri(&format!("/query/registration/{}", sn)).to_request();
+            let (_, resp) = json_body(test::call_service(&mut app, req).await).await;
+            assert_eq!(resp["status"], "404");
+            assert_eq!(code(&resp), Some(ErrorCode::InvalidRegistration.code()), "{}", sn);
+        }
+
+        // 合法的sn转为大写后查询
+        let req = test::TestRequest::get().uri("/query/registration/a0aabc000acd4").to_request();
+        let (_, resp) = json_body(test::call_service(&mut app, req).await).await;
+        assert_eq!(resp["sn"], "A0AABC000ACD4");
+        assert_eq!(code(&resp), Some(ErrorCode::InternalError.code()));
+
+        let req = test::TestRequest::get().uri("/query/device_by_mac/not-a-mac").to_request();
+        let (_, resp) = json_body(test::call_service(&mut app, req).await).await;
+        assert_eq!(code(&resp), Some(ErrorCode::InvalidRegistration.code()));
+    }
+
+    #[actix_rt::test]
+    async fn test_registry_import() {
+        let mut app = test_app!(RedisPool::disconnected());
+        let import = |uri: &str, body: &str| test::TestRequest::post().uri(uri).set_payload(body.to_string()).to_request();
+
+        // 缺少batch参数
+        let resp = test::call_service(&mut app, import("/registry/import", "A0AABC000ACD4\n")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let (status, resp) = json_body(test::call_service(&mut app, import("/registry/import?batch=A0A-1&date=2020-13-45", "A0AABC000ACD4\n")).await).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(code(&resp), Some(ErrorCode::InvalidRegistration.code()));
+
+        // 没有一行合法，逐行返回原因
+        let (status, resp) = json_body(test::call_service(&mut app, import("/registry/import?batch=A0A-1", "BADSN\nA0AABC000ACD4,zz\n")).await).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(code(&resp), Some(ErrorCode::InvalidRegistration.code()));
+        assert_eq!(resp["errors"].as_array().map(|e| e.len()), Some(2));
+
+        let (_, resp) = json_body(test::call_service(&mut app, import("/registry/import?batch=A0A-1&date=2020-11-12", "A0AABC000ACD4\n")).await).await;
+        assert_eq!(resp["status"], "408");
+        assert_eq!(code(&resp), Some(ErrorCode::InternalError.code()));
+    }
+
+    #[actix_rt::test]
+    async fn test_clones_and_nodes() {
+        let mut app = test_app!(RedisPool::disconnected());
+
+        let resp = test::call_service(&mut app, test::TestRequest::get().uri("/query/suspected_clones?limit=abc").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        for uri in ["/query/suspected_clones?limit=10", "/query/suspected_clone/A0AABC000ACD4", "/query/nodes"].iter() {
+            let (status, resp) = json_body(test::call_service(&mut app, test::TestRequest::get().uri(uri).to_request()).await).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(resp["status"], "404", "{}", uri);
+            assert_eq!(code(&resp), Some(ErrorCode::InternalError.code()), "{}", uri);
+        }
+    }
+
+    /// 需要cfg.toml中配置的redis，`cargo test -- --ignored`执行
+    #[actix_rt::test]
+    #[ignore]
+    async fn test_not_found() {
+        let config = config::load_config("cfg.toml", false);
+        let pool = RedisPool::new(&config.redis.ip.unwrap(), &config.redis.port.unwrap(), 1).await.unwrap();
+        let mut app = test_app!(pool);
+
+        for uri in ["/query/registration/A2EB2SBEEFEF9", "/query/push_result/A2EB2SBEEFEF9/1a2b", "/query/suspected_clone/A2EB2SBEEFEF9"].iter() {
+            let (_, resp) = json_body(test::call_service(&mut app, test::TestRequest::get().uri(uri).to_request()).await).await;
+            assert_eq!(resp["status"], "404", "{}", uri);
+            assert_eq!(code(&resp), Some(ErrorCode::NotFound.code()), "{}", uri);
+        }
+    }
+}
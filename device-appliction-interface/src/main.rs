@@ -11,11 +11,36 @@ mod http_service;
 mod middleware;
 mod common;
 
+const USAGE: &str = "\
+usage: device-appliction-interface                启动http服务
+       device-appliction-interface import <CSV> [--batch <BATCH>] [--date <YYYY-MM-DD>]
+                                                  导入产线的*-finished.csv到出厂登记
+
+    --batch <BATCH>       批次，默认为文件名去掉-finished后缀
+    --date <YYYY-MM-DD>   没有烧写日期的行使用的日期，默认为当天";
+
 fn main() {
+    let argv: Vec<String> = std::env::args().skip(1).collect();
+    if argv.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
 
     // 加载配置
-    let sysconf: config::Config = config::load_config("cfg.toml", true);
+    let sysconf: config::Config = config::load_config("cfg.toml", argv.is_empty());
+
+    if argv.first().map(|a| a.as_str()) == Some("import") {
+        let result = sysconf.http_service.sku_rule()
+            .map_err(|e| e.to_string())
+            .and_then(|rule| hs::import::run(&argv[1..], rule, sysconf.redis));
+        if let Err(e) = result {
+            eprintln!("import: {}\n\n{}", e, USAGE);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     config::log_init(sysconf.log);
 
     hs::main::launch(sysconf.http_service, sysconf.redis).expect("error inside occurs");
-}
\ No newline at end of file
+}
@@ -2,26 +2,27 @@
 
 #[allow(unused_imports)]
 use log::{error, info, warn};
-use serde_json;
-use serde_json::Value;
 
-pub fn parse_sn(msg: &String) -> Option<String> {
-    let sn: Option<String> = if let Ok(data) = serde_json::from_str(msg) {
-        match data {
-            Value::Object(t) => {
-                if t.contains_key("sn") {
-                    match t.get("sn").unwrap() {
-                        Value::String(v) => Some(v.to_string()),
-                        _ => None,
-                    }
-                } else {
-                    None
-                }
+pub use csod::code::ErrorCode;
+pub use csod::message::CsodMessage;
+
+/// 解析http推送的下行消息，只接受服务端可以下发给设备的CSoD消息，且端单元status合法
+pub fn parse_downlink(msg: &str) -> Result<CsodMessage, (ErrorCode, String)> {
+    match CsodMessage::parse(msg) {
+        Ok(m) if m.is_downlink() => {
+            if let Err(e) = m.validate_units() {
+                warn!("invalid unit status {:?}: {}", msg, e);
+                return Err((ErrorCode::InvalidUnitStatus, e.to_string()));
             }
-            _ => None,
+            Ok(m)
+        }
+        Ok(m) => {
+            warn!("not a downlink message: {:?}", m);
+            Err((ErrorCode::UnknownType, "not a downlink message".to_string()))
+        }
+        Err(e) => {
+            warn!("invalid csod message {:?}: {}", msg, e);
+            Err((ErrorCode::classify(msg), "invalid csod message".to_string()))
         }
-    } else {
-        None
-    };
-    sn
-}
\ No newline at end of file
+    }
+}
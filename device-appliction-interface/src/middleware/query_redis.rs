@@ -1,25 +1,94 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 #[allow(unused_imports)]
 use log::{error, info, warn};
 use tokio::time::Duration;
 use tokio::time::delay_for;
 
-use super::redis_wrapper as rw;
+use csod::code::ErrorCode;
+use csod::message::{CsodMessage, MessageId};
+use csod::registry::{self, Registration, NAMESPACE_REGISTRY};
 
-const REDIS_ADDR: &str = "127.0.0.1";
-const REDIS_PORT: &str = "6379";
+use super::redis_wrapper as rw;
 
 pub const NAMESPACE_DEVICES_BORN: &str = "csod/devices_born";
 pub const NAMESPACE_DEVICES_ALIVE: &str = "csod/devices_alive";
 pub const NAMESPACE_DEVICE_STATUS: &str = "csod/device_status";
+pub const NAMESPACE_DEVICE_DOWNLINK: &str = "csod/downlink";
+pub const NAMESPACE_DEVICE_UPLINK: &str = "csod/uplink";
+/// 连接服务节点的下行消息通知频道为`csod/downlink_notify/{node}`，设备所在节点记录在设备状态的`node`字段
+pub const NAMESPACE_DOWNLINK_NOTIFY: &str = "csod/downlink_notify";
+/// 离线设备暂存命令的消息ID队列，key为`csod/offline/{sn}`
+pub const NAMESPACE_DEVICE_OFFLINE: &str = "csod/offline";
+/// 暂存命令的记录，key为`csod/command/{sn}/{id}`，字段: status, msg, expire_at, ack
+pub const NAMESPACE_DEVICE_COMMAND: &str = "csod/command";
 //pub const NAMESPACE_DEVICES_COMMON_EVENT_NOTIFY: &str = "csod/mq/p5";
+/// 连接服务标记的疑似克隆sn，score为最近一次检测时间，检测记录的key为`csod/suspected_clones/{sn}`
+pub const NAMESPACE_SUSPECTED_CLONES: &str = "csod/suspected_clones";
+/// 连接服务节点，score为最近心跳时间，节点信息的key为`csod/node/{id}`
+pub const NAMESPACE_NODES: &str = "csod/nodes";
+pub const NAMESPACE_NODE: &str = "csod/node";
+/// 在节点上线的sn集合，key为`csod/node_devices/{id}`
+pub const NAMESPACE_NODE_DEVICES: &str = "csod/node_devices";
 
-pub async fn get_devices_num() -> Result<Option<String>, String> {
-    let mut redis_conn = if let Ok(e) = rw::RedisConn::new(REDIS_ADDR, REDIS_PORT).await {
-        e
-    } else {
-        warn!("connection redis fail.");
-        return Err("connection redis fail.".to_string());
-    };
+/// 未配置时每个设备下行队列的最大长度
+pub const DEFAULT_DOWNLINK_MAX_DEPTH: usize = 16;
+
+/// 未配置时暂存命令的最大有效期(秒)
+pub const DEFAULT_OFFLINE_MAX_TTL: u64 = 86400;
+/// 暂存命令过期后，其记录继续保留的时间(秒)，便于查询结果
+pub const COMMAND_RETENTION_SECS: u64 = 86400;
+
+/// 暂存命令的状态
+pub const COMMAND_QUEUED: &str = "queued";
+pub const COMMAND_EXPIRED: &str = "expired";
+
+/// 暂存命令的处理结果
+#[derive(Debug)]
+pub struct CommandResult {
+    /// queued, delivered, acked, expired
+    pub status: String,
+    pub expire_at: u64,
+    pub ack: Option<String>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// 下发消息失败的原因
+#[derive(Debug)]
+pub enum TransmitError {
+    /// 设备下行队列已满，值为队列上限
+    QueueFull(usize),
+    /// 设备响应与请求的类型或消息ID不对应
+    AckMismatch(String),
+    Redis(String),
+}
+
+impl TransmitError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            TransmitError::QueueFull(_) => ErrorCode::QueueFull,
+            TransmitError::AckMismatch(_) => ErrorCode::AckMismatch,
+            TransmitError::Redis(_) => ErrorCode::InternalError,
+        }
+    }
+}
+
+impl fmt::Display for TransmitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransmitError::QueueFull(max) => write!(f, "downlink queue full (max depth {})", max),
+            TransmitError::AckMismatch(ack) => write!(f, "ack mismatch {}", ack),
+            TransmitError::Redis(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+pub async fn get_devices_num(pool: &rw::RedisPool) -> Result<Option<String>, String> {
+    let mut redis_conn = pool.get();
 
     let rv = redis_conn.zcard(NAMESPACE_DEVICES_BORN).await;
     if let Ok(Some(v)) = rv {
@@ -31,13 +100,8 @@ pub async fn get_devices_num() -> Result<Option<String>, String> {
 }
 
 
-pub async fn get_alive_devices_num() -> Result<Option<String>, String> {
-    let mut redis_conn = if let Ok(e) = rw::RedisConn::new(REDIS_ADDR, REDIS_PORT).await {
-        e
-    } else {
-        warn!("connection redis fail.");
-        return Err("connection redis fail.".to_string());
-    };
+pub async fn get_alive_devices_num(pool: &rw::RedisPool) -> Result<Option<String>, String> {
+    let mut redis_conn = pool.get();
 
     let rv = redis_conn.zcard(NAMESPACE_DEVICES_ALIVE).await;
     if let Ok(Some(v)) = rv {
@@ -48,100 +112,332 @@ pub async fn get_alive_devices_num() -> Result<Option<String>, String> {
     }
 }
 
-pub async fn sn_is_alive(sn: &str) -> bool {
-    let mut redis_conn = if let Ok(e) = rw::RedisConn::new(REDIS_ADDR, REDIS_PORT).await {
-        e
-    } else {
-        return false;
-    };
+pub async fn sn_is_alive(pool: &rw::RedisPool, sn: &str) -> bool {
+    let mut redis_conn = pool.get();
 
     let rv = redis_conn.zrank(NAMESPACE_DEVICES_ALIVE, sn).await;
-    if let Ok(Some(_)) = rv {
-        true
-    } else {
-        false
-    }
+    matches!(rv, Ok(Some(_)))
 }
 
 
-// 写下行消息
-async fn write_downlink(sn: &str, msg: &str) -> Result<Option<usize>, String> {
-    let mut redis_conn = if let Ok(e) = rw::RedisConn::new(REDIS_ADDR, REDIS_PORT).await {
-        e
-    } else {
-        warn!("dev {} {}", sn, "connection redis fail.".to_string());
-        return Err("connection redis fail.".to_string());
-    };
+// 通知设备所在的连接服务节点取下行队列，设备不在线时没有节点需要通知，上线时会主动取一次
+async fn notify_owner(redis_conn: &mut rw::RedisConn, sn: &str) -> Result<(), ()> {
+    match redis_conn.hget_optional(&format!("{}/{}", NAMESPACE_DEVICE_STATUS, sn), "node").await? {
+        Some(node) => redis_conn.publish(&format!("{}/{}", NAMESPACE_DOWNLINK_NOTIFY, node), sn).await.map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+// 写下行消息：推入设备下行队列，并通知设备所在的连接服务节点，队列长度达到上限时拒绝写入
+async fn write_downlink(pool: &rw::RedisPool, sn: &str, msg: &str, max_depth: usize) -> Result<usize, TransmitError> {
+    let mut redis_conn = pool.get();
     info!("push msg to downlink {}", msg);
-    match redis_conn.hset(&*format!("{}/{}", NAMESPACE_DEVICE_STATUS, sn), "downlink", msg).await {
+    let len = match redis_conn.push_to_list_bounded(&format!("{}/{}", NAMESPACE_DEVICE_DOWNLINK, sn), msg, max_depth).await {
         Err(_) => {
-            warn!("dev {} {}", sn, "set redis fail.".to_string());
-            Err("set redis fail.".to_string())
+            warn!("dev {} push redis fail.", sn);
+            return Err(TransmitError::Redis("push redis fail.".to_string()));
         }
-        Ok(v) => {
-            Ok(v)
+        Ok(None) => {
+            warn!("dev {} downlink queue full, max depth {}", sn, max_depth);
+            return Err(TransmitError::QueueFull(max_depth));
         }
+        Ok(Some(v)) => v,
+    };
+    // 消息已入队，通知失败时不能返回错误，否则调用方重试会重复下发；设备在下一次通知或重连时取出
+    if notify_owner(&mut redis_conn, sn).await.is_err() {
+        warn!("dev {} publish downlink notify fail, msg stays queued.", sn);
     }
+    Ok(len)
 }
 
-// 清除上行ack
-pub async fn clear_uplink(sn: &str) -> Result<(), String> {
-    let mut redis_conn = if let Ok(e) = rw::RedisConn::new(REDIS_ADDR, REDIS_PORT).await {
-        e
-    } else {
-        warn!("dev {} {}", sn, "connection redis fail.".to_string());
-        return Err("connection redis fail.".to_string());
-    };
+/// 设备下行队列中等待下发的消息数量
+pub async fn get_downlink_depth(pool: &rw::RedisPool, sn: &str) -> Result<usize, String> {
+    let mut redis_conn = pool.get();
 
-    if let Ok(_) = redis_conn.hdel(&*format!("{}/{}", NAMESPACE_DEVICE_STATUS, sn), "uplink").await {
-        Ok(())
-    } else {
-        warn!("dev {} {}", sn, "clear redis hash uplink msg failed".to_string());
-        Err("clear redis hash uplink msg failed".to_string())
-    }
+    redis_conn.llen(&format!("{}/{}", NAMESPACE_DEVICE_DOWNLINK, sn)).await
+        .map_err(|_| "read fail.".to_string())
 }
 
-// 读上行ack消息
-pub async fn readline_uplink(sn: &str) -> Result<String, String> {
-    let mut redis_conn = if let Ok(e) = rw::RedisConn::new(REDIS_ADDR, REDIS_PORT).await {
-        e
-    } else {
-        warn!("dev {} {}", sn, "connection redis fail.".to_string());
-        return Err("connection redis fail.".to_string());
-    };
+// 读上行ack消息，ack按请求的消息ID存放，读到后即删除
+pub async fn readline_uplink(pool: &rw::RedisPool, sn: &str, id: &MessageId) -> Result<String, String> {
+    let mut redis_conn = pool.get();
+    let key = format!("{}/{}/{}", NAMESPACE_DEVICE_UPLINK, sn, id);
 
-    if let Ok(v) = redis_conn.hget(&*format!("{}/{}", NAMESPACE_DEVICE_STATUS, sn), "uplink").await {
-        // 读完值就从redis删除掉
-        // TODO: 这里删除失败没处理
-        let _ = redis_conn.hdel(&*format!("{}/{}", NAMESPACE_DEVICE_STATUS, sn), "uplink").await;
+    if let Ok(v) = redis_conn.get(&key).await {
+        // TODO: 这里删除失败没处理，key会自动过期
+        let _ = redis_conn.del_key(&key).await;
         Ok(v)
     } else {
-        //warn!("dev {} {}", sn, "read redis hash fail".to_string());
-        Err("read redis hash fail".to_string())
+        Err("read redis uplink fail".to_string())
     }
 }
 
 
-pub async fn transparent_transmit_wit_ack(sn: &str, msg: &str) -> Result<Option<String>, String> {
-    // 先清除ack
-    if let Err(e) = clear_uplink(&sn).await {
-        warn!("dev {} clear ack failed", sn);
-        return Err(e);
-    }
+/// 为请求分配消息ID后下发，只等待带有相同消息ID、且类型与请求对应的ack
+pub async fn transparent_transmit_wit_ack(pool: &rw::RedisPool, sn: &str, mut msg: CsodMessage, max_depth: usize) -> Result<Option<String>, TransmitError> {
+    let id = MessageId::generate();
+    msg.set_id(id.clone());
 
-    if let Err(e) = write_downlink(sn, msg).await {
+    if let Err(e) = write_downlink(pool, sn, &msg.to_line(), max_depth).await {
         warn!("dev {} write downlink msg failed", sn);
         return Err(e);
     }
 
     // 等待ack最多5s
-    for i in 1..50 {
-        if let Ok(rv) = readline_uplink(sn).await {
-            return Ok(Some(format!("{}", rv)));
+    for _ in 1..50 {
+        if let Ok(rv) = readline_uplink(pool, sn, &id).await {
+            return match CsodMessage::parse(&rv) {
+                Ok(ack) if ack.is_ack_for(&msg) => Ok(Some(rv)),
+                _ => {
+                    warn!("dev {} ack mismatch, request {}, ack {}", sn, msg.to_line(), rv);
+                    Err(TransmitError::AckMismatch(rv))
+                }
+            };
         } else {
-            //warn!("dev {}, delay {}", sn, i);
             delay_for(Duration::from_millis(100)).await;
         }
     };
     Ok(None)
-}
\ No newline at end of file
+}
+
+/// 设备离线时暂存命令，设备在`ttl`秒内重新上线则下发，返回用于查询结果的消息ID
+pub async fn store_for_offline(pool: &rw::RedisPool, sn: &str, mut msg: CsodMessage, ttl: u64, max_depth: usize) -> Result<MessageId, TransmitError> {
+    let mut redis_conn = pool.get();
+    let id = MessageId::generate();
+    msg.set_id(id.clone());
+
+    let command_key = format!("{}/{}/{}", NAMESPACE_DEVICE_COMMAND, sn, id);
+    let expire_at = format!("{}", now_secs() + ttl);
+    let line = msg.to_line();
+    let record = [("status", COMMAND_QUEUED), ("msg", line.as_str()), ("expire_at", expire_at.as_str())];
+    let offline_key = format!("{}/{}", NAMESPACE_DEVICE_OFFLINE, sn);
+    // 记录与入队在同一个脚本内完成，不会留下没有记录的消息ID或没有入队的记录
+    match redis_conn.store_command(&command_key, &offline_key, id.as_str(), &record, ttl + COMMAND_RETENTION_SECS, max_depth).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            warn!("dev {} offline queue full, max depth {}", sn, max_depth);
+            return Err(TransmitError::QueueFull(max_depth));
+        }
+        Err(_) => {
+            warn!("dev {} store command failed", sn);
+            return Err(TransmitError::Redis("store command fail.".to_string()));
+        }
+    }
+    // 设备可能在判断离线之后刚好上线，通知一次由连接服务取走
+    let _ = notify_owner(&mut redis_conn, sn).await;
+    info!("dev {} offline, store command {} for {}s", sn, id, ttl);
+    Ok(id)
+}
+
+/// 查询暂存命令的结果，记录不存在返回None，超过有效期仍未下发的视为过期；
+/// 消息ID由`MessageId::generate`生成，不是十六进制的ID不会存在，不查询redis
+pub async fn get_command_result(pool: &rw::RedisPool, sn: &str, id: &str) -> Result<Option<CommandResult>, String> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(None);
+    }
+    let mut redis_conn = pool.get();
+    let mut record = redis_conn.hgetall(&format!("{}/{}/{}", NAMESPACE_DEVICE_COMMAND, sn, id)).await
+        .map_err(|_| "read fail.".to_string())?;
+    if record.is_empty() {
+        return Ok(None);
+    }
+
+    let expire_at = record.get("expire_at").and_then(|v| v.parse().ok()).unwrap_or(0);
+    let mut status = record.remove("status").unwrap_or_else(|| COMMAND_QUEUED.to_string());
+    if status == COMMAND_QUEUED && now_secs() > expire_at {
+        status = COMMAND_EXPIRED.to_string();
+    }
+    Ok(Some(CommandResult { status, expire_at, ack: record.remove("ack") }))
+}
+
+
+/// 登记记录被并发修改时重新读取的次数上限
+const REGISTER_RETRIES: usize = 3;
+
+/// 登记的结果
+#[derive(Debug, Default)]
+pub struct RegisterSummary {
+    /// 新登记的数量
+    pub added: usize,
+    /// 覆盖已有登记的数量
+    pub updated: usize,
+    /// SKU或MAC已属于其他sn而未登记的记录: sn, 原因
+    pub conflicts: Vec<(String, String)>,
+}
+
+/// 登记设备，已登记的sn以新记录整体覆盖，SKU、MAC反查索引在同一脚本中更新
+pub async fn register_devices(pool: &rw::RedisPool, records: &[Registration]) -> Result<RegisterSummary, String> {
+    let mut redis_conn = pool.get();
+    let registered_at = format!("{}", now_secs());
+    let mut summary = RegisterSummary::default();
+    for record in records {
+        let sku = match &record.sku {
+            Some(sku) => sku,
+            None => return Err(format!("sn {} has no sku", record.sn)),
+        };
+        let registry_key = registry::registry_key(&record.sn);
+        let sku_key = registry::sku_key(sku);
+        let mac_key = record.mac.as_deref().map(registry::mac_key).unwrap_or_default();
+
+        let fields = record.fields();
+        let mut result = Ok(-3);
+        for _ in 0..REGISTER_RETRIES {
+            // 旧的索引key在脚本外读取后传入，脚本内确认记录未被并发修改
+            let old_sku = redis_conn.hget_optional(&registry_key, "sku").await
+                .map_err(|_| format!("register {} fail.", record.sn))?.unwrap_or_default();
+            let old_mac = redis_conn.hget_optional(&registry_key, "mac").await
+                .map_err(|_| format!("register {} fail.", record.sn))?.unwrap_or_default();
+            let old_sku_key = if old_sku.is_empty() { String::new() } else { registry::sku_key(&old_sku) };
+            let old_mac_key = if old_mac.is_empty() { String::new() } else { registry::mac_key(&old_mac) };
+
+            let mut args = vec![record.sn.as_str(), registered_at.as_str(), old_sku.as_str(), old_mac.as_str()];
+            for (k, v) in fields.iter() {
+                args.push(k);
+                args.push(v.as_str());
+            }
+            args.push("registered_at");
+            args.push(registered_at.as_str());
+
+            result = redis_conn.register([&registry_key, NAMESPACE_REGISTRY, &sku_key, &mac_key, &old_sku_key, &old_mac_key], &args).await;
+            if result != Ok(-3) {
+                break;
+            }
+            warn!("registration of {} changed concurrently, retry", record.sn);
+        }
+        match result {
+            Ok(1) => summary.added += 1,
+            Ok(0) => summary.updated += 1,
+            Ok(-1) => summary.conflicts.push((record.sn.clone(), format!("mac {} belongs to another sn", record.mac.as_deref().unwrap_or("")))),
+            Ok(-2) => summary.conflicts.push((record.sn.clone(), format!("sku {} belongs to another sn", sku))),
+            Ok(_) | Err(_) => return Err(format!("register {} fail.", record.sn)),
+        }
+    }
+    info!("registered {} devices: {:?}", records.len(), summary);
+    Ok(summary)
+}
+
+/// 由SKU或MAC反查sn，`key`为`registry::sku_key`或`registry::mac_key`的结果
+pub async fn lookup_sn(pool: &rw::RedisPool, key: &str) -> Result<Option<String>, String> {
+    let mut redis_conn = pool.get();
+    redis_conn.get_optional(key).await
+        .map_err(|_| "read fail.".to_string())
+}
+
+/// 查询设备的出厂登记，未登记返回None
+pub async fn get_registration(pool: &rw::RedisPool, sn: &str) -> Result<Option<(Registration, u64)>, String> {
+    let mut redis_conn = pool.get();
+    let fields = redis_conn.hgetall(&registry::registry_key(sn)).await
+        .map_err(|_| "read fail.".to_string())?;
+    if fields.is_empty() {
+        return Ok(None);
+    }
+    let registered_at = fields.get("registered_at").and_then(|v| v.parse().ok()).unwrap_or(0);
+    match Registration::from_fields(sn, &fields) {
+        Ok(record) => Ok(Some((record, registered_at))),
+        Err(e) => {
+            warn!("dev {} registration broken: {}", sn, e);
+            Err(e.to_string())
+        }
+    }
+}
+
+/// 疑似克隆设备的检测记录
+#[derive(Debug)]
+pub struct SuspectedClone {
+    /// 最近一次检测时间，unix时间戳秒
+    pub detected_at: u64,
+    /// 累计被标记的次数
+    pub flags: u64,
+    /// 最近一次检测窗口内上线的来源ip
+    pub addrs: Vec<String>,
+    pub switches: u64,
+    /// 连接被新连接顶替的累计次数
+    pub takeovers: u64,
+}
+
+fn parse_stamp(v: Option<&String>) -> u64 {
+    v.and_then(|v| v.parse::<f64>().ok()).map(|v| v as u64).unwrap_or(0)
+}
+
+/// 最近被标记的疑似克隆sn与检测时间，按时间从新到旧，最多`limit`个
+pub async fn get_suspected_clones(pool: &rw::RedisPool, limit: usize) -> Result<(Vec<(String, u64)>, u64), String> {
+    let mut redis_conn = pool.get();
+    let total = redis_conn.zcard(NAMESPACE_SUSPECTED_CLONES).await
+        .map_err(|_| "read fail.".to_string())?
+        .unwrap_or(0);
+    if limit == 0 {
+        return Ok((vec![], total));
+    }
+    let members = redis_conn.zrevrange_withscores(NAMESPACE_SUSPECTED_CLONES, 0, limit as isize - 1).await
+        .map_err(|_| "read fail.".to_string())?;
+    Ok((members.into_iter().map(|(sn, score)| (sn, score as u64)).collect(), total))
+}
+
+/// 查询sn的疑似克隆记录，没有被标记过返回None
+pub async fn get_suspected_clone(pool: &rw::RedisPool, sn: &str) -> Result<Option<SuspectedClone>, String> {
+    let mut redis_conn = pool.get();
+    let record = redis_conn.hgetall(&format!("{}/{}", NAMESPACE_SUSPECTED_CLONES, sn)).await
+        .map_err(|_| "read fail.".to_string())?;
+    if record.is_empty() {
+        return Ok(None);
+    }
+    let status = redis_conn.hgetall(&format!("{}/{}", NAMESPACE_DEVICE_STATUS, sn)).await
+        .map_err(|_| "read fail.".to_string())?;
+    Ok(Some(SuspectedClone {
+        detected_at: parse_stamp(record.get("detected_at")),
+        flags: record.get("flags").and_then(|v| v.parse().ok()).unwrap_or(0),
+        addrs: record.get("addrs").map(|v| v.split(',').filter(|a| !a.is_empty()).map(|a| a.to_string()).collect()).unwrap_or_default(),
+        switches: record.get("switches").and_then(|v| v.parse().ok()).unwrap_or(0),
+        takeovers: status.get("takeovers").and_then(|v| v.parse().ok()).unwrap_or(0),
+    }))
+}
+
+pub async fn get_registered_num(pool: &rw::RedisPool) -> Result<u64, String> {
+    let mut redis_conn = pool.get();
+    redis_conn.zcard(NAMESPACE_REGISTRY).await
+        .map(|n| n.unwrap_or(0))
+        .map_err(|_| "read fail.".to_string())
+}
+
+/// 连接服务节点的状态
+#[derive(Debug)]
+pub struct NodeInfo {
+    pub id: String,
+    pub addr: String,
+    /// 节点上报的连接数
+    pub connections: u64,
+    /// 记录在该节点上线的设备数
+    pub devices: u64,
+    /// 最近心跳时间，unix时间戳秒
+    pub heartbeat: u64,
+    pub ttl: u64,
+    /// 心跳未超过ttl
+    pub alive: bool,
+}
+
+/// 所有登记的连接服务节点，按最近心跳从新到旧；宕机的节点在被其他节点回收前仍会列出
+pub async fn get_nodes(pool: &rw::RedisPool) -> Result<Vec<NodeInfo>, String> {
+    let mut redis_conn = pool.get();
+    let members = redis_conn.zrevrange_withscores(NAMESPACE_NODES, 0, -1).await
+        .map_err(|_| "read fail.".to_string())?;
+    let now = now_secs();
+    let mut nodes = vec![];
+    for (id, heartbeat) in members {
+        let record = redis_conn.hgetall(&format!("{}/{}", NAMESPACE_NODE, id)).await
+            .map_err(|_| "read fail.".to_string())?;
+        let devices = redis_conn.scard(&format!("{}/{}", NAMESPACE_NODE_DEVICES, id)).await
+            .map_err(|_| "read fail.".to_string())?;
+        let heartbeat = heartbeat as u64;
+        let ttl = record.get("ttl").and_then(|v| v.parse().ok()).unwrap_or(0);
+        nodes.push(NodeInfo {
+            addr: record.get("addr").cloned().unwrap_or_default(),
+            connections: record.get("connections").and_then(|v| v.parse().ok()).unwrap_or(0),
+            devices,
+            heartbeat,
+            ttl,
+            alive: now.saturating_sub(heartbeat) <= ttl,
+            id,
+        });
+    }
+    Ok(nodes)
+}
@@ -1,37 +1,135 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 #[allow(unused_imports)]
 use log::{
     error,
     info,
 };
 use redis::{
-    aio::Connection,
+    aio::ConnectionManager,
     AsyncCommands,
-    Client,
     cmd as redis_cmd,
+    IntoConnectionInfo,
     RedisResult,
+    Script,
 };
 
+/// 队列长度未达上限才推入，返回推入后长度，已满返回-1
+const BOUNDED_LPUSH_SCRIPT: &str = r"
+if redis.call('llen', KEYS[1]) >= tonumber(ARGV[2]) then
+    return -1
+end
+return redis.call('lpush', KEYS[1], ARGV[1])
+";
+
+/// 登记设备并维护SKU、MAC反查索引，KEYS依次为登记hash、登记有序集合、SKU key、MAC key、旧的SKU key、旧的MAC key(没有时为空串)，
+/// ARGV依次为sn、登记时间戳、调用前读取的旧SKU、旧MAC(没有为空)，之后为hash的field, value
+/// 旧记录与读取时不同(期间被并发修改)时不做任何修改，返回-3由调用方重新读取；
+/// SKU或MAC已属于其他sn时不做任何修改，分别返回-2、-1；否则以新记录整体覆盖，清除旧的索引，返回是否新登记
+const REGISTER_SCRIPT: &str = r"
+local sn = ARGV[1]
+local old = redis.call('hmget', KEYS[1], 'sku', 'mac')
+if (old[1] or '') ~= ARGV[3] or (old[2] or '') ~= ARGV[4] then
+    return -3
+end
+local sku_owner = redis.call('get', KEYS[3])
+if sku_owner and sku_owner ~= sn then
+    return -2
+end
+if KEYS[4] ~= '' then
+    local mac_owner = redis.call('get', KEYS[4])
+    if mac_owner and mac_owner ~= sn then
+        return -1
+    end
+end
+if KEYS[5] ~= '' then
+    redis.call('del', KEYS[5])
+end
+if KEYS[6] ~= '' then
+    redis.call('del', KEYS[6])
+end
+redis.call('del', KEYS[1])
+for i = 5, #ARGV, 2 do
+    redis.call('hset', KEYS[1], ARGV[i], ARGV[i + 1])
+end
+redis.call('set', KEYS[3], sn)
+if KEYS[4] ~= '' then
+    redis.call('set', KEYS[4], sn)
+end
+return redis.call('zadd', KEYS[2], 'NX', ARGV[2], sn)
+";
+
+/// 暂存离线命令，KEYS依次为命令记录hash、离线队列，ARGV依次为消息ID、队列长度上限、记录有效期(秒)，之后为hash的field, value
+/// 队列已满时不写入记录，返回-1；否则写入记录并设置有效期、消息ID推入队列，返回队列长度
+const STORE_COMMAND_SCRIPT: &str = r"
+if redis.call('llen', KEYS[2]) >= tonumber(ARGV[2]) then
+    return -1
+end
+redis.call('del', KEYS[1])
+for i = 4, #ARGV, 2 do
+    redis.call('hset', KEYS[1], ARGV[i], ARGV[i + 1])
+end
+redis.call('expire', KEYS[1], ARGV[3])
+return redis.call('lpush', KEYS[2], ARGV[1])
+";
+
+/// redis连接句柄，底层为多路复用连接，clone开销很小，断线后自动重连
+#[derive(Clone)]
 pub struct RedisConn {
-    conn: Option<Connection>,
+    conn: Option<ConnectionManager>,
+}
+
+/// http服务共享的redis连接池，以actix app data注入各handler
+pub struct RedisPool {
+    conns: Vec<RedisConn>,
+    next: AtomicUsize,
+}
+
+#[allow(dead_code)]
+impl RedisPool {
+    /// 建立`size`个多路复用连接，`size`为0时按1处理
+    pub async fn new(ip: &str, port: &str, size: usize) -> RedisResult<RedisPool> {
+        let mut conns = Vec::with_capacity(size.max(1));
+        for _ in 0..size.max(1) {
+            conns.push(RedisConn::new(ip, port).await?);
+        }
+        info!("redis pool created, size {}", conns.len());
+        Ok(RedisPool { conns, next: AtomicUsize::new(0) })
+    }
+
+    /// 轮询取出一个连接句柄
+    pub fn get(&self) -> RedisConn {
+        let idx = self.next.fetch_add(1, Ordering::Relaxed) % self.conns.len();
+        self.conns[idx].clone()
+    }
+
+    pub fn size(&self) -> usize {
+        self.conns.len()
+    }
+
+    /// 没有连接的连接池，所有redis请求都返回错误，用于测试handler的参数校验与出错路径
+    #[cfg(test)]
+    pub fn disconnected() -> RedisPool {
+        RedisPool { conns: vec![RedisConn { conn: None }], next: AtomicUsize::new(0) }
+    }
 }
 
 #[allow(dead_code)]
 impl RedisConn {
-    /// 建立一个redis async连接
+    /// 建立一个redis多路复用连接，需要在tokio runtime内调用
     pub async fn new(ip: &str, port: &str) -> RedisResult<RedisConn> {
         let _redis_addr = format!("redis://{}:{}/", ip, port);
-        let client_result = Client::open(_redis_addr);
-
-        match client_result {
-            Ok(cli) => {
-                match cli.get_async_connection().await {
-                    Ok(conn) => Ok(RedisConn { conn: Some(conn) }),
-                    Err(e) => {
-                        error!("get async redis connection fail: {:?}", e);
-                        Err(e)
-                    }
-                }
+        let conn_info = match _redis_addr.into_connection_info() {
+            Ok(info) => info,
+            Err(e) => {
+                error!("invalid redis addr: {:?}", e);
+                return Err(e);
             }
+        };
+
+        match ConnectionManager::new(conn_info).await {
+            Ok(conn) => Ok(RedisConn { conn: Some(conn) }),
             Err(e) => {
                 error!("connect redis fail: {:?}", e);
                 Err(e)
@@ -71,6 +169,42 @@ impl RedisConn {
         }
     }
 
+    /// key不存在时返回None
+    pub async fn get_optional(&mut self, key: &str) -> Result<Option<String>, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        match cli.get::<&str, Option<String>>(key).await {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                error!("get key({}) failed: {:?}", key, e);
+                Err(())
+            }
+        }
+    }
+
+    /// 删除指定key，返回删除的key数量
+    pub async fn del_key(&mut self, key: &str) -> Result<usize, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        match cli.del::<&str, usize>(key).await {
+            Ok(n) => Ok(n),
+            Err(e) => {
+                error!("del key({}) failed: {:?}", key, e);
+                Err(())
+            }
+        }
+    }
+
     pub async fn zcard(&mut self, key: &str) -> Result<Option<u64>, ()> {
         let cli = if let Some(conn) = &mut self.conn {
             conn
@@ -92,6 +226,40 @@ impl RedisConn {
         }
     }
 
+    /// 返回集合的成员数量，key不存在时为0
+    pub async fn scard(&mut self, key: &str) -> Result<u64, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        let query: RedisResult<u64> = redis_cmd("scard")
+            .arg(key)
+            .query_async(cli)
+            .await;
+
+        query.map_err(|e| error!("{:?}", e))
+    }
+
+    /// 按score从大到小返回有序集合中的成员与score
+    pub async fn zrevrange_withscores(&mut self, key: &str, start: isize, stop: isize) -> Result<Vec<(String, f64)>, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        let query: RedisResult<Vec<(String, f64)>> = redis_cmd("zrevrange")
+            .arg(key).arg(start).arg(stop).arg("withscores")
+            .query_async(cli)
+            .await;
+
+        query.map_err(|e| error!("{:?}", e))
+    }
+
     /// 返回指定成员在有序集合中的索引
     pub async fn zrank(&mut self, key: &str, member: &str) -> Result<Option<usize>, ()> {
         let cli = if let Some(conn) = &mut self.conn {
@@ -135,6 +303,60 @@ impl RedisConn {
         }
     }
 
+    /// 一次设置hash表的多个字段
+    pub async fn hset_multiple(&mut self, key: &str, items: &[(&str, &str)]) -> Result<(), ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        match cli.hset_multiple::<&str, &str, &str, ()>(key, items).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("set hash fail: {}, {:?}, {:?}", key, items, e);
+                Err(())
+            }
+        }
+    }
+
+    /// 获取hash表所有字段，key不存在时返回空表
+    pub async fn hgetall(&mut self, key: &str) -> Result<HashMap<String, String>, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        match cli.hgetall::<&str, HashMap<String, String>>(key).await {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                error!("get hash fail: {}, {:?}", key, e);
+                Err(())
+            }
+        }
+    }
+
+    /// 设置key的过期时间(秒)
+    pub async fn expire(&mut self, key: &str, seconds: usize) -> Result<(), ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        match cli.expire::<&str, ()>(key, seconds).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("expire key({}) fail: {:?}", key, e);
+                Err(())
+            }
+        }
+    }
+
     /// 删除hash表指定key的指定字段
     pub async fn hdel(&mut self, key: &str, field: &str) -> Result<u64, ()> {
         let cli = if let Some(conn) = &mut self.conn {
@@ -156,6 +378,23 @@ impl RedisConn {
         }
     }
 
+    /// 获取hash表指定key的指定字段，key或字段不存在时返回None
+    pub async fn hget_optional(&mut self, key: &str, field: &str) -> Result<Option<String>, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        let query: RedisResult<Option<String>> = redis_cmd("hget")
+            .arg(key).arg(field)
+            .query_async(cli)
+            .await;
+
+        query.map_err(|e| error!("hget key({}) field({}) failed: {:?}", key, field, e))
+    }
+
     /// 获取hash表指定key的指定字段
     pub async fn hget(&mut self, key: &str, field: &str) -> Result<String, ()> {
         let cli = if let Some(conn) = &mut self.conn {
@@ -176,4 +415,132 @@ impl RedisConn {
             Err(())
         }
     }
-}
\ No newline at end of file
+
+    /// 将数据从左端(lpush)推入指定redis list，返回推入后list长度
+    pub async fn push_to_list(&mut self, list_name: &str, v: &str) -> Result<usize, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        match cli.lpush::<&str, &str, usize>(list_name, v).await {
+            Ok(len) => Ok(len),
+            Err(e) => {
+                error!("push msg({}) to list({}) failed: {:?}", v, list_name, e);
+                Err(())
+            }
+        }
+    }
+
+    /// 带长度上限的lpush，检查与推入在redis内原子执行，队列已满返回None
+    pub async fn push_to_list_bounded(&mut self, list_name: &str, v: &str, max_len: usize) -> Result<Option<usize>, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        let query: RedisResult<i64> = Script::new(BOUNDED_LPUSH_SCRIPT)
+            .key(list_name).arg(v).arg(max_len)
+            .invoke_async(cli)
+            .await;
+        match query {
+            Ok(len) if len >= 0 => Ok(Some(len as usize)),
+            Ok(_) => Ok(None),
+            Err(e) => {
+                error!("push msg({}) to list({}) failed: {:?}", v, list_name, e);
+                Err(())
+            }
+        }
+    }
+
+    /// 写入命令记录并推入离线队列，在redis内原子执行，队列已满返回None，见`STORE_COMMAND_SCRIPT`
+    pub async fn store_command(&mut self, command_key: &str, queue: &str, id: &str, record: &[(&str, &str)], ttl: u64, max_len: usize) -> Result<Option<usize>, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        let script = Script::new(STORE_COMMAND_SCRIPT);
+        let mut invocation = script.key(command_key);
+        invocation.key(queue).arg(id).arg(max_len).arg(ttl);
+        for (field, value) in record {
+            invocation.arg(*field).arg(*value);
+        }
+        match invocation.invoke_async::<_, i64>(cli).await {
+            Ok(len) if len >= 0 => Ok(Some(len as usize)),
+            Ok(_) => Ok(None),
+            Err(e) => {
+                error!("store command({}) to list({}) failed: {:?}", command_key, queue, e);
+                Err(())
+            }
+        }
+    }
+
+    /// 执行登记脚本，见`REGISTER_SCRIPT`
+    pub async fn register(&mut self, keys: [&str; 6], args: &[&str]) -> Result<i64, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        let script = Script::new(REGISTER_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        for key in keys.iter() {
+            invocation.key(*key);
+        }
+        for arg in args {
+            invocation.arg(*arg);
+        }
+        match invocation.invoke_async::<_, i64>(cli).await {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                error!("register {:?} failed: {:?}", keys, e);
+                Err(())
+            }
+        }
+    }
+
+    /// 返回list长度，key不存在时为0
+    pub async fn llen(&mut self, list_name: &str) -> Result<usize, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        match cli.llen::<&str, usize>(list_name).await {
+            Ok(len) => Ok(len),
+            Err(e) => {
+                error!("llen list({}) failed: {:?}", list_name, e);
+                Err(())
+            }
+        }
+    }
+
+    /// 向指定频道发布消息，返回收到消息的订阅者数量
+    pub async fn publish(&mut self, channel: &str, msg: &str) -> Result<usize, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        match cli.publish::<&str, &str, usize>(channel, msg).await {
+            Ok(n) => Ok(n),
+            Err(e) => {
+                error!("publish msg({}) to channel({}) failed: {:?}", msg, channel, e);
+                Err(())
+            }
+        }
+    }
+}
@@ -17,6 +17,7 @@
 //!
 
 use std::env;
+use std::fmt;
 use std::fs::File;
 use std::io::Read;
 
@@ -24,6 +25,8 @@ use loge;
 use serde_derive::Deserialize;
 use toml;
 
+use csod::message::Encoding;
+
 #[derive(Deserialize)]
 #[derive(Debug)]
 #[derive(Clone)]
@@ -33,13 +36,71 @@ pub struct LogConfig {
     pub format: Option<String>,
 }
 
+/// `Debug`不输出`sn_salt`与`psk_master_key`的内容，启动时整个配置会写入日志
 #[derive(Deserialize)]
-#[derive(Debug)]
 #[derive(Clone)]
 pub struct PerceptionServiceConfig {
     pub ip: Option<String>,
     pub port: Option<String>,
     pub heartbeat_interval: Option<u64>,
+    pub sn_salt: Option<String>,
+    pub max_frames_per_minute: Option<u32>,
+    pub min_version: Option<u32>,
+    pub max_frame_size: Option<usize>,
+    pub encodings: Option<Vec<Encoding>>,
+    pub allow_deflate: Option<bool>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub tls_client_ca: Option<String>,
+    pub psk_master_key: Option<String>,
+    pub psk_products: Option<Vec<String>>,
+    pub challenge_auth: Option<bool>,
+    pub auth_max_failures: Option<u32>,
+    pub auth_ban_secs: Option<u64>,
+    pub registered_only: Option<bool>,
+    pub clone_window_secs: Option<u64>,
+    pub clone_min_switches: Option<u32>,
+    pub shutdown_deadline_secs: Option<u64>,
+    pub shutdown_reconnect_hint: Option<bool>,
+    pub presence_sweep_interval_secs: Option<u64>,
+    pub presence_expire_factor: Option<u64>,
+    pub node_id: Option<String>,
+    pub node_heartbeat_secs: Option<u64>,
+    pub node_expire_secs: Option<u64>,
+}
+
+impl fmt::Debug for PerceptionServiceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PerceptionServiceConfig")
+            .field("ip", &self.ip)
+            .field("port", &self.port)
+            .field("heartbeat_interval", &self.heartbeat_interval)
+            .field("sn_salt", &self.sn_salt.as_ref().map(|_| "******"))
+            .field("max_frames_per_minute", &self.max_frames_per_minute)
+            .field("min_version", &self.min_version)
+            .field("max_frame_size", &self.max_frame_size)
+            .field("encodings", &self.encodings)
+            .field("allow_deflate", &self.allow_deflate)
+            .field("tls_cert", &self.tls_cert)
+            .field("tls_key", &self.tls_key)
+            .field("tls_client_ca", &self.tls_client_ca)
+            .field("psk_master_key", &self.psk_master_key.as_ref().map(|_| "******"))
+            .field("psk_products", &self.psk_products)
+            .field("challenge_auth", &self.challenge_auth)
+            .field("auth_max_failures", &self.auth_max_failures)
+            .field("auth_ban_secs", &self.auth_ban_secs)
+            .field("registered_only", &self.registered_only)
+            .field("clone_window_secs", &self.clone_window_secs)
+            .field("clone_min_switches", &self.clone_min_switches)
+            .field("shutdown_deadline_secs", &self.shutdown_deadline_secs)
+            .field("shutdown_reconnect_hint", &self.shutdown_reconnect_hint)
+            .field("presence_sweep_interval_secs", &self.presence_sweep_interval_secs)
+            .field("presence_expire_factor", &self.presence_expire_factor)
+            .field("node_id", &self.node_id)
+            .field("node_heartbeat_secs", &self.node_heartbeat_secs)
+            .field("node_expire_secs", &self.node_expire_secs)
+            .finish()
+    }
 }
 
 #[derive(Deserialize)]
@@ -48,6 +109,7 @@ pub struct PerceptionServiceConfig {
 pub struct RedisConfig {
     pub ip: Option<String>,
     pub port: Option<String>,
+    pub pool_size: Option<usize>,
 }
 
 #[derive(Deserialize)]
@@ -117,7 +179,7 @@ pub fn load_config(toml_path: &str, verbose: bool) -> Config {
             "Null".to_string()
         });
 
-        println!("[perception connection]: \n\tip = {:?}\n\tport = {:?}\n\theartbeat_interval = {:?}", if let Some(e) = &config.perception_service.ip {
+        println!("[perception connection]: \n\tip = {:?}\n\tport = {:?}\n\theartbeat_interval = {:?}\n\tsn_salt = {:?}\n\tmax_frames_per_minute = {:?}\n\tmin_version = {:?}\n\tmax_frame_size = {:?}\n\tencodings = {:?}\n\tallow_deflate = {:?}\n\ttls_cert = {:?}\n\ttls_key = {:?}\n\ttls_client_ca = {:?}\n\tpsk_master_key = {:?}\n\tpsk_products = {:?}\n\tchallenge_auth = {:?}\n\tauth_max_failures = {:?}\n\tauth_ban_secs = {:?}\n\tregistered_only = {:?}\n\tclone_window_secs = {:?}\n\tclone_min_switches = {:?}\n\tshutdown_deadline_secs = {:?}\n\tshutdown_reconnect_hint = {:?}\n\tpresence_sweep_interval_secs = {:?}\n\tpresence_expire_factor = {:?}\n\tnode_id = {:?}\n\tnode_heartbeat_secs = {:?}\n\tnode_expire_secs = {:?}", if let Some(e) = &config.perception_service.ip {
             e.clone()
         } else {
             "Null".to_string()
@@ -129,9 +191,105 @@ pub fn load_config(toml_path: &str, verbose: bool) -> Config {
             format!("{}", &e)
         } else {
             "Null".to_string()
+        }, if config.perception_service.sn_salt.is_some() {
+            "******".to_string()
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.max_frames_per_minute {
+            format!("{}", &e)
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.min_version {
+            format!("{}", &e)
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.max_frame_size {
+            format!("{}", &e)
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.encodings {
+            e.iter().map(|enc| enc.to_string()).collect::<Vec<String>>().join(",")
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.allow_deflate {
+            format!("{}", &e)
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.tls_cert {
+            e.clone()
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.tls_key {
+            e.clone()
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.tls_client_ca {
+            e.clone()
+        } else {
+            "Null".to_string()
+        }, if config.perception_service.psk_master_key.is_some() {
+            "******".to_string()
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.psk_products {
+            e.join(",")
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.challenge_auth {
+            format!("{}", &e)
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.auth_max_failures {
+            format!("{}", &e)
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.auth_ban_secs {
+            format!("{}", &e)
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.registered_only {
+            format!("{}", &e)
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.clone_window_secs {
+            format!("{}", &e)
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.clone_min_switches {
+            format!("{}", &e)
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.shutdown_deadline_secs {
+            format!("{}", &e)
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.shutdown_reconnect_hint {
+            format!("{}", &e)
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.presence_sweep_interval_secs {
+            format!("{}", &e)
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.presence_expire_factor {
+            format!("{}", &e)
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.node_id {
+            e.clone()
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.node_heartbeat_secs {
+            format!("{}", &e)
+        } else {
+            "Null".to_string()
+        }, if let Some(e) = &config.perception_service.node_expire_secs {
+            format!("{}", &e)
+        } else {
+            "Null".to_string()
         });
 
-        println!("[redis]: \n\tip = {:?}\n\tport = {:?}", if let Some(e) = &config.redis.ip {
+        println!("[redis]: \n\tip = {:?}\n\tport = {:?}\n\tpool_size = {:?}", if let Some(e) = &config.redis.ip {
             e.clone()
         } else {
             "Null".to_string()
@@ -139,8 +297,26 @@ pub fn load_config(toml_path: &str, verbose: bool) -> Config {
             e.clone()
         } else {
             "Null".to_string()
+        }, if let Some(e) = &config.redis.pool_size {
+            format!("{}", &e)
+        } else {
+            "Null".to_string()
         });
         println!("===========================================");
     }
     config
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod config_test {
+    use super::PerceptionServiceConfig;
+
+    #[test]
+    fn test_debug_redacts_secrets() {
+        let cfg: PerceptionServiceConfig = toml::from_str("port = \"8900\"\nsn_salt = \"anbwscx\"\npsk_master_key = \"c2VjcmV0\"").unwrap();
+        let printed = format!("{:?}", cfg);
+        assert!(printed.contains("port: Some(\"8900\")"));
+        assert!(!printed.contains("anbwscx"));
+        assert!(!printed.contains("c2VjcmV0"));
+        assert!(printed.contains("psk_master_key: Some(\"******\")"));
+    }
+}
@@ -20,7 +20,7 @@ use redis::{
 mod test_redis_conn {
     use std::borrow::Borrow;
 
-    use futures::executor::block_on;
+    use tokio::runtime::Runtime;
 
     use crate::config;
     use crate::middleware_wrapper::redis_wrapper::{NAMESPACE_DEVICES_BORN, RedisConn};
@@ -31,7 +31,8 @@ mod test_redis_conn {
     #[test]
     fn test_hash() {
         let config: config::Config = config::load_config("cfg.toml", true);
-        let redis_conn = block_on(RedisConn::new(&config.redis.ip.unwrap(), &config.redis.port.unwrap()));
+        let mut rt = Runtime::new().unwrap();
+        let redis_conn = rt.block_on(RedisConn::new(&config.redis.ip.unwrap(), &config.redis.port.unwrap()));
         let mut conn = if let Ok(instance) = redis_conn {
             instance
         } else {
@@ -41,18 +42,18 @@ mod test_redis_conn {
 
         // 设置一个值
         let sn = "sn_test_1234";
-        let _ = block_on(conn.del_key("sn_test_1234"));
-        let _ = block_on(conn.hset(sn, "online", "true"));
-        let mut status = block_on(conn.hget(sn, "online"));
+        let _ = rt.block_on(conn.del_key("sn_test_1234"));
+        let _ = rt.block_on(conn.hset(sn, "online", "true"));
+        let mut status = rt.block_on(conn.hget(sn, "online"));
         assert_eq!("true".to_string(), status.unwrap());
 
         // 删除
-        let _ = block_on(conn.hdel(sn, "status"));
-        status = block_on(conn.hget(sn, "status"));
+        let _ = rt.block_on(conn.hdel(sn, "status"));
+        status = rt.block_on(conn.hget(sn, "status"));
         assert_eq!(Err(()), status);
 
-        let _ = block_on(conn.hset_online_with_time(sn, "true"));
-        status = block_on(conn.hget(sn, "online"));
+        let _ = rt.block_on(conn.hset_online_with_time(sn, "true"));
+        status = rt.block_on(conn.hget(sn, "online"));
         assert_eq!("true".to_string(), status.unwrap());
     }
 
@@ -60,7 +61,8 @@ mod test_redis_conn {
     #[test]
     fn test_sorted_set() {
         let config: config::Config = config::load_config("cfg.toml", true);
-        let redis_conn = block_on(RedisConn::new(&config.redis.ip.unwrap(), &config.redis.port.unwrap()));
+        let mut rt = Runtime::new().unwrap();
+        let redis_conn = rt.block_on(RedisConn::new(&config.redis.ip.unwrap(), &config.redis.port.unwrap()));
         let mut conn = if let Ok(instance) = redis_conn {
             instance
         } else {
@@ -68,22 +70,23 @@ mod test_redis_conn {
             panic!("redis connection false");
         };
 
-        block_on(conn.del_key(NAMESPACE_DEVICES_BORN));
+        rt.block_on(conn.del_key(NAMESPACE_DEVICES_BORN));
 
         for i in 0..100 {
-            if block_on(conn.zadd_device_born_with_timestamp(&format!("sn_test_{}", i))).is_err() {
+            if rt.block_on(conn.zadd_device_born_with_timestamp(&format!("sn_test_{}", i))).is_err() {
                 assert!(false);
             }
         }
 
-        assert_eq!(Ok(100), block_on(conn.zcard_devices_born()));
+        assert_eq!(Ok(100), rt.block_on(conn.zcard_devices_born()));
     }
 
     /// 测试redis连接->set值->get值
     #[test]
     fn test_set_get() {
         let config: config::Config = config::load_config("cfg.toml", true);
-        let redis_conn = block_on(RedisConn::new(&config.redis.ip.unwrap(), &config.redis.port.unwrap()));
+        let mut rt = Runtime::new().unwrap();
+        let redis_conn = rt.block_on(RedisConn::new(&config.redis.ip.unwrap(), &config.redis.port.unwrap()));
         let mut conn = if let Ok(instance) = redis_conn {
             instance
         } else {
@@ -91,11 +94,11 @@ mod test_redis_conn {
             panic!("redis connection false");
         };
 
-        if block_on(conn.set("test__", "test_fuck__")).is_err() {
+        if rt.block_on(conn.set("test__", "test_fuck__")).is_err() {
             assert!(false);
         };
 
-        match block_on(conn.get("test__")) {
+        match rt.block_on(conn.get("test__")) {
             Ok(v) => {
                 assert_eq!(v, String::from("test_fuck__"));
             }
@@ -109,7 +112,8 @@ mod test_redis_conn {
     #[test]
     fn test_push_pop() {
         let config: config::Config = config::load_config("cfg.toml", true);
-        let redis_conn = block_on(RedisConn::new(&config.redis.ip.unwrap(), &config.redis.port.unwrap()));
+        let mut rt = Runtime::new().unwrap();
+        let redis_conn = rt.block_on(RedisConn::new(&config.redis.ip.unwrap(), &config.redis.port.unwrap()));
         let mut conn = if let Ok(instance) = redis_conn {
             instance
         } else {
@@ -117,16 +121,16 @@ mod test_redis_conn {
             panic!("redis connection false");
         };
 
-        block_on(conn.del_key("test-p5"));
+        rt.block_on(conn.del_key("test-p5"));
 
         for i in 0..=100 {
-            if block_on(conn.push_to_list("test-p5", &format!("fuck-{}", i))).is_err() {
+            if rt.block_on(conn.push_to_list("test-p5", &format!("fuck-{}", i))).is_err() {
                 assert!(false);
             }
         }
 
         for i in 0..=100 {
-            if let Ok(v) = block_on(conn.pop_from_list("test-p5")) {
+            if let Ok(v) = rt.block_on(conn.pop_from_list("test-p5")) {
                 assert_eq!(format!("fuck-{:?}", i), v.unwrap());
             } else {
                 assert!(false);
@@ -139,8 +143,9 @@ mod test_redis_conn {
     #[bench]
     fn bench_redis_conn_new(b: &mut Bencher) {
         let config: config::Config = config::load_config("cfg.toml", true);
+        let mut rt = Runtime::new().unwrap();
         b.iter(|| {
-            let redis_conn = block_on(RedisConn::new(&config.redis.ip.borrow().as_ref().unwrap(), &config.redis.port.borrow().as_ref().unwrap()));
+            let redis_conn = rt.block_on(RedisConn::new(&config.redis.ip.borrow().as_ref().unwrap(), &config.redis.port.borrow().as_ref().unwrap()));
             if redis_conn.is_err() {
                 assert!(false);
             };
@@ -148,9 +153,147 @@ mod test_redis_conn {
     }
 }
 
+/// 在线状态脚本的集成测试，需要cfg.toml中配置的redis，`cargo test -- --ignored`执行
+#[cfg(test)]
+mod test_presence {
+    use redis::{aio::Connection, AsyncCommands, Client};
+    use tokio::runtime::Runtime;
+
+    use crate::config;
+    use crate::middleware_wrapper::redis_wrapper::{
+        NAMESPACE_CONNECT_HISTORY,
+        NAMESPACE_DEVICE_STATUS,
+        NAMESPACE_DEVICES_ALIVE,
+        NAMESPACE_NODE_DEVICES,
+        NAMESPACE_NODES,
+        RedisConn,
+    };
+
+    async fn connect() -> (RedisConn, Connection) {
+        let config: config::Config = config::load_config("cfg.toml", false);
+        let (ip, port) = (config.redis.ip.unwrap(), config.redis.port.unwrap());
+        let conn = RedisConn::new(&ip, &port).await.unwrap();
+        let raw = Client::open(format!("redis://{}:{}/", ip, port)).unwrap().get_async_connection().await.unwrap();
+        (conn, raw)
+    }
+
+    fn status_key(sn: &str) -> String {
+        format!("{}/{}", NAMESPACE_DEVICE_STATUS, sn)
+    }
+
+    fn node_devices_key(node: &str) -> String {
+        format!("{}/{}", NAMESPACE_NODE_DEVICES, node)
+    }
+
+    async fn reset(conn: &mut RedisConn, sn: &str, nodes: &[&str]) {
+        conn.del_key(&status_key(sn)).await;
+        conn.del_key(&format!("{}/{}", NAMESPACE_CONNECT_HISTORY, sn)).await;
+        let _ = conn.zrem(NAMESPACE_DEVICES_ALIVE, sn).await;
+        for node in nodes {
+            conn.del_key(&node_devices_key(node)).await;
+            let _ = conn.zrem(NAMESPACE_NODES, node).await;
+        }
+    }
+
+    /// 设备从节点a换到节点b上线，纪元递增并记录顶替与上线记录，旧纪元不能刷新或标记离线
+    #[test]
+    #[ignore]
+    fn test_epoch_takeover() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mut conn, mut raw) = connect().await;
+            let sn = "presence_test_takeover";
+            reset(&mut conn, sn, &["presence_test_a", "presence_test_b"]).await;
+
+            let first = conn.activate_device(sn, "presence_test_a", "10.0.0.1", 60).await.unwrap();
+            assert_eq!(first.replaced, None);
+            let second = conn.activate_device(sn, "presence_test_b", "10.0.0.2", 60).await.unwrap();
+            assert_eq!(second.epoch, first.epoch + 1);
+            assert_eq!(second.replaced, Some(("presence_test_a".to_string(), "10.0.0.1".to_string())));
+            let addrs: Vec<&str> = second.history.iter().map(|(_, addr)| addr.as_str()).collect();
+            assert_eq!(addrs, vec!["10.0.0.1", "10.0.0.2"]);
+            assert_eq!(conn.hget_optional(&status_key(sn), "takeover_node").await, Ok(Some("presence_test_a".to_string())));
+            let (first, second) = (first.epoch, second.epoch);
+            assert!(!raw.sismember::<_, _, bool>(node_devices_key("presence_test_a"), sn).await.unwrap());
+            assert!(raw.sismember::<_, _, bool>(node_devices_key("presence_test_b"), sn).await.unwrap());
+
+            assert_eq!(conn.touch_device(sn, first).await, Ok(false));
+            assert_eq!(conn.deactivate_device(sn, first, "test").await, Ok(false));
+            assert_eq!(conn.hget_optional(&status_key(sn), "online").await, Ok(Some("true".to_string())));
+
+            assert_eq!(conn.touch_device(sn, second).await, Ok(true));
+            assert_eq!(conn.deactivate_device(sn, second, "test").await, Ok(true));
+            assert!(!raw.sismember::<_, _, bool>(node_devices_key("presence_test_b"), sn).await.unwrap());
+            assert_eq!(conn.hget_optional(&status_key(sn), "node").await, Ok(None));
+            assert_eq!(conn.hget_optional(&status_key(sn), "offline_reason").await, Ok(Some("test".to_string())));
+        });
+    }
+
+    /// 刷新时间早于截止时间的设备被清理，之后刷新过的不受影响
+    #[test]
+    #[ignore]
+    fn test_sweep() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mut conn, mut raw) = connect().await;
+            let (stale, fresh) = ("presence_test_stale", "presence_test_fresh");
+            reset(&mut conn, stale, &["presence_test_sweep"]).await;
+            reset(&mut conn, fresh, &[]).await;
+
+            conn.activate_device(stale, "presence_test_sweep", "10.0.0.1", 60).await.unwrap();
+            conn.activate_device(fresh, "presence_test_sweep", "10.0.0.1", 60).await.unwrap();
+            // 只有score为1的设备早于截止时间2
+            conn.zadd(NAMESPACE_DEVICES_ALIVE, "1", stale).await.unwrap();
+            conn.zadd(NAMESPACE_DEVICES_ALIVE, "3", fresh).await.unwrap();
+            assert_eq!(conn.sweep_alive(2.0, 100, "expired").await, Ok(vec![stale.to_string()]));
+
+            assert_eq!(conn.zrank(NAMESPACE_DEVICES_ALIVE, stale).await, Ok(None));
+            assert!(!raw.sismember::<_, _, bool>(node_devices_key("presence_test_sweep"), stale).await.unwrap());
+            assert_eq!(conn.hget_optional(&status_key(stale), "online").await, Ok(Some("false".to_string())));
+            assert_eq!(conn.hget_optional(&status_key(stale), "offline_reason").await, Ok(Some("expired".to_string())));
+            assert!(raw.sismember::<_, _, bool>(node_devices_key("presence_test_sweep"), fresh).await.unwrap());
+
+            reset(&mut conn, fresh, &["presence_test_sweep"]).await;
+        });
+    }
+
+    /// 心跳超时的节点被回收，其上的设备离线且纪元递增；有心跳的节点不回收
+    #[test]
+    #[ignore]
+    fn test_reap_node() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mut conn, mut raw) = connect().await;
+            let (sn, moved) = ("presence_test_reaped", "presence_test_moved");
+            reset(&mut conn, sn, &["presence_test_down", "presence_test_up"]).await;
+            reset(&mut conn, moved, &[]).await;
+
+            let epoch = conn.activate_device(sn, "presence_test_down", "10.0.0.1", 60).await.unwrap().epoch;
+            conn.activate_device(moved, "presence_test_down", "10.0.0.1", 60).await.unwrap();
+            conn.activate_device(moved, "presence_test_up", "10.0.0.1", 60).await.unwrap();
+            // 集合中残留已转到其他节点的设备，回收时只移出集合
+            raw.sadd::<_, _, u8>(node_devices_key("presence_test_down"), moved).await.unwrap();
+            conn.node_heartbeat("presence_test_up", &[]).await.unwrap();
+
+            let now = conn.get_unix_timestamp();
+            assert_eq!(conn.reap_node("presence_test_up", now - 60.0, "node down").await, Ok(None));
+            assert_eq!(conn.reap_node("presence_test_down", now + 1.0, "node down").await, Ok(Some(1)));
+
+            assert_eq!(conn.touch_device(sn, epoch).await, Ok(false));
+            assert_eq!(conn.hget_optional(&status_key(sn), "node").await, Ok(None));
+            assert_eq!(conn.hget_optional(&status_key(sn), "offline_reason").await, Ok(Some("node down".to_string())));
+            assert_eq!(conn.hget_optional(&status_key(moved), "node").await, Ok(Some("presence_test_up".to_string())));
+            assert_eq!(raw.scard::<_, u64>(node_devices_key("presence_test_down")).await, Ok(0));
+
+            reset(&mut conn, moved, &["presence_test_up"]).await;
+            conn.del_key("csod/node/presence_test_up").await;
+        });
+    }
+}
+
 #[allow(dead_code)]
 async fn redis_set_key(con: &mut Connection) -> redis::RedisResult<()> {
-    con.set(format!("fukkkkkkkkk : {}", 12), b"fucko").await?;
+    con.set::<_, _, ()>(format!("fukkkkkkkkk : {}", 12), b"fucko").await?;
     Ok(())
 }
 
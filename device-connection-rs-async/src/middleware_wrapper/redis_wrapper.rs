@@ -1,43 +1,358 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::SystemTime;
 
 #[allow(unused_imports)]
 use log::{
     error,
     info,
+    warn,
 };
 use redis::{
-    aio::Connection,
+    aio::ConnectionManager,
     AsyncCommands,
     cmd as redis_cmd,
+    IntoConnectionInfo,
     RedisResult,
+    Script,
 };
 
+/// 带序号的event去重入队: KEYS[1]为设备status hash，KEYS[2]为事件队列，ARGV为event、序号、去重窗口
+/// 序号不大于已入队的最大序号且相差在窗口内视为重复，返回0；否则入队并记录序号，返回1
+/// 序号远小于记录值时认为设备计数器已重置，按新事件处理
+const PUSH_EVENT_DEDUP_SCRIPT: &str = r"
+local last = tonumber(redis.call('hget', KEYS[1], 'event_seq'))
+local seq = tonumber(ARGV[2])
+if last and seq <= last and last - seq < tonumber(ARGV[3]) then
+    return 0
+end
+redis.call('lpush', KEYS[2], ARGV[1])
+redis.call('hset', KEYS[1], 'event_seq', ARGV[2])
+return 1
+";
+
+/// key存在时才设置hash字段，ARGV依次为field, value，返回是否设置
+const HSET_IF_EXISTS_SCRIPT: &str = r"
+if redis.call('exists', KEYS[1]) == 0 then
+    return 0
+end
+for i = 1, #ARGV, 2 do
+    redis.call('hset', KEYS[1], ARGV[i], ARGV[i + 1])
+end
+return 1
+";
+
+/// 取出离线暂存的命令: KEYS依次为离线队列、命令记录hash，ARGV为预期的消息ID、当前时间戳
+/// 队列取出端不是预期的ID(已被其他连接取走)时不做修改，返回nil；记录不存在返回{'missing'}；
+/// 已过期的命令标记为expired，返回{'expired', msg}；否则返回{'ok', msg}，status在下发成功后才标记为delivered
+const POP_COMMAND_SCRIPT: &str = r"
+if redis.call('lindex', KEYS[1], -1) ~= ARGV[1] then
+    return nil
+end
+redis.call('rpop', KEYS[1])
+local record = redis.call('hmget', KEYS[2], 'msg', 'expire_at')
+if not record[1] then
+    return {'missing'}
+end
+if tonumber(ARGV[2]) > (tonumber(record[2]) or 0) then
+    redis.call('hset', KEYS[2], 'status', 'expired')
+    return {'expired', record[1]}
+end
+return {'ok', record[1]}
+";
+
+/// 设备上线: KEYS依次为设备status hash、在线有序集合、born有序集合、本节点的设备集合、上线记录列表，在其他节点上线过时KEYS[6]为上一节点的设备集合，
+/// ARGV为sn、时间戳、节点id、调用前读取的上一节点id(没有为空)、来源ip、上线记录保留时长(秒)、上线记录条数上限
+/// 上一节点与读取时不同(期间被其他连接修改)时不做修改，返回nil由调用方重新读取；
+/// 否则递增status中的`epoch`作为本次连接的纪元，设置在线状态、所在节点与来源ip，第一次上线的设备加入born集合；
+/// 上线时设备仍记录在某个节点上(不论是否本节点)，即顶替了旧连接，记入takeover字段；
+/// 返回{纪元, 上一节点, 旧连接来源ip, 上线记录...}，上线记录为`时间戳 来源ip`，新的在前，所有节点共享，用于检测克隆。
+/// 下行队列保留，激活后由handler取出，调用方仍在等待ack的消息不会丢失
+const ACTIVATE_SCRIPT: &str = r"
+local prev = redis.call('hget', KEYS[1], 'node')
+if (prev or '') ~= ARGV[4] then
+    return nil
+end
+local prev_addr = ''
+local epoch = redis.call('hincrby', KEYS[1], 'epoch', 1)
+if prev then
+    prev_addr = redis.call('hget', KEYS[1], 'addr') or ''
+    redis.call('hincrby', KEYS[1], 'takeovers', 1)
+    redis.call('hset', KEYS[1], 'takeover_from', prev_addr)
+    redis.call('hset', KEYS[1], 'takeover_to', ARGV[5])
+    redis.call('hset', KEYS[1], 'takeover_node', prev)
+    redis.call('hset', KEYS[1], 'takeover_at', ARGV[2])
+    if prev ~= ARGV[3] then
+        redis.call('srem', KEYS[6], ARGV[1])
+    end
+end
+redis.call('hset', KEYS[1], 'node', ARGV[3])
+redis.call('hset', KEYS[1], 'addr', ARGV[5])
+redis.call('sadd', KEYS[4], ARGV[1])
+redis.call('hset', KEYS[1], 'online', 'true')
+redis.call('hset', KEYS[1], 'toggletime', ARGV[2])
+redis.call('zadd', KEYS[2], ARGV[2], ARGV[1])
+if not redis.call('zscore', KEYS[3], ARGV[1]) then
+    redis.call('hset', KEYS[1], 'borntime', ARGV[2])
+    redis.call('zadd', KEYS[3], ARGV[2], ARGV[1])
+end
+redis.call('lpush', KEYS[5], ARGV[2] .. ' ' .. ARGV[5])
+redis.call('ltrim', KEYS[5], 0, tonumber(ARGV[7]) - 1)
+redis.call('expire', KEYS[5], ARGV[6])
+local result = {tostring(epoch), prev or '', prev_addr}
+for _, entry in ipairs(redis.call('lrange', KEYS[5], 0, -1)) do
+    result[#result + 1] = entry
+end
+return result
+";
+
+/// 刷新在线状态: KEYS依次为设备status hash、在线有序集合，ARGV为sn、纪元、时间戳
+/// 纪元不是最新的(设备已在其他连接上线)时不做修改，返回0
+const TOUCH_SCRIPT: &str = r"
+if redis.call('hget', KEYS[1], 'epoch') ~= ARGV[2] then
+    return 0
+end
+redis.call('hset', KEYS[1], 'online', 'true')
+redis.call('zadd', KEYS[2], ARGV[3], ARGV[1])
+return 1
+";
+
+/// 设备离线: KEYS与ARGV同TOUCH_SCRIPT，设备记录了所在节点时KEYS[3]为该节点的设备集合，ARGV[4]为离线原因，ARGV[5]为调用前读取的节点id(没有为空)
+/// 纪元不是最新的时不做修改，返回0；节点与读取时不同时不做修改，返回-1由调用方重新读取
+const DEACTIVATE_SCRIPT: &str = r"
+if redis.call('hget', KEYS[1], 'epoch') ~= ARGV[2] then
+    return 0
+end
+local node = redis.call('hget', KEYS[1], 'node')
+if (node or '') ~= ARGV[5] then
+    return -1
+end
+if node then
+    redis.call('srem', KEYS[3], ARGV[1])
+    redis.call('hdel', KEYS[1], 'node')
+end
+redis.call('hset', KEYS[1], 'online', 'false')
+redis.call('hset', KEYS[1], 'toggletime', ARGV[3])
+redis.call('hset', KEYS[1], 'offline_reason', ARGV[4])
+redis.call('zrem', KEYS[2], ARGV[1])
+return 1
+";
+
+/// 标记疑似克隆: KEYS依次为疑似克隆有序集合、该sn的记录hash、告警队列，ARGV为sn、时间戳、来源ip、切换次数、告警、检测窗口(秒)
+/// 检测窗口内已被标记过(可能由其他节点标记)时不做修改，返回0；否则记录本次检测结果并推入告警，返回该sn累计被标记的次数
+const FLAG_CLONE_SCRIPT: &str = r"
+local last = redis.call('hget', KEYS[2], 'detected_at')
+if last and tonumber(ARGV[2]) - tonumber(last) < tonumber(ARGV[6]) then
+    return 0
+end
+redis.call('zadd', KEYS[1], ARGV[2], ARGV[1])
+local count = redis.call('hincrby', KEYS[2], 'flags', 1)
+redis.call('hset', KEYS[2], 'detected_at', ARGV[2])
+redis.call('hset', KEYS[2], 'addrs', ARGV[3])
+redis.call('hset', KEYS[2], 'switches', ARGV[4])
+redis.call('lpush', KEYS[3], ARGV[5])
+return count
+";
+
+/// 清理一个过期的在线记录: KEYS依次为在线有序集合、在线状态事件队列、设备status hash，设备记录了所在节点时KEYS[4]为该节点的设备集合，
+/// ARGV为sn、截止时间戳、当前时间戳、离线原因、调用前读取的节点id(没有为空)
+/// 节点与读取时不同时返回-1由调用方重新读取；最近刷新时间已不早于截止时间(期间重新上线或刷新)时返回0；
+/// 否则移出在线集合与所在节点，status标记为离线，并推入offline事件，返回1
+const SWEEP_SCRIPT: &str = r"
+local node = redis.call('hget', KEYS[3], 'node')
+if (node or '') ~= ARGV[5] then
+    return -1
+end
+local seen = redis.call('zscore', KEYS[1], ARGV[1])
+if not seen or tonumber(seen) >= tonumber(ARGV[2]) then
+    return 0
+end
+if node then
+    redis.call('srem', KEYS[4], ARGV[1])
+    redis.call('hdel', KEYS[3], 'node')
+end
+redis.call('zrem', KEYS[1], ARGV[1])
+redis.call('hset', KEYS[3], 'online', 'false')
+redis.call('hset', KEYS[3], 'toggletime', ARGV[3])
+redis.call('hset', KEYS[3], 'offline_reason', ARGV[4])
+redis.call('lpush', KEYS[2], cjson.encode({type = 'offline', sn = ARGV[1], reason = ARGV[4], last_seen = math.floor(tonumber(seen)), time = math.floor(tonumber(ARGV[3]))}))
+return 1
+";
+
+/// 回收心跳超时的节点: KEYS依次为节点有序集合、节点hash、节点的设备集合，ARGV为节点id、心跳截止时间戳
+/// 节点在截止时间之后有过心跳时不回收，返回nil；否则删除节点记录，返回节点的设备集合，
+/// 其中的设备由调用方逐个执行`REAP_DEVICE_SCRIPT`。节点之后恢复心跳时按未登记处理，重新登记
+const REAP_NODE_SCRIPT: &str = r"
+local beat = redis.call('zscore', KEYS[1], ARGV[1])
+if beat and tonumber(beat) >= tonumber(ARGV[2]) then
+    return nil
+end
+redis.call('zrem', KEYS[1], ARGV[1])
+redis.call('del', KEYS[2])
+return redis.call('smembers', KEYS[3])
+";
+
+/// 回收节点上的一个设备: KEYS依次为设备status hash、节点的设备集合、在线有序集合、在线状态事件队列，ARGV为sn、节点id、当前时间戳、离线原因
+/// 设备已不属于该节点时只移出节点的设备集合，返回0；否则标记为离线并推入offline事件，返回1。
+/// 设备的纪元同时递增，节点只是暂时失联时，其上的连接在下一帧发现纪元过期而关闭，设备重连后重新记录所在节点
+const REAP_DEVICE_SCRIPT: &str = r"
+redis.call('srem', KEYS[2], ARGV[1])
+if redis.call('hget', KEYS[1], 'node') ~= ARGV[2] then
+    return 0
+end
+redis.call('hincrby', KEYS[1], 'epoch', 1)
+redis.call('hdel', KEYS[1], 'node')
+redis.call('zrem', KEYS[3], ARGV[1])
+redis.call('hset', KEYS[1], 'online', 'false')
+redis.call('hset', KEYS[1], 'toggletime', ARGV[3])
+redis.call('hset', KEYS[1], 'offline_reason', ARGV[4])
+redis.call('lpush', KEYS[4], cjson.encode({type = 'offline', sn = ARGV[1], reason = ARGV[4], node = ARGV[2], time = math.floor(tonumber(ARGV[3]))}))
+return 1
+";
+
+/// 节点心跳: KEYS依次为节点有序集合、节点hash，ARGV为节点id、时间戳，之后依次为field, value
+/// 返回心跳前节点记录是否存在，不存在表示节点未登记或已被回收
+const NODE_HEARTBEAT_SCRIPT: &str = r"
+local registered = redis.call('exists', KEYS[2])
+redis.call('zadd', KEYS[1], ARGV[2], ARGV[1])
+redis.call('hset', KEYS[2], 'heartbeat', ARGV[2])
+for i = 3, #ARGV, 2 do
+    redis.call('hset', KEYS[2], ARGV[i], ARGV[i + 1])
+end
+return registered
+";
+
+/// 获取或续期锁: KEYS[1]为锁，ARGV为持有者标识、有效期(毫秒)，锁空闲或已由自己持有时返回1
+const LOCK_SCRIPT: &str = r"
+local owner = redis.call('get', KEYS[1])
+if owner == ARGV[1] then
+    redis.call('pexpire', KEYS[1], ARGV[2])
+    return 1
+end
+if not owner then
+    redis.call('set', KEYS[1], ARGV[1], 'px', ARGV[2])
+    return 1
+end
+return 0
+";
+
+/// 释放锁: KEYS[1]为锁，ARGV[1]为持有者标识，只释放自己持有的锁
+const UNLOCK_SCRIPT: &str = r"
+if redis.call('get', KEYS[1]) == ARGV[1] then
+    return redis.call('del', KEYS[1])
+end
+return 0
+";
+
+/// 记录认证失败: KEYS依次为sn、ip的失败计数，ARGV[1]为封禁时长(秒)
+/// 计数递增并重新设置过期时间，封禁时长内没有新的失败时计数自动清除，返回各计数
+const AUTH_FAIL_SCRIPT: &str = r"
+local counts = {}
+for i = 1, #KEYS do
+    counts[i] = redis.call('incr', KEYS[i])
+    redis.call('expire', KEYS[i], ARGV[1])
+end
+return counts
+";
+
+/// 先读取再执行的脚本发现读取的记录已被并发修改时，重新读取的次数上限
+const SCRIPT_RETRIES: usize = 3;
+
+/// `activate_device`的结果
+#[derive(Debug, PartialEq)]
+pub struct Activation {
+    /// 本次连接的纪元
+    pub epoch: u64,
+    /// 设备上线前仍记录在某个节点上时，为该节点id与旧连接的来源ip，即本次上线顶替了旧连接
+    pub replaced: Option<(String, String)>,
+    /// 所有节点共享的上线记录(时间戳, 来源ip)，按时间先后，包含本次上线
+    pub history: Vec<(f64, String)>,
+}
+
+/// redis连接句柄，底层为多路复用连接，clone开销很小，断线后自动重连
+#[derive(Clone)]
 pub struct RedisConn {
-    conn: Option<Connection>,
+    conn: Option<ConnectionManager>,
+}
+
+/// 整个连接服务共享的redis连接池，所有设备handler轮流复用池内的多路复用连接
+pub struct RedisPool {
+    conns: Vec<RedisConn>,
+    next: AtomicUsize,
 }
 
 pub const NAMESPACE_DEVICES_BORN: &str = "csod/devices_born";
 pub const NAMESPACE_DEVICES_ALIVE: &str = "csod/devices_alive";
 pub const NAMESPACE_DEVICE_STATUS: &str = "csod/device_status";
 pub const NAMESPACE_DEVICES_COMMON_EVENT_NOTIFY: &str = "csod/mq/p5";
+/// 每个设备的下行消息队列，key为`csod/downlink/{sn}`
+pub const NAMESPACE_DEVICE_DOWNLINK: &str = "csod/downlink";
+/// 设备的get/set响应，key为`csod/uplink/{sn}/{id}`，id为请求中的消息ID
+pub const NAMESPACE_DEVICE_UPLINK: &str = "csod/uplink";
+/// 离线设备暂存命令的消息ID队列，key为`csod/offline/{sn}`
+pub const NAMESPACE_DEVICE_OFFLINE: &str = "csod/offline";
+/// 暂存命令的记录，key为`csod/command/{sn}/{id}`，字段: status, msg, expire_at, ack
+pub const NAMESPACE_DEVICE_COMMAND: &str = "csod/command";
+/// 下行消息入队通知频道，消息内容为sn
+pub const NAMESPACE_DOWNLINK_NOTIFY: &str = "csod/downlink_notify";
+/// 设备的上线记录，key为`csod/connect_history/{sn}`，元素为`时间戳 来源ip`，新的在前，超过克隆检测窗口后过期
+pub const NAMESPACE_CONNECT_HISTORY: &str = "csod/connect_history";
+/// 上线记录保留的条数上限
+const CONNECT_HISTORY_LEN: usize = 32;
+/// 疑似克隆的sn，score为最近一次检测时间；检测记录的key为`csod/suspected_clones/{sn}`，字段: flags, detected_at, addrs, switches
+pub const NAMESPACE_SUSPECTED_CLONES: &str = "csod/suspected_clones";
+/// 服务端产生的告警队列，如疑似克隆
+pub const NAMESPACE_ALARM_NOTIFY: &str = "csod/mq/alarm";
+/// 在线状态事件队列，目前只有清理过期在线记录时产生的offline事件
+pub const NAMESPACE_PRESENCE_NOTIFY: &str = "csod/mq/presence";
+/// 在线记录清理任务的锁，多个连接服务进程同时只有一个执行清理
+pub const NAMESPACE_SWEEPER_LOCK: &str = "csod/sweeper_lock";
+/// 认证失败计数，key为`csod/auth_fail/sn/{sn}`与`csod/auth_fail/ip/{ip}`，最近一次失败后封禁时长内有效，多个节点共享
+pub const NAMESPACE_AUTH_FAIL: &str = "csod/auth_fail";
+/// 连接服务节点，有序集合，score为最近心跳时间
+pub const NAMESPACE_NODES: &str = "csod/nodes";
+/// 节点信息，key为`csod/node/{id}`，字段: addr, pid, started_at, heartbeat, ttl, connections
+pub const NAMESPACE_NODE: &str = "csod/node";
+/// 节点上在线的sn集合，key为`csod/node_devices/{id}`
+pub const NAMESPACE_NODE_DEVICES: &str = "csod/node_devices";
+
+#[allow(dead_code)]
+impl RedisPool {
+    /// 建立`size`个多路复用连接，`size`为0时按1处理
+    pub async fn new(ip: &str, port: &str, size: usize) -> RedisResult<RedisPool> {
+        let mut conns = Vec::with_capacity(size.max(1));
+        for _ in 0..size.max(1) {
+            conns.push(RedisConn::new(ip, port).await?);
+        }
+        info!("redis pool created, size {}", conns.len());
+        Ok(RedisPool { conns, next: AtomicUsize::new(0) })
+    }
+
+    /// 轮询取出一个连接句柄
+    pub fn get(&self) -> RedisConn {
+        let idx = self.next.fetch_add(1, Ordering::Relaxed) % self.conns.len();
+        self.conns[idx].clone()
+    }
+
+    pub fn size(&self) -> usize {
+        self.conns.len()
+    }
+}
 
 #[allow(dead_code)]
 impl RedisConn {
-    /// 建立一个redis async连接
+    /// 建立一个redis多路复用连接，需要在tokio runtime内调用
     pub async fn new(ip: &str, port: &str) -> RedisResult<RedisConn> {
         let _redis_addr = format!("redis://{}:{}/", ip, port);
-        let client_result = redis::Client::open(_redis_addr);
-
-        match client_result {
-            Ok(cli) => {
-                match cli.get_async_connection().await {
-                    Ok(conn) => Ok(RedisConn { conn: Some(conn) }),
-                    Err(e) => {
-                        error!("get async redis connection fail: {:?}", e);
-                        Err(e)
-                    }
-                }
+        let conn_info = match _redis_addr.into_connection_info() {
+            Ok(info) => info,
+            Err(e) => {
+                error!("invalid redis addr: {:?}", e);
+                return Err(e);
             }
+        };
+
+        match ConnectionManager::new(conn_info).await {
+            Ok(conn) => Ok(RedisConn { conn: Some(conn) }),
             Err(e) => {
                 error!("connect redis fail: {:?}", e);
                 Err(e)
@@ -63,6 +378,24 @@ impl RedisConn {
         }
     }
 
+    /// 设置值并指定过期时间(秒)
+    pub async fn set_ex(&mut self, k: &str, v: &str, seconds: usize) -> Result<(), ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        match cli.set_ex::<&str, &str, String>(k, v, seconds).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("set key({}) with expire to redis failed: {:?}", k, e);
+                Err(())
+            }
+        }
+    }
+
     pub async fn get(&mut self, key: &str) -> Result<String, ()> {
         let cli = if let Some(conn) = &mut self.conn {
             conn
@@ -99,6 +432,24 @@ impl RedisConn {
     }
 
 
+    /// 将数据从右端(rpush)放回指定redis list，下一次pop时最先取出
+    pub async fn push_back_to_list(&mut self, list_name: &str, v: &str) -> Result<usize, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        match cli.rpush::<&str, &str, usize>(list_name, v).await {
+            Ok(len) => Ok(len),
+            Err(e) => {
+                error!("push back msg({}) to list({}) failed: {:?}", v, list_name, e);
+                Err(())
+            }
+        }
+    }
+
     pub async fn pop_from_list(&mut self, list_name: &str) -> Result<Option<String>, ()> {
         let cli = if let Some(conn) = &mut self.conn {
             conn
@@ -116,6 +467,42 @@ impl RedisConn {
         }
     }
 
+    /// 查看列表取出端(右端)的元素，不取出
+    pub async fn peek_list_tail(&mut self, list_name: &str) -> Result<Option<String>, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        match cli.lindex::<&str, Option<String>>(list_name, -1).await {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                error!("peek list({}) failed: {:?}", list_name, e);
+                Err(())
+            }
+        }
+    }
+
+    /// 读取计数，key不存在时为0
+    pub async fn get_count(&mut self, key: &str) -> Result<u64, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        match cli.get::<&str, Option<u64>>(key).await {
+            Ok(v) => Ok(v.unwrap_or(0)),
+            Err(e) => {
+                error!("get count({}) failed: {:?}", key, e);
+                Err(())
+            }
+        }
+    }
+
     pub async fn del_key(&mut self, key: &str) {
         let cli = if let Some(conn) = &mut self.conn {
             conn
@@ -130,6 +517,24 @@ impl RedisConn {
             .await;
     }
 
+    /// key是否存在
+    pub async fn exists(&mut self, key: &str) -> Result<bool, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        match cli.exists::<&str, bool>(key).await {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                error!("exists key({}) fail: {:?}", key, e);
+                Err(())
+            }
+        }
+    }
+
     pub async fn zcard(&mut self, key: &str) -> Result<u64, ()> {
         let cli = if let Some(conn) = &mut self.conn {
             conn
@@ -312,6 +717,69 @@ impl RedisConn {
         }
     }
 
+    /// 读取hash字段，key或字段不存在时返回None
+    pub async fn hget_optional(&mut self, key: &str, field: &str) -> Result<Option<String>, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        let query: RedisResult<Option<String>> = redis_cmd("hget")
+            .arg(key).arg(field)
+            .query_async(cli)
+            .await;
+        query.map_err(|e| error!("hget {} {} fail: {:?}", key, field, e))
+    }
+
+    /// 按序号去重后推入事件队列，入队与记录序号在redis内原子执行，返回是否为新事件
+    pub async fn push_event_dedup(&mut self, status_key: &str, queue: &str, event: &str, seq: u64, window: u64) -> Result<bool, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        let query: RedisResult<u8> = Script::new(PUSH_EVENT_DEDUP_SCRIPT)
+            .key(status_key).key(queue)
+            .arg(event).arg(seq).arg(window)
+            .invoke_async(cli)
+            .await;
+        match query {
+            Ok(v) => Ok(v == 1),
+            Err(e) => {
+                error!("push event({}) to list({}) failed: {:?}", event, queue, e);
+                Err(())
+            }
+        }
+    }
+
+    /// hash表存在时才设置字段，不会因为记录已过期而重新创建，返回是否设置
+    pub async fn hset_if_exists(&mut self, key: &str, items: &[(&str, &str)]) -> Result<bool, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        let script = Script::new(HSET_IF_EXISTS_SCRIPT);
+        let mut invocation = script.key(key);
+        for (field, value) in items {
+            invocation.arg(*field).arg(*value);
+        }
+        let query: RedisResult<u8> = invocation.invoke_async(cli).await;
+        match query {
+            Ok(v) => Ok(v == 1),
+            Err(e) => {
+                error!("set hash if exists fail: {}, {:?}", key, e);
+                Err(())
+            }
+        }
+    }
+
     /// 删除hash表指定key的指定字段
     pub async fn hdel(&mut self, key: &str, field: &str) -> Result<u64, ()> {
         let cli = if let Some(conn) = &mut self.conn {
@@ -333,6 +801,362 @@ impl RedisConn {
         }
     }
 
+    /// 从离线队列取出消息ID为`id`的命令并读取记录，在redis内原子执行，返回(结果, msg)，取出端已不是`id`时返回None，见`POP_COMMAND_SCRIPT`
+    pub async fn pop_command(&mut self, queue: &str, command_key: &str, id: &str) -> Result<Option<(String, Option<String>)>, ()> {
+        let stamp = format!("{}", self.get_unix_timestamp());
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        let query: RedisResult<Option<Vec<String>>> = Script::new(POP_COMMAND_SCRIPT)
+            .key(queue).key(command_key)
+            .arg(id).arg(&stamp)
+            .invoke_async(cli)
+            .await;
+        match query {
+            Ok(Some(mut v)) if !v.is_empty() => {
+                let msg = if v.len() > 1 { Some(v.remove(1)) } else { None };
+                Ok(Some((v.remove(0), msg)))
+            }
+            Ok(_) => Ok(None),
+            Err(e) => {
+                error!("pop command({}) from list({}) failed: {:?}", id, queue, e);
+                Err(())
+            }
+        }
+    }
+
+    /// 设备从`addr`连接到节点`node`上线，上线记录保留`history_secs`秒，见`ACTIVATE_SCRIPT`
+    pub async fn activate_device(&mut self, sn: &str, node: &str, addr: &str, history_secs: u64) -> Result<Activation, ()> {
+        let status = format!("{}/{}", NAMESPACE_DEVICE_STATUS, sn);
+        for _ in 0..SCRIPT_RETRIES {
+            let prev = self.hget_optional(&status, "node").await?.unwrap_or_default();
+            let stamp = format!("{}", self.get_unix_timestamp());
+            let cli = if let Some(conn) = &mut self.conn {
+                conn
+            } else {
+                error!("Redis conn in RedisConn struct is None");
+                return Err(());
+            };
+
+            let script = Script::new(ACTIVATE_SCRIPT);
+            let mut invocation = script.key(&status);
+            invocation.key(NAMESPACE_DEVICES_ALIVE)
+                .key(NAMESPACE_DEVICES_BORN)
+                .key(format!("{}/{}", NAMESPACE_NODE_DEVICES, node))
+                .key(format!("{}/{}", NAMESPACE_CONNECT_HISTORY, sn));
+            if !prev.is_empty() && prev != node {
+                invocation.key(format!("{}/{}", NAMESPACE_NODE_DEVICES, prev));
+            }
+            invocation.arg(sn).arg(&stamp).arg(node).arg(&prev).arg(addr).arg(history_secs.max(1)).arg(CONNECT_HISTORY_LEN);
+            let query: RedisResult<Option<Vec<String>>> = invocation.invoke_async(cli).await;
+            match query {
+                Ok(Some(result)) if result.len() >= 3 => return Ok(RedisConn::parse_activation(result)),
+                Ok(Some(result)) => {
+                    error!("activate device({}) fail: unexpected result {:?}", sn, result);
+                    return Err(());
+                }
+                Ok(None) => warn!("node of device({}) changed while activating, retry", sn),
+                Err(e) => {
+                    error!("activate device({}) fail: {:?}", sn, e);
+                    return Err(());
+                }
+            }
+        }
+        error!("activate device({}) fail: node keeps changing", sn);
+        Err(())
+    }
+
+    fn parse_activation(result: Vec<String>) -> Activation {
+        let epoch = result[0].parse().unwrap_or(0);
+        let replaced = if result[1].is_empty() { None } else { Some((result[1].clone(), result[2].clone())) };
+        let mut history: Vec<(f64, String)> = result[3..].iter()
+            .filter_map(|entry| {
+                let mut parts = entry.splitn(2, ' ');
+                match (parts.next().and_then(|t| t.parse().ok()), parts.next()) {
+                    (Some(stamp), Some(addr)) => Some((stamp, addr.to_string())),
+                    _ => None,
+                }
+            })
+            .collect();
+        history.reverse();
+        Activation { epoch, replaced, history }
+    }
+
+    /// 刷新在线状态，纪元不是最新的时返回false，见`TOUCH_SCRIPT`
+    pub async fn touch_device(&mut self, sn: &str, epoch: u64) -> Result<bool, ()> {
+        let stamp = format!("{}", self.get_unix_timestamp());
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        let query: RedisResult<u8> = Script::new(TOUCH_SCRIPT)
+            .key(format!("{}/{}", NAMESPACE_DEVICE_STATUS, sn))
+            .key(NAMESPACE_DEVICES_ALIVE)
+            .arg(sn).arg(epoch).arg(&stamp)
+            .invoke_async(cli)
+            .await;
+        match query {
+            Ok(v) => Ok(v == 1),
+            Err(e) => {
+                error!("update device({}) presence fail: {:?}", sn, e);
+                Err(())
+            }
+        }
+    }
+
+    /// 设备离线并记录原因，纪元不是最新的时返回false，见`DEACTIVATE_SCRIPT`
+    pub async fn deactivate_device(&mut self, sn: &str, epoch: u64, reason: &str) -> Result<bool, ()> {
+        let status = format!("{}/{}", NAMESPACE_DEVICE_STATUS, sn);
+        for _ in 0..SCRIPT_RETRIES {
+            let node = self.hget_optional(&status, "node").await?.unwrap_or_default();
+            let stamp = format!("{}", self.get_unix_timestamp());
+            let cli = if let Some(conn) = &mut self.conn {
+                conn
+            } else {
+                error!("Redis conn in RedisConn struct is None");
+                return Err(());
+            };
+
+            let script = Script::new(DEACTIVATE_SCRIPT);
+            let mut invocation = script.key(&status);
+            invocation.key(NAMESPACE_DEVICES_ALIVE);
+            if !node.is_empty() {
+                invocation.key(format!("{}/{}", NAMESPACE_NODE_DEVICES, node));
+            }
+            invocation.arg(sn).arg(epoch).arg(&stamp).arg(reason).arg(&node);
+            match invocation.invoke_async::<_, i64>(cli).await {
+                Ok(v) if v >= 0 => return Ok(v == 1),
+                Ok(_) => warn!("node of device({}) changed while deactivating, retry", sn),
+                Err(e) => {
+                    error!("deactivate device({}) fail: {:?}", sn, e);
+                    return Err(());
+                }
+            }
+        }
+        error!("deactivate device({}) fail: node keeps changing", sn);
+        Err(())
+    }
+
+    /// 标记疑似克隆并推入告警，返回该sn累计被标记的次数，`window`秒内已标记过时返回0，见`FLAG_CLONE_SCRIPT`
+    pub async fn flag_clone(&mut self, sn: &str, addrs: &str, switches: usize, alarm: &str, window: u64) -> Result<u64, ()> {
+        let stamp = format!("{}", self.get_unix_timestamp());
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        let query: RedisResult<u64> = Script::new(FLAG_CLONE_SCRIPT)
+            .key(NAMESPACE_SUSPECTED_CLONES)
+            .key(format!("{}/{}", NAMESPACE_SUSPECTED_CLONES, sn))
+            .key(NAMESPACE_ALARM_NOTIFY)
+            .arg(sn).arg(&stamp).arg(addrs).arg(switches).arg(alarm).arg(window)
+            .invoke_async(cli)
+            .await;
+        query.map_err(|e| error!("flag clone of device({}) fail: {:?}", sn, e))
+    }
+
+    /// 认证失败计数递增，返回各key的计数，见`AUTH_FAIL_SCRIPT`
+    pub async fn record_auth_failure(&mut self, keys: &[&str], ban_secs: u64) -> Result<Vec<u64>, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        let script = Script::new(AUTH_FAIL_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        for key in keys {
+            invocation.key(*key);
+        }
+        let query: RedisResult<Vec<u64>> = invocation.arg(ban_secs).invoke_async(cli).await;
+        query.map_err(|e| error!("record auth failure({:?}) fail: {:?}", keys, e))
+    }
+
+    /// 获取或续期`key`上的锁，返回是否持有，见`LOCK_SCRIPT`
+    pub async fn acquire_lock(&mut self, key: &str, owner: &str, ttl_ms: u64) -> Result<bool, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        let query: RedisResult<u8> = Script::new(LOCK_SCRIPT)
+            .key(key)
+            .arg(owner).arg(ttl_ms)
+            .invoke_async(cli)
+            .await;
+        query.map(|v| v == 1).map_err(|e| error!("acquire lock({}) fail: {:?}", key, e))
+    }
+
+    /// 释放自己持有的锁，见`UNLOCK_SCRIPT`
+    pub async fn release_lock(&mut self, key: &str, owner: &str) -> Result<bool, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        let query: RedisResult<u8> = Script::new(UNLOCK_SCRIPT)
+            .key(key)
+            .arg(owner)
+            .invoke_async(cli)
+            .await;
+        query.map(|v| v == 1).map_err(|e| error!("release lock({}) fail: {:?}", key, e))
+    }
+
+    /// 清理最近刷新时间早于`before`的在线记录，最多`limit`个，返回被清理的sn，见`SWEEP_SCRIPT`
+    pub async fn sweep_alive(&mut self, before: f64, limit: usize, reason: &str) -> Result<Vec<String>, ()> {
+        let stale = {
+            let cli = if let Some(conn) = &mut self.conn {
+                conn
+            } else {
+                error!("Redis conn in RedisConn struct is None");
+                return Err(());
+            };
+            let query: RedisResult<Vec<String>> = redis_cmd("zrangebyscore")
+                .arg(NAMESPACE_DEVICES_ALIVE).arg("-inf").arg(format!("({}", before)).arg("limit").arg(0).arg(limit)
+                .query_async(cli)
+                .await;
+            query.map_err(|e| error!("sweep alive devices fail: {:?}", e))?
+        };
+
+        let mut swept = vec![];
+        for sn in stale {
+            let status = format!("{}/{}", NAMESPACE_DEVICE_STATUS, sn);
+            for _ in 0..SCRIPT_RETRIES {
+                let node = self.hget_optional(&status, "node").await?.unwrap_or_default();
+                let stamp = format!("{}", self.get_unix_timestamp());
+                let cli = if let Some(conn) = &mut self.conn {
+                    conn
+                } else {
+                    error!("Redis conn in RedisConn struct is None");
+                    return Err(());
+                };
+
+                let script = Script::new(SWEEP_SCRIPT);
+                let mut invocation = script.key(NAMESPACE_DEVICES_ALIVE);
+                invocation.key(NAMESPACE_PRESENCE_NOTIFY).key(&status);
+                if !node.is_empty() {
+                    invocation.key(format!("{}/{}", NAMESPACE_NODE_DEVICES, node));
+                }
+                invocation.arg(&sn).arg(before).arg(&stamp).arg(reason).arg(&node);
+                match invocation.invoke_async::<_, i64>(cli).await {
+                    Ok(1) => {
+                        swept.push(sn);
+                        break;
+                    }
+                    Ok(0) => break,
+                    Ok(_) => warn!("node of device({}) changed while sweeping, retry", sn),
+                    Err(e) => {
+                        error!("sweep device({}) fail: {:?}", sn, e);
+                        return Err(());
+                    }
+                }
+            }
+        }
+        Ok(swept)
+    }
+
+    /// 按score范围返回有序集合的成员，`max`不包含在内
+    pub async fn zrangebyscore_below(&mut self, key: &str, max: f64) -> Result<Vec<String>, ()> {
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        let query: RedisResult<Vec<String>> = redis_cmd("zrangebyscore")
+            .arg(key).arg("-inf").arg(format!("({}", max))
+            .query_async(cli)
+            .await;
+        query.map_err(|e| error!("zrangebyscore {} fail: {:?}", key, e))
+    }
+
+    /// 节点心跳，同时更新节点信息，返回心跳前节点记录是否存在，见`NODE_HEARTBEAT_SCRIPT`
+    pub async fn node_heartbeat(&mut self, node: &str, items: &[(&str, &str)]) -> Result<bool, ()> {
+        let stamp = format!("{}", self.get_unix_timestamp());
+        let cli = if let Some(conn) = &mut self.conn {
+            conn
+        } else {
+            error!("Redis conn in RedisConn struct is None");
+            return Err(());
+        };
+
+        let script = Script::new(NODE_HEARTBEAT_SCRIPT);
+        let mut invocation = script.key(NAMESPACE_NODES);
+        invocation.key(format!("{}/{}", NAMESPACE_NODE, node)).arg(node).arg(&stamp);
+        for (field, value) in items {
+            invocation.arg(*field).arg(*value);
+        }
+        let query: RedisResult<u8> = invocation.invoke_async(cli).await;
+        query.map(|registered| registered == 1).map_err(|e| error!("heartbeat of node({}) fail: {:?}", node, e))
+    }
+
+    /// 回收心跳早于`before`的节点，返回离线的设备数，节点已恢复心跳时返回None，见`REAP_NODE_SCRIPT`、`REAP_DEVICE_SCRIPT`
+    pub async fn reap_node(&mut self, node: &str, before: f64, reason: &str) -> Result<Option<u64>, ()> {
+        let devices_key = format!("{}/{}", NAMESPACE_NODE_DEVICES, node);
+        let devices = {
+            let cli = if let Some(conn) = &mut self.conn {
+                conn
+            } else {
+                error!("Redis conn in RedisConn struct is None");
+                return Err(());
+            };
+            let query: RedisResult<Option<Vec<String>>> = Script::new(REAP_NODE_SCRIPT)
+                .key(NAMESPACE_NODES)
+                .key(format!("{}/{}", NAMESPACE_NODE, node))
+                .key(&devices_key)
+                .arg(node).arg(before)
+                .invoke_async(cli)
+                .await;
+            match query {
+                Ok(Some(devices)) => devices,
+                Ok(None) => return Ok(None),
+                Err(e) => {
+                    error!("reap node({}) fail: {:?}", node, e);
+                    return Err(());
+                }
+            }
+        };
+
+        let mut count = 0;
+        for sn in devices {
+            let stamp = format!("{}", self.get_unix_timestamp());
+            let cli = if let Some(conn) = &mut self.conn {
+                conn
+            } else {
+                error!("Redis conn in RedisConn struct is None");
+                return Err(());
+            };
+            let query: RedisResult<u8> = Script::new(REAP_DEVICE_SCRIPT)
+                .key(format!("{}/{}", NAMESPACE_DEVICE_STATUS, sn))
+                .key(&devices_key)
+                .key(NAMESPACE_DEVICES_ALIVE)
+                .key(NAMESPACE_PRESENCE_NOTIFY)
+                .arg(&sn).arg(node).arg(&stamp).arg(reason)
+                .invoke_async(cli)
+                .await;
+            match query {
+                Ok(reaped) => count += u64::from(reaped),
+                Err(e) => error!("reap device({}) of node({}) fail: {:?}", sn, node, e),
+            }
+        }
+        Ok(Some(count))
+    }
+
     /// 设置指定key的`toggletime`字段，并同时设置当前时间戳
     pub async fn hset_toggletime(&mut self, key: &str) -> Result<Option<usize>, ()> {
         self.hset(key, "toggletime", &format!("{}", self.get_unix_timestamp())).await
@@ -0,0 +1,231 @@
+//!
+//! # 设备认证
+//! 握手时服务端在pong中下发随机challenge，设备回复`auth`，
+//! 其中`mac` = HMAC-SHA256(认证密钥, challenge || sn)，sn为大写，认证密钥见`secure::auth_key`。
+//! 认证通过后设备才会被激活；失败按sn与来源ip分别在redis中计数，所有节点共享，
+//! 超过次数的sn或ip在一段时间内直接拒绝
+//!
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[allow(unused_imports)]
+use log::{
+    error,
+    info,
+    warn,
+};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::common::config::PerceptionServiceConfig as PerceptCfg;
+use crate::middleware_wrapper::redis_wrapper::{RedisConn, NAMESPACE_AUTH_FAIL};
+use crate::perception_service::secure::{auth_key, device_key};
+
+pub const CHALLENGE_LEN: usize = 16;
+
+/// 未配置时的失败次数上限与封禁时长
+const DEFAULT_MAX_FAILURES: u32 = 5;
+const DEFAULT_BAN_SECS: u64 = 300;
+
+pub struct Authenticator {
+    /// 没有配置时不认证
+    master_key: Option<Vec<u8>>,
+    max_failures: u32,
+    ban_secs: u64,
+    rng: SystemRandom,
+    /// 本进程累计的失败次数，只用于统计
+    total_failures: AtomicU64,
+}
+
+impl Authenticator {
+    pub fn from_cfg(cfg: &PerceptCfg) -> Result<Authenticator, String> {
+        let master_key = if cfg.challenge_auth.unwrap_or(false) {
+            match &cfg.psk_master_key {
+                Some(key) => Some(key.as_bytes().to_vec()),
+                None => return Err("challenge_auth requires psk_master_key".to_string()),
+            }
+        } else {
+            None
+        };
+        Ok(Authenticator::new(
+            master_key,
+            cfg.auth_max_failures.unwrap_or(DEFAULT_MAX_FAILURES),
+            cfg.auth_ban_secs.unwrap_or(DEFAULT_BAN_SECS),
+        ))
+    }
+
+    fn new(master_key: Option<Vec<u8>>, max_failures: u32, ban_secs: u64) -> Authenticator {
+        Authenticator {
+            master_key,
+            max_failures: max_failures.max(1),
+            ban_secs: ban_secs.max(1),
+            rng: SystemRandom::new(),
+            total_failures: AtomicU64::new(0),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.master_key.is_some()
+    }
+
+    /// 生成challenge，base64
+    pub fn challenge(&self) -> Result<String, String> {
+        let mut nonce = [0u8; CHALLENGE_LEN];
+        self.rng.fill(&mut nonce).map_err(|_| "generate challenge failed".to_string())?;
+        Ok(base64::encode(nonce))
+    }
+
+    /// 校验设备的应答，`sn`须已转为大写，比较为常数时间
+    pub fn verify(&self, sn: &str, challenge: &str, mac: &str) -> bool {
+        let master_key = match &self.master_key {
+            Some(key) => key,
+            None => return true,
+        };
+        let (challenge, mac) = match (base64::decode(challenge), base64::decode(mac)) {
+            (Ok(challenge), Ok(mac)) => (challenge, mac),
+            _ => return false,
+        };
+        let key = auth_key(&device_key(master_key, sn));
+        hmac::verify(&key, &[&challenge[..], sn.as_bytes()].concat(), &mac).is_ok()
+    }
+
+    fn sn_key(sn: &str) -> String {
+        format!("{}/sn/{}", NAMESPACE_AUTH_FAIL, sn)
+    }
+
+    fn ip_key(ip: &IpAddr) -> String {
+        format!("{}/ip/{}", NAMESPACE_AUTH_FAIL, ip)
+    }
+
+    /// 失败计数达到上限即被封禁，计数在最近一次失败后`ban_secs`过期
+    fn exceeded(&self, count: u64) -> bool {
+        count >= u64::from(self.max_failures)
+    }
+
+    /// 查询失败时不拒绝，redis不可用不影响设备上线
+    async fn blocked(&self, conn: &mut RedisConn, key: &str) -> bool {
+        match conn.get_count(key).await {
+            Ok(count) => self.exceeded(count),
+            Err(_) => {
+                error!("query auth failures of {} failed", key);
+                false
+            }
+        }
+    }
+
+    pub async fn sn_blocked(&self, conn: &mut RedisConn, sn: &str) -> bool {
+        self.blocked(conn, &Authenticator::sn_key(sn)).await
+    }
+
+    pub async fn ip_blocked(&self, conn: &mut RedisConn, ip: &IpAddr) -> bool {
+        self.blocked(conn, &Authenticator::ip_key(ip)).await
+    }
+
+    pub async fn record_failure(&self, conn: &mut RedisConn, sn: &str, ip: &IpAddr) {
+        let total = self.total_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        let keys = [Authenticator::sn_key(sn), Authenticator::ip_key(ip)];
+        match conn.record_auth_failure(&[&keys[0], &keys[1]], self.ban_secs).await {
+            Ok(counts) => {
+                for (key, count) in keys.iter().zip(counts) {
+                    if count == u64::from(self.max_failures) {
+                        warn!("{} blocked for {}s after {} auth failures", key, self.ban_secs, count);
+                    }
+                }
+            }
+            Err(_) => error!("record auth failure of sn {}, ip {} failed", sn, ip),
+        }
+        warn!("auth failed: sn {}, ip {} ({} failures on this node)", sn, ip, total);
+    }
+
+    /// 认证成功后清除该sn的失败记录，ip的记录保留
+    pub async fn record_success(&self, conn: &mut RedisConn, sn: &str) {
+        conn.del_key(&Authenticator::sn_key(sn)).await;
+    }
+
+    /// 本进程累计失败次数
+    pub fn total_failures(&self) -> u64 {
+        self.total_failures.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod auth_test {
+    use std::net::IpAddr;
+
+    use ring::hmac;
+    use tokio::runtime::Runtime;
+
+    use crate::config;
+    use crate::middleware_wrapper::redis_wrapper::RedisConn;
+    use crate::perception_service::secure::{auth_key, device_key};
+
+    use super::Authenticator;
+
+    #[test]
+    fn test_verify() {
+        let auth = Authenticator::new(Some(b"master".to_vec()), 5, 60);
+        let challenge = auth.challenge().unwrap();
+        assert_ne!(challenge, auth.challenge().unwrap());
+
+        let sn = "A0AA1B0001F3E";
+        let key = auth_key(&device_key(b"master", sn));
+        let msg = [&base64::decode(&challenge).unwrap()[..], sn.as_bytes()].concat();
+        let mac = base64::encode(hmac::sign(&key, &msg));
+        assert!(auth.verify(sn, &challenge, &mac));
+        // 其他sn、其他challenge、非base64
+        assert!(!auth.verify("A0AA1B0002F3E", &challenge, &mac));
+        assert!(!auth.verify(sn, &auth.challenge().unwrap(), &mac));
+        assert!(!auth.verify(sn, &challenge, "not base64!"));
+        // 直接用设备密钥(会话密钥的ikm)签名的应答不能通过
+        let raw = hmac::Key::new(hmac::HMAC_SHA256, &device_key(b"master", sn));
+        assert!(!auth.verify(sn, &challenge, &base64::encode(hmac::sign(&raw, &msg))));
+
+        let disabled = Authenticator::new(None, 5, 60);
+        assert!(!disabled.enabled());
+    }
+
+    #[test]
+    fn test_exceeded() {
+        let auth = Authenticator::new(Some(b"master".to_vec()), 3, 60);
+        assert!(!auth.exceeded(2));
+        assert!(auth.exceeded(3));
+        assert_eq!(Authenticator::sn_key("A0AA1B0001F3E"), "csod/auth_fail/sn/A0AA1B0001F3E");
+        assert_eq!(Authenticator::ip_key(&"10.0.0.1".parse().unwrap()), "csod/auth_fail/ip/10.0.0.1");
+    }
+
+    /// 失败计数在redis中，需要cfg.toml中配置的redis
+    #[test]
+    #[ignore]
+    fn test_block() {
+        let config: config::Config = config::load_config("cfg.toml", false);
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut conn = RedisConn::new(&config.redis.ip.unwrap(), &config.redis.port.unwrap()).await.unwrap();
+            let auth = Authenticator::new(Some(b"master".to_vec()), 3, 60);
+            let ip: IpAddr = "10.255.0.1".parse().unwrap();
+            let other_ip: IpAddr = "10.255.0.2".parse().unwrap();
+            for key in ["auth_test_a", "auth_test_b"].iter().map(|sn| Authenticator::sn_key(sn)).chain([ip, other_ip].iter().map(Authenticator::ip_key)) {
+                conn.del_key(&key).await;
+            }
+
+            for _ in 0..2 {
+                auth.record_failure(&mut conn, "auth_test_a", &ip).await;
+            }
+            assert!(!auth.sn_blocked(&mut conn, "auth_test_a").await);
+            // 同一ip换sn继续尝试，ip先被封禁
+            auth.record_failure(&mut conn, "auth_test_b", &ip).await;
+            assert!(auth.ip_blocked(&mut conn, &ip).await);
+            assert!(!auth.sn_blocked(&mut conn, "auth_test_b").await);
+            // 同一sn换ip继续尝试，sn被封禁，其他节点同样可见
+            auth.record_failure(&mut conn, "auth_test_a", &other_ip).await;
+            let other_node = Authenticator::new(Some(b"master".to_vec()), 3, 60);
+            assert!(other_node.sn_blocked(&mut conn, "auth_test_a").await);
+            assert_eq!(auth.total_failures(), 4);
+
+            auth.record_success(&mut conn, "auth_test_a").await;
+            assert!(!auth.sn_blocked(&mut conn, "auth_test_a").await);
+            assert!(auth.ip_blocked(&mut conn, &ip).await);
+        });
+    }
+}
@@ -0,0 +1,638 @@
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use bytes::{Buf, BufMut, BytesMut};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use serde_json::Value;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::middleware_wrapper::json_wrapper::{CsodMessage, Encoding};
+use crate::perception_service::secure::{FrameCipher, SEAL_OVERHEAD};
+
+/// 未配置时单帧的最大字节数(不含帧尾`\n`或二进制帧头)，建立安全层时为解密后的字节数
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 4096;
+
+/// 二进制帧头: 1字节标志 + 2字节大端长度，二进制帧最大65535字节
+const BINARY_HEADER_LEN: usize = 3;
+/// 帧内容经过deflate(raw, RFC 1951)压缩，长度为压缩后的长度
+const FLAG_DEFLATE: u8 = 0x01;
+/// 小于该长度的下行帧不压缩
+const DEFLATE_MIN_LEN: usize = 64;
+
+/// 解码得到的一帧
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    Line(String),
+    /// 超出最大长度的帧，值为超限时已缓存的字节数，该帧剩余部分直到`\n`都被丢弃
+    Oversize(usize),
+    /// 不是合法utf-8的帧，已丢弃
+    InvalidUtf8,
+    /// 无法解压或解码的二进制帧，已丢弃
+    InvalidPayload,
+    /// 解密失败或序号重复的加密帧，已丢弃
+    DecryptFailed,
+}
+
+/// 所有连接共享的分帧统计
+#[derive(Default)]
+pub struct FrameStats {
+    frames_in: AtomicU64,
+    bytes_in: AtomicU64,
+    frames_out: AtomicU64,
+    bytes_out: AtomicU64,
+    oversize: AtomicU64,
+    invalid_utf8: AtomicU64,
+    invalid_payload: AtomicU64,
+    decrypt_failed: AtomicU64,
+}
+
+/// `FrameStats`某一时刻的值
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct FrameStatsSnapshot {
+    pub frames_in: u64,
+    pub bytes_in: u64,
+    pub frames_out: u64,
+    pub bytes_out: u64,
+    pub oversize: u64,
+    pub invalid_utf8: u64,
+    pub invalid_payload: u64,
+    pub decrypt_failed: u64,
+}
+
+impl FrameStats {
+    pub fn new() -> FrameStats {
+        FrameStats::default()
+    }
+
+    pub fn snapshot(&self) -> FrameStatsSnapshot {
+        FrameStatsSnapshot {
+            frames_in: self.frames_in.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            frames_out: self.frames_out.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            oversize: self.oversize.load(Ordering::Relaxed),
+            invalid_utf8: self.invalid_utf8.load(Ordering::Relaxed),
+            invalid_payload: self.invalid_payload.load(Ordering::Relaxed),
+            decrypt_failed: self.decrypt_failed.load(Ordering::Relaxed),
+        }
+    }
+
+    fn add(counter: &AtomicU64, n: usize) {
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+/// CSoD传输层分帧: 默认以`\n`分隔，单帧长度有上限，超长帧与非utf-8帧丢弃后上报，不会断开连接
+///
+/// 握手协商二进制编码后切换为长度前缀的二进制帧，收到的帧在这里转为JSON文本，
+/// 下发的消息在这里编码，连接之外(redis、http服务)只看到JSON
+///
+/// 建立安全层后，帧内容在分帧之后、解码之前解密，文本帧的密文以base64表示
+///
+/// 读写两个方向各用一个实例，共享同一个`FrameStats`
+pub struct CsodCodec {
+    max_frame_size: usize,
+    encoding: Encoding,
+    /// 下发的二进制帧是否压缩，收到的帧按帧头标志解压
+    deflate: bool,
+    /// 下次从该位置开始查找`\n`，避免重复扫描
+    next_index: usize,
+    /// 正在丢弃超长帧的剩余部分
+    discarding: bool,
+    /// 二进制超长帧还需丢弃的字节数
+    skip: usize,
+    /// 安全层，读方向解密、写方向加密
+    cipher: Option<FrameCipher>,
+    stats: Arc<FrameStats>,
+}
+
+impl CsodCodec {
+    pub fn new(max_frame_size: usize, stats: Arc<FrameStats>) -> CsodCodec {
+        CsodCodec {
+            max_frame_size: max_frame_size.max(1),
+            encoding: Encoding::Json,
+            deflate: false,
+            next_index: 0,
+            discarding: false,
+            skip: 0,
+            cipher: None,
+            stats,
+        }
+    }
+
+    /// 切换帧编码，握手完成后调用，之前已解码的帧不受影响
+    pub fn set_encoding(&mut self, encoding: Encoding, deflate: bool) {
+        self.encoding = encoding;
+        self.deflate = deflate && encoding.is_binary();
+    }
+
+    /// 启用安全层，握手完成后调用
+    pub fn set_cipher(&mut self, cipher: FrameCipher) {
+        self.cipher = Some(cipher);
+    }
+
+    fn binary_max_frame_size(&self) -> usize {
+        self.max_frame_size.min(u16::MAX as usize)
+    }
+
+    /// 文本帧在线上的最大字节数，加密帧为明文上限加上序号、tag后按base64扩展的长度
+    fn wire_max_frame_size(&self) -> usize {
+        match self.cipher {
+            Some(_) => (self.max_frame_size + SEAL_OVERHEAD).div_ceil(3) * 4,
+            None => self.max_frame_size,
+        }
+    }
+
+    /// 二进制帧在线上的最大字节数，受帧头长度字段限制
+    fn binary_wire_max_frame_size(&self) -> usize {
+        match self.cipher {
+            Some(_) => (self.binary_max_frame_size() + SEAL_OVERHEAD).min(u16::MAX as usize),
+            None => self.binary_max_frame_size(),
+        }
+    }
+
+    fn take_line(&mut self, buf: &mut BytesMut, end: usize) -> Option<Frame> {
+        let line = buf.split_to(end + 1);
+        FrameStats::add(&self.stats.bytes_in, line.len());
+        let mut content = &line[..end];
+        if content.last() == Some(&b'\r') {
+            content = &content[..content.len() - 1];
+        }
+        // 空行不算一帧
+        if content.iter().all(|b| b.is_ascii_whitespace()) {
+            return None;
+        }
+        if content.len() > self.wire_max_frame_size() {
+            FrameStats::add(&self.stats.oversize, 1);
+            return Some(Frame::Oversize(content.len()));
+        }
+        let plain;
+        if let Some(cipher) = self.cipher.as_mut() {
+            match base64::decode(content).ok().and_then(|sealed| cipher.open(&sealed).ok()) {
+                Some(data) => plain = data,
+                None => {
+                    FrameStats::add(&self.stats.decrypt_failed, 1);
+                    return Some(Frame::DecryptFailed);
+                }
+            }
+            // 最大帧长限制的是明文
+            if plain.len() > self.max_frame_size {
+                FrameStats::add(&self.stats.oversize, 1);
+                return Some(Frame::Oversize(plain.len()));
+            }
+            content = &plain[..];
+        }
+        match std::str::from_utf8(content) {
+            Ok(s) => {
+                FrameStats::add(&self.stats.frames_in, 1);
+                Some(Frame::Line(s.to_string()))
+            }
+            Err(_) => {
+                FrameStats::add(&self.stats.invalid_utf8, 1);
+                Some(Frame::InvalidUtf8)
+            }
+        }
+    }
+
+    /// 二进制帧内容转为JSON文本帧
+    fn transcode(&mut self, flags: u8, payload: &[u8]) -> Frame {
+        if flags & !FLAG_DEFLATE != 0 {
+            return Frame::InvalidPayload;
+        }
+        let plain;
+        let payload = match self.cipher.as_mut() {
+            Some(cipher) => match cipher.open(payload) {
+                Ok(data) if data.len() > self.binary_max_frame_size() => return Frame::Oversize(data.len()),
+                Ok(data) => {
+                    plain = data;
+                    &plain[..]
+                }
+                Err(_) => return Frame::DecryptFailed,
+            },
+            None => payload,
+        };
+        let inflated;
+        let payload = if flags & FLAG_DEFLATE != 0 {
+            // 限制解压后的长度，防止压缩炸弹
+            let mut out = Vec::new();
+            let limit = self.binary_max_frame_size() as u64 + 1;
+            if DeflateDecoder::new(payload).take(limit).read_to_end(&mut out).is_err() {
+                return Frame::InvalidPayload;
+            }
+            if out.len() > self.binary_max_frame_size() {
+                return Frame::Oversize(out.len());
+            }
+            inflated = out;
+            &inflated[..]
+        } else {
+            payload
+        };
+        let value = match self.encoding {
+            Encoding::Cbor => serde_cbor::from_slice::<Value>(payload).ok(),
+            Encoding::MsgPack => rmp_serde::from_slice::<Value>(payload).ok(),
+            Encoding::Json => std::str::from_utf8(payload).ok().and_then(|s| serde_json::from_str(s).ok()),
+        };
+        match value {
+            Some(value) => Frame::Line(value.to_string()),
+            None => Frame::InvalidPayload,
+        }
+    }
+
+    fn decode_binary(&mut self, buf: &mut BytesMut) -> Option<Frame> {
+        loop {
+            if self.skip > 0 {
+                let n = self.skip.min(buf.len());
+                buf.advance(n);
+                FrameStats::add(&self.stats.bytes_in, n);
+                self.skip -= n;
+                if self.skip > 0 {
+                    return None;
+                }
+            }
+            if buf.len() < BINARY_HEADER_LEN {
+                return None;
+            }
+            let flags = buf[0];
+            let len = u16::from_be_bytes([buf[1], buf[2]]) as usize;
+            if len > self.binary_wire_max_frame_size() {
+                // 长度已知，丢弃该帧内容后继续
+                buf.advance(BINARY_HEADER_LEN);
+                FrameStats::add(&self.stats.bytes_in, BINARY_HEADER_LEN);
+                FrameStats::add(&self.stats.oversize, 1);
+                self.skip = len;
+                return Some(Frame::Oversize(len));
+            }
+            if buf.len() < BINARY_HEADER_LEN + len {
+                buf.reserve(BINARY_HEADER_LEN + len - buf.len());
+                return None;
+            }
+            let data = buf.split_to(BINARY_HEADER_LEN + len);
+            FrameStats::add(&self.stats.bytes_in, data.len());
+            // 空帧不算一帧
+            if len == 0 {
+                continue;
+            }
+            let frame = self.transcode(flags, &data[BINARY_HEADER_LEN..]);
+            match frame {
+                Frame::Oversize(_) => FrameStats::add(&self.stats.oversize, 1),
+                Frame::InvalidPayload => FrameStats::add(&self.stats.invalid_payload, 1),
+                Frame::DecryptFailed => FrameStats::add(&self.stats.decrypt_failed, 1),
+                _ => FrameStats::add(&self.stats.frames_in, 1),
+            }
+            return Some(frame);
+        }
+    }
+
+    fn encode_binary(&mut self, msg: &CsodMessage, dst: &mut BytesMut) -> Result<(), io::Error> {
+        let value = serde_json::to_value(msg).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut payload = match self.encoding {
+            Encoding::Cbor => serde_cbor::to_vec(&value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Encoding::MsgPack => rmp_serde::to_vec(&value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Encoding::Json => value.to_string().into_bytes(),
+        };
+        let mut flags = 0;
+        if self.deflate && payload.len() >= DEFLATE_MIN_LEN {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&payload)?;
+            let compressed = encoder.finish()?;
+            // 压缩后没有变小则不压缩
+            if compressed.len() < payload.len() {
+                payload = compressed;
+                flags |= FLAG_DEFLATE;
+            }
+        }
+        if payload.len() > self.binary_max_frame_size() {
+            FrameStats::add(&self.stats.oversize, 1);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame size {} exceeds {}", payload.len(), self.binary_max_frame_size()),
+            ));
+        }
+        if let Some(cipher) = self.cipher.as_mut() {
+            payload = cipher.seal(&payload);
+            if payload.len() > self.binary_wire_max_frame_size() {
+                FrameStats::add(&self.stats.oversize, 1);
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("sealed frame size {} exceeds {}", payload.len(), self.binary_wire_max_frame_size()),
+                ));
+            }
+        }
+        dst.reserve(BINARY_HEADER_LEN + payload.len());
+        dst.put_u8(flags);
+        dst.put_u16(payload.len() as u16);
+        dst.put_slice(&payload);
+        FrameStats::add(&self.stats.frames_out, 1);
+        FrameStats::add(&self.stats.bytes_out, BINARY_HEADER_LEN + payload.len());
+        Ok(())
+    }
+}
+
+impl Decoder for CsodCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Frame>, io::Error> {
+        if self.encoding.is_binary() {
+            return Ok(self.decode_binary(buf));
+        }
+        loop {
+            let newline = buf[self.next_index..].iter().position(|b| *b == b'\n');
+            match (self.discarding, newline) {
+                (true, Some(offset)) => {
+                    let discarded = buf.split_to(self.next_index + offset + 1);
+                    FrameStats::add(&self.stats.bytes_in, discarded.len());
+                    self.discarding = false;
+                    self.next_index = 0;
+                }
+                (true, None) => {
+                    FrameStats::add(&self.stats.bytes_in, buf.len());
+                    buf.clear();
+                    self.next_index = 0;
+                    return Ok(None);
+                }
+                (false, Some(offset)) => {
+                    let end = self.next_index + offset;
+                    self.next_index = 0;
+                    if let Some(frame) = self.take_line(buf, end) {
+                        return Ok(Some(frame));
+                    }
+                }
+                (false, None) if buf.len() > self.wire_max_frame_size() + 1 => {
+                    // 还没收到帧尾就已超长，立即上报，之后丢弃到帧尾
+                    let len = buf.len();
+                    FrameStats::add(&self.stats.bytes_in, len);
+                    FrameStats::add(&self.stats.oversize, 1);
+                    buf.clear();
+                    self.next_index = 0;
+                    self.discarding = true;
+                    return Ok(Some(Frame::Oversize(len)));
+                }
+                (false, None) => {
+                    self.next_index = buf.len();
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Frame>, io::Error> {
+        if let Some(frame) = self.decode(buf)? {
+            return Ok(Some(frame));
+        }
+        // 连接关闭时没有帧尾的最后一帧，不完整的二进制帧直接丢弃
+        if buf.is_empty() || self.discarding || self.encoding.is_binary() {
+            buf.clear();
+            return Ok(None);
+        }
+        buf.put_u8(b'\n');
+        let end = buf.len() - 1;
+        self.next_index = 0;
+        Ok(self.take_line(buf, end))
+    }
+}
+
+impl Encoder<&CsodMessage> for CsodCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: &CsodMessage, dst: &mut BytesMut) -> Result<(), io::Error> {
+        if self.encoding.is_binary() {
+            return self.encode_binary(msg, dst);
+        }
+        let mut line = msg.to_line();
+        if line.len() > self.max_frame_size {
+            FrameStats::add(&self.stats.oversize, 1);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame size {} exceeds {}", line.len(), self.max_frame_size),
+            ));
+        }
+        if let Some(cipher) = self.cipher.as_mut() {
+            line = base64::encode(cipher.seal(line.as_bytes()));
+        }
+        dst.reserve(line.len() + 1);
+        dst.put_slice(line.as_bytes());
+        dst.put_u8(b'\n');
+        FrameStats::add(&self.stats.frames_out, 1);
+        FrameStats::add(&self.stats.bytes_out, line.len() + 1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod codec_test {
+    use std::sync::Arc;
+
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::middleware_wrapper::json_wrapper::{CsodMessage, Encoding, Version};
+    use crate::perception_service::secure::SecureSession;
+
+    use super::{CsodCodec, Frame, FrameStats};
+
+    fn decode_all(codec: &mut CsodCodec, buf: &mut BytesMut) -> Vec<Frame> {
+        let mut frames = vec![];
+        while let Some(frame) = codec.decode(buf).unwrap() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn test_decode_lines() {
+        let stats = Arc::new(FrameStats::new());
+        let mut codec = CsodCodec::new(16, stats.clone());
+        let mut buf = BytesMut::from(&b"{\"a\":1}\r\n\n{\"b\""[..]);
+        assert_eq!(decode_all(&mut codec, &mut buf), vec![Frame::Line("{\"a\":1}".to_string())]);
+        // 半帧等待后续数据
+        buf.extend_from_slice(b":2}\n");
+        assert_eq!(decode_all(&mut codec, &mut buf), vec![Frame::Line("{\"b\":2}".to_string())]);
+        assert!(buf.is_empty());
+        assert_eq!(stats.snapshot().frames_in, 2);
+    }
+
+    #[test]
+    fn test_oversize_and_utf8() {
+        let stats = Arc::new(FrameStats::new());
+        let mut codec = CsodCodec::new(8, stats.clone());
+
+        // 一直不发帧尾，超限后立即上报并丢弃，缓存不增长
+        let mut buf = BytesMut::from(&b"0123456789abcdef"[..]);
+        assert_eq!(decode_all(&mut codec, &mut buf), vec![Frame::Oversize(16)]);
+        assert!(buf.is_empty());
+        buf.extend_from_slice(b"more garbage");
+        assert!(decode_all(&mut codec, &mut buf).is_empty());
+        assert!(buf.is_empty());
+        // 帧尾之后恢复正常
+        buf.extend_from_slice(b"tail\nok\n");
+        assert_eq!(decode_all(&mut codec, &mut buf), vec![Frame::Line("ok".to_string())]);
+
+        // 一次收到完整的超长帧
+        buf.extend_from_slice(b"123456789\nok\n");
+        assert_eq!(decode_all(&mut codec, &mut buf), vec![Frame::Oversize(9), Frame::Line("ok".to_string())]);
+
+        buf.extend_from_slice(b"\xff\xfe\nok\n");
+        assert_eq!(decode_all(&mut codec, &mut buf), vec![Frame::InvalidUtf8, Frame::Line("ok".to_string())]);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.oversize, 2);
+        assert_eq!(snapshot.invalid_utf8, 1);
+        // 丢弃的帧不计入收到的帧数
+        assert_eq!(snapshot.frames_in, 3);
+    }
+
+    #[test]
+    fn test_decode_eof_and_encode() {
+        let stats = Arc::new(FrameStats::new());
+        let mut codec = CsodCodec::new(64, stats.clone());
+        let mut buf = BytesMut::from(&b"last"[..]);
+        assert_eq!(codec.decode_eof(&mut buf).unwrap(), Some(Frame::Line("last".to_string())));
+        assert_eq!(codec.decode_eof(&mut buf).unwrap(), None);
+
+        let mut out = BytesMut::new();
+        codec.encode(&CsodMessage::pong(Version(0)), &mut out).unwrap();
+        assert_eq!(&out[..], &b"{\"type\":\"pong\",\"v\":\"0\"}\n"[..]);
+        let mut small = CsodCodec::new(8, stats.clone());
+        assert!(small.encode(&CsodMessage::pong(Version(0)), &mut out).is_err());
+        assert_eq!(stats.snapshot().frames_out, 1);
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let set = CsodMessage::parse(r#"{"v":"1","type":"set","sn":"123","id":"1a2b","010":"01","0a1":"000a0a000a0a000a0a000a0a000a0a000a0a000a0a000a0a"}"#).unwrap();
+        for &(encoding, deflate) in [(Encoding::Cbor, false), (Encoding::MsgPack, false), (Encoding::Cbor, true)].iter() {
+            let stats = Arc::new(FrameStats::new());
+            let mut writer = CsodCodec::new(256, stats.clone());
+            let mut reader = CsodCodec::new(256, stats.clone());
+            writer.set_encoding(encoding, deflate);
+            reader.set_encoding(encoding, deflate);
+
+            let mut buf = BytesMut::new();
+            writer.encode(&set, &mut buf).unwrap();
+            // 足够长的帧才压缩
+            assert_eq!(buf[0] == super::FLAG_DEFLATE, deflate);
+            writer.encode(&CsodMessage::pong(Version(1)), &mut buf).unwrap();
+            // 二进制帧比json短
+            assert!(buf.len() < set.to_line().len() + CsodMessage::pong(Version(1)).to_line().len());
+            // 半帧等待后续数据
+            let mut partial = buf.split_to(4);
+            assert_eq!(reader.decode(&mut partial).unwrap(), None);
+            partial.unsplit(buf);
+            match decode_all(&mut reader, &mut partial).as_slice() {
+                [Frame::Line(a), Frame::Line(b)] => {
+                    assert_eq!(CsodMessage::parse(a).unwrap(), set);
+                    assert_eq!(CsodMessage::parse(b).unwrap(), CsodMessage::pong(Version(1)));
+                }
+                other => panic!("unexpected frames {:?}", other),
+            }
+            assert_eq!(stats.snapshot().frames_in, 2);
+        }
+    }
+
+    #[test]
+    fn test_binary_invalid() {
+        let stats = Arc::new(FrameStats::new());
+        let mut reader = CsodCodec::new(8, stats.clone());
+        reader.set_encoding(Encoding::MsgPack, false);
+        let mut writer = CsodCodec::new(64, stats.clone());
+        writer.set_encoding(Encoding::MsgPack, false);
+        let mut pong = BytesMut::new();
+        writer.encode(&CsodMessage::pong(Version(1)), &mut pong).unwrap();
+
+        // 超长帧按长度跳过，之后的帧不受影响
+        let mut buf = BytesMut::from(&[0u8, 0, 10][..]);
+        buf.extend_from_slice(&[0xaa; 6]);
+        assert_eq!(decode_all(&mut reader, &mut buf), vec![Frame::Oversize(10)]);
+        buf.extend_from_slice(&[0xaa; 4]);
+        // 未知标志、无法解码、声明压缩但不是deflate数据
+        buf.extend_from_slice(&[0x80, 0, 1, 0x90, 0, 0, 1, 0xc1, 1, 0, 2, 0xff, 0xff]);
+        assert_eq!(
+            decode_all(&mut reader, &mut buf),
+            vec![Frame::InvalidPayload, Frame::InvalidPayload, Frame::InvalidPayload]
+        );
+        assert!(buf.is_empty());
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.oversize, 1);
+        assert_eq!(snapshot.invalid_payload, 3);
+        assert_eq!(snapshot.frames_in, 0);
+        assert_eq!(snapshot.frames_out, 1);
+    }
+
+    #[test]
+    fn test_secure_frames() {
+        let pong = CsodMessage::pong(Version(1));
+        for &encoding in [Encoding::Json, Encoding::Cbor].iter() {
+            let stats = Arc::new(FrameStats::new());
+            // 服务端用下行密钥加密，设备端用同一会话的下行密钥解密
+            let server = SecureSession::derive(b"device key", &[1; 16], &[2; 16]);
+            let device = SecureSession::derive(b"device key", &[1; 16], &[2; 16]);
+            let mut writer = CsodCodec::new(256, stats.clone());
+            let mut reader = CsodCodec::new(256, stats.clone());
+            writer.set_encoding(encoding, false);
+            reader.set_encoding(encoding, false);
+            writer.set_cipher(server.down);
+            reader.set_cipher(device.down);
+
+            let mut buf = BytesMut::new();
+            writer.encode(&pong, &mut buf).unwrap();
+            // 密文中看不到明文
+            assert!(!buf.windows(4).any(|w| w == b"pong"));
+            let replay = buf.clone();
+            match decode_all(&mut reader, &mut buf).as_slice() {
+                [Frame::Line(line)] => assert_eq!(CsodMessage::parse(line).unwrap(), pong),
+                other => panic!("unexpected frames {:?}", other),
+            }
+            // 重放的帧被丢弃
+            let mut replay = replay;
+            assert_eq!(decode_all(&mut reader, &mut replay), vec![Frame::DecryptFailed]);
+            assert_eq!(stats.snapshot().decrypt_failed, 1);
+        }
+    }
+
+    #[test]
+    fn test_secure_frame_size() {
+        let pong = CsodMessage::pong(Version(1));
+        let max = pong.to_line().len();
+        for &encoding in [Encoding::Json, Encoding::Cbor].iter() {
+            let stats = Arc::new(FrameStats::new());
+            let server = SecureSession::derive(b"device key", &[1; 16], &[2; 16]);
+            let device = SecureSession::derive(b"device key", &[1; 16], &[2; 16]);
+            let mut writer = CsodCodec::new(max, stats.clone());
+            let mut reader = CsodCodec::new(max, stats.clone());
+            writer.set_encoding(encoding, false);
+            reader.set_encoding(encoding, false);
+            writer.set_cipher(device.up);
+            reader.set_cipher(server.up);
+
+            // 明文不超过最大帧长即可，密文长度不受限
+            let mut buf = BytesMut::new();
+            writer.encode(&pong, &mut buf).unwrap();
+            assert!(buf.len() > max + 1);
+            match decode_all(&mut reader, &mut buf).as_slice() {
+                [Frame::Line(line)] => assert_eq!(CsodMessage::parse(line).unwrap(), pong),
+                other => panic!("unexpected frames {:?}", other),
+            }
+            assert_eq!(stats.snapshot().frames_in, 1);
+
+            // 解密后超过最大帧长，二进制帧由长度字段即可判断
+            let mut device = SecureSession::derive(b"device key", &[1; 16], &[2; 16]).up;
+            device.seal(b"skip the frame sent above");
+            let sealed = device.seal(&vec![b' '; max + 1]);
+            let mut buf = BytesMut::new();
+            if encoding.is_binary() {
+                buf.extend_from_slice(&[0, 0, sealed.len() as u8]);
+                buf.extend_from_slice(&sealed);
+            } else {
+                buf.extend_from_slice(base64::encode(&sealed).as_bytes());
+                buf.extend_from_slice(b"\n");
+            }
+            let oversize = if encoding.is_binary() { sealed.len() } else { max + 1 };
+            assert_eq!(decode_all(&mut reader, &mut buf), vec![Frame::Oversize(oversize)]);
+            let snapshot = stats.snapshot();
+            assert_eq!(snapshot.oversize, 1);
+            assert_eq!(snapshot.frames_in, 1);
+        }
+    }
+}
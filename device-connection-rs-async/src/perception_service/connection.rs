@@ -1,34 +1,56 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Instant;
+
+use futures::SinkExt;
 #[allow(dead_code)]
 use log::{error, info, warn};
 use tokio::{
-    io::BufReader,
-    io::BufWriter,
-    net::tcp::ReadHalf,
-    net::tcp::WriteHalf,
+    io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf},
     net::TcpListener,
-    net::TcpStream,
-    prelude::*,
     runtime,
+    signal::unix::{signal, SignalKind},
     stream::StreamExt,
     time::Duration,
     time::timeout,
 };
+use tokio_rustls::TlsAcceptor;
+use tokio_util::codec::{FramedRead, FramedWrite};
 
+use csod::registry;
 use tcp_err::ServerError;
 
 use crate::common::config::PerceptionServiceConfig as PerceptCfg;
 use crate::common::config::RedisConfig as RedisCfg;
 use crate::middleware_wrapper::json_wrapper::{
-    is_heartbeat,
-    MESTYPE,
-    parse_msg_type,
+    CsodMessage,
+    Encoding,
+    ErrorCode,
+    MIN_VERSION,
+    parse_frame,
+    Version,
 };
+use crate::middleware_wrapper::redis_wrapper::{RedisConn, RedisPool};
+use crate::perception_service::auth::Authenticator;
+use crate::perception_service::codec::{CsodCodec, DEFAULT_MAX_FRAME_SIZE, Frame, FrameStats};
+use crate::perception_service::downlink_notify::DownlinkNotifier;
 use crate::perception_service::map2redis;
+use crate::perception_service::node::Node;
+use crate::perception_service::rate_limit::RateLimiter;
+use crate::perception_service::secure::{PskPolicy, SecureSession};
+use crate::perception_service::session::{Evict, SessionRegistry};
+use crate::perception_service::sn_verifier::SnVerifier;
+use crate::perception_service::sweeper::Sweeper;
+use crate::perception_service::tls;
 
 use super::device;
 
 //use std::net::Shutdown;
 
+/// 未配置时服务关闭等待设备离线的期限
+const DEFAULT_SHUTDOWN_DEADLINE_SECS: u64 = 10;
+
 #[allow(non_camel_case_types)]
 mod tcp_err {
     #[derive(Debug)]
@@ -38,73 +60,159 @@ mod tcp_err {
     }
 }
 
+/// 设备连接，明文TCP或TLS
+trait DeviceStream: AsyncRead + AsyncWrite + Unpin + Send {}
 
-async fn readline<'a>(stream: &'a mut BufReader<ReadHalf<'_>>) -> Result<String, ServerError> {
-    let mut line = String::new();
-    match stream.read_line(&mut line).await {
-        Ok(n) => {
-            if n > 0 {
-                Ok(line)
-            } else {
-                Err(ServerError::Broken)
-            }
-        }
-        Err(e) => {
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> DeviceStream for T {}
+
+/// 连接的来源
+struct Peer {
+    ip: IpAddr,
+    /// mTLS时设备证书的CN
+    cn: Option<String>,
+}
+
+/// 所有连接共享的服务状态
+struct Context {
+    cfg: Arc<PerceptCfg>,
+    redis_pool: Arc<RedisPool>,
+    verifier: Arc<SnVerifier>,
+    psk: PskPolicy,
+    auth: Arc<Authenticator>,
+    notifier: Arc<DownlinkNotifier>,
+    sessions: Arc<SessionRegistry>,
+    frame_stats: Arc<FrameStats>,
+    node: Arc<Node>,
+    /// 配置了证书时使用TLS
+    tls_acceptor: Option<TlsAcceptor>,
+}
+
+type FrameReader = FramedRead<ReadHalf<Box<dyn DeviceStream>>, CsodCodec>;
+type FrameWriter = FramedWrite<WriteHalf<Box<dyn DeviceStream>>, CsodCodec>;
+
+async fn readline(stream: &mut FrameReader) -> Result<Frame, ServerError> {
+    match stream.next().await {
+        Some(Ok(frame)) => Ok(frame),
+        Some(Err(e)) => {
             warn!("connection error: {}", e);
             Err(ServerError::Broken)
         }
+        None => Err(ServerError::Broken),
     }
 }
 
-fn check_sn(sn: &str) -> bool {
-    match sn.len() {
-        13 => {
-            true
-        }
-        _ => {
-            false
-        }
-    }
+/// 握手协商的结果
+struct Handshake {
+    sn: String,
+    version: Version,
+    /// 设备是否请求过帧编码，未请求时pong保持原样
+    enc_requested: bool,
+    encoding: Encoding,
+    deflate: bool,
+    /// 建立了安全层时，pong之后的帧均加密
+    secure: Option<SecureSession>,
 }
 
-/// 握手成功返回sn
-async fn handshake<'a>(reader: &'a mut BufReader<ReadHalf<'_>>) -> Result<String, ()> {
-    let pinsn: String;
+/// 握手成功返回sn与协商的协议版本、帧编码，失败返回需要告知设备的错误
+///
+/// 启用mTLS时`peer_cn`为设备证书的CN，必须与ping中的sn一致；
+/// 配置`registered_only`时在`redis_conn`中查询出厂登记，未登记的sn在加入`csod/devices_born`之前被拒绝
+async fn handshake(reader: &mut FrameReader, verifier: &SnVerifier, psk: &PskPolicy, cfg: &PerceptCfg, peer_cn: Option<&str>, redis_conn: &mut RedisConn) -> Result<Handshake, (ErrorCode, String)> {
+    let min_version = Version(cfg.min_version.unwrap_or(MIN_VERSION.0));
     for _ in 0..4 {
-        if let Ok(msg) = readline(reader).await {
-            if let Some(sn) = is_heartbeat(&msg) {
-                info!("sn {}", sn);
-                pinsn = sn.clone().trim().to_string();
-                if check_sn(&pinsn) {
-                    return Ok(pinsn);
-                } else {
-                    error!("invalid sn {}", pinsn);
-                    return Err(());
+        if let Ok(Frame::Line(msg)) = readline(reader).await {
+            if let Some(CsodMessage::Ping { v, sn, enc, deflate, psk_nonce }) = parse_frame(&msg) {
+                info!("sn {}, version {}", sn, v);
+                // sn统一为大写，出厂登记、设备密钥派生与之后所有redis记录都使用大写的sn
+                let sn = sn.as_str().to_uppercase();
+                let version = match Version::negotiate(v, min_version) {
+                    Ok(version) => version,
+                    Err(reason) => {
+                        error!("unsupported version {} from sn {}", v, sn);
+                        return Err((ErrorCode::UnsupportedVersion, reason));
+                    }
+                };
+                if let Err(e) = verifier.verify(&sn) {
+                    error!("invalid sn {}", sn);
+                    return Err((ErrorCode::SnRejected, e.to_string()));
                 }
+                if cfg.registered_only.unwrap_or(false) {
+                    match redis_conn.exists(&registry::registry_key(&sn)).await {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            error!("sn {} not registered", sn);
+                            return Err((ErrorCode::SnRejected, "sn not registered".to_string()));
+                        }
+                        Err(_) => {
+                            error!("query registry of sn {} failed", sn);
+                            return Err((ErrorCode::SnRejected, "registry unavailable".to_string()));
+                        }
+                    }
+                }
+                if cfg.tls_client_ca.is_some() && peer_cn.map(str::to_uppercase).as_deref() != Some(sn.as_str()) {
+                    error!("certificate CN {:?} does not match sn {}", peer_cn, sn);
+                    return Err((ErrorCode::SnRejected, "certificate CN does not match sn".to_string()));
+                }
+                let secure = match psk_nonce {
+                    Some(nonce) => psk.accept(&sn, &nonce).map_err(|e| (ErrorCode::MalformedMessage, e))?,
+                    None => None,
+                };
+                if secure.is_none() && psk.requires(&sn) {
+                    error!("sn {} must use the security layer", sn);
+                    return Err((ErrorCode::EncryptionRequired, "psk_nonce required".to_string()));
+                }
+                let allowed = cfg.encodings.clone().unwrap_or_default();
+                let (encoding, deflate) = Encoding::negotiate(enc, deflate, &allowed, cfg.allow_deflate.unwrap_or(false));
+                return Ok(Handshake {
+                    sn,
+                    version,
+                    enc_requested: enc.is_some(),
+                    encoding,
+                    deflate,
+                    secure,
+                });
             }
         }
         tokio::time::delay_for(Duration::from_millis(100)).await;
     }
     error!("handshake failed");
-    Err(())
+    Err((ErrorCode::MalformedMessage, "expect ping".to_string()))
+}
+
+/// 下一帧，优先返回激活前已读取的帧
+///
+/// 只在poll时取出`pending`且立即就绪，在`select!`中未被选中时不会丢失
+async fn next_frame(pending: &mut Option<Frame>, reader: &mut FrameReader) -> Result<Frame, ServerError> {
+    match pending.take() {
+        Some(frame) => Ok(frame),
+        None => readline(reader).await,
+    }
 }
 
-async fn writeline<'a>(
-    stream: &'a mut BufWriter<WriteHalf<'_>>,
-    line: String,
-) -> Result<usize, ServerError> {
-    let mut byte_line: Vec<u8> = line.trim().as_bytes().to_vec();
-    if byte_line.len() == 0 {
-        return Err(ServerError::INVALID_DATA);
+/// 等待设备对challenge的应答，收到的第一帧必须是auth，`sn`为握手时转为大写的sn
+async fn authenticate(reader: &mut FrameReader, auth: &Authenticator, sn: &str, challenge: &str) -> bool {
+    match readline(reader).await {
+        Ok(Frame::Line(msg)) => match parse_frame(&msg) {
+            Some(CsodMessage::Auth { sn: auth_sn, mac, .. }) => auth_sn.as_str().eq_ignore_ascii_case(sn) && auth.verify(sn, challenge, &mac),
+            _ => false,
+        },
+        _ => false,
     }
-    byte_line.push('\n' as u8);
-    match stream.write_all(&mut byte_line).await {
+}
+
+/// 超出最大帧长的消息不发送，连接不受影响
+async fn writeline(
+    stream: &mut FrameWriter,
+    msg: &CsodMessage,
+) -> Result<(), ServerError> {
+    match stream.send(msg).await {
         Ok(_) => {
-            if stream.flush().await.is_err() {
-                warn!("flush with writeline tcp fail")
-            }
-            info!("write msg -> {:?}", line);
-            Ok(byte_line.len())
+            info!("write msg -> {:?}", msg);
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            warn!("drop msg: {}", e);
+            Err(ServerError::INVALID_DATA)
         }
         Err(_) => {
             info!("write broken");
@@ -113,117 +221,378 @@ async fn writeline<'a>(
     }
 }
 
-async fn echo_pong<'a>(stream: &'a mut BufWriter<WriteHalf<'_>>) -> Result<usize, ServerError> {
-    let pong_msg = "{\"type\":\"pong\"}".to_string();
-    writeline(stream, pong_msg).await
+/// pong中带上协商的协议版本，设备之后按该版本通信
+async fn echo_pong(stream: &mut FrameWriter, version: Version) -> Result<(), ServerError> {
+    writeline(stream, &CsodMessage::pong(version)).await
+}
+
+/// 版本协商之前握手失败时告知设备原因，随后断开连接，不区分版本；协商之后使用`echo_error`
+async fn reject(stream: &mut FrameWriter, code: ErrorCode, msg: &str) -> Result<(), ServerError> {
+    writeline(stream, &CsodMessage::error(MIN_VERSION, code, msg)).await
+}
+
+/// 连接建立后要发给设备的error帧，v0固件不认识error帧，返回None，见CSoD.md错误帧说明
+fn error_frame(version: Version, code: ErrorCode, msg: &str) -> Option<CsodMessage> {
+    if version.supports_error_frame() {
+        Some(CsodMessage::error(version, code, msg))
+    } else {
+        None
+    }
+}
+
+/// 连接中向设备发送error帧，协商版本不支持error帧时只记录日志
+async fn echo_error(stream: &mut FrameWriter, version: Version, code: ErrorCode, msg: &str) -> Result<(), ServerError> {
+    match error_frame(version, code, msg) {
+        Some(frame) => writeline(stream, &frame).await,
+        None => {
+            info!("skip error frame for v{} device: {}({})", version, msg, code.code());
+            Ok(())
+        }
+    }
 }
 
 /// 连接处理Handler
-async fn handler(mut stream: TcpStream, hb_interval: u64, redis_cfg: RedisCfg) {
-    let (stream_read, stream_write) = stream.split();
-    let mut stream_reader: BufReader<ReadHalf<'_>> = BufReader::new(stream_read);
-    let mut stream_writer: BufWriter<WriteHalf<'_>> = BufWriter::new(stream_write);
+async fn handler(stream: Box<dyn DeviceStream>, peer: Peer, ctx: Arc<Context>) {
+    let Context { cfg, redis_pool, verifier, psk, auth, notifier, sessions, frame_stats, node, .. } = &*ctx;
+    let (stream_read, stream_write) = tokio::io::split(stream);
+    // 读写共用同一分帧规则，单帧长度有上限，避免设备一直不发帧尾导致缓存无限增长
+    let max_frame_size = cfg.max_frame_size.unwrap_or(DEFAULT_MAX_FRAME_SIZE);
+    let mut stream_reader: FrameReader = FramedRead::new(stream_read, CsodCodec::new(max_frame_size, frame_stats.clone()));
+    let mut stream_writer: FrameWriter = FramedWrite::new(stream_write, CsodCodec::new(max_frame_size, frame_stats.clone()));
+
+    // 认证失败次数过多的ip直接拒绝
+    if auth.enabled() && auth.ip_blocked(&mut redis_pool.get(), &peer.ip).await {
+        warn!("reject blocked ip {}", peer.ip);
+        let _ = reject(&mut stream_writer, ErrorCode::RateLimited, "too many auth failures").await;
+        return;
+    }
 
     // 等待新连接40s上报sn信息，超时退出(40s来自并发测试，当瞬间发起大量连接时，从os层面无法及时将这些数据上报到应用层)
-    let sn = if let Ok(Ok(v)) = timeout(Duration::from_millis(40000), handshake(&mut stream_reader)).await {
-        if let Ok(_) = echo_pong(&mut stream_writer).await {
-            info!("handshake ok, from device(sn {})", v);
-            v
-        } else {
-            error!("echo pong msg failed");
+    let (sn, version, challenge, secure) = match timeout(Duration::from_millis(40000), handshake(&mut stream_reader, verifier, psk, cfg, peer.cn.as_deref(), &mut redis_pool.get())).await {
+        Ok(Ok(hs)) => {
+            if auth.enabled() && auth.sn_blocked(&mut redis_pool.get(), &hs.sn).await {
+                warn!("reject blocked sn {} from {}", hs.sn, peer.ip);
+                let _ = echo_error(&mut stream_writer, hs.version, ErrorCode::RateLimited, "too many auth failures").await;
+                return;
+            }
+            // pong以明文json发送，之后双方按协商的编码与安全层收发
+            let mut pong = if hs.enc_requested {
+                CsodMessage::pong_with_encoding(hs.version, hs.encoding, hs.deflate)
+            } else {
+                CsodMessage::pong(hs.version)
+            };
+            let challenge = if auth.enabled() {
+                match auth.challenge() {
+                    Ok(challenge) => Some(challenge),
+                    Err(e) => {
+                        error!("{}", e);
+                        return;
+                    }
+                }
+            } else {
+                None
+            };
+            if let Some(challenge) = &challenge {
+                pong.set_challenge(challenge.clone());
+            }
+            let secure = hs.secure.is_some();
+            let down = match hs.secure {
+                Some(session) => {
+                    pong.set_psk_nonce(session.server_nonce);
+                    stream_reader.decoder_mut().set_cipher(session.up);
+                    Some(session.down)
+                }
+                None => None,
+            };
+            stream_reader.decoder_mut().set_encoding(hs.encoding, hs.deflate);
+            if writeline(&mut stream_writer, &pong).await.is_ok() {
+                stream_writer.encoder_mut().set_encoding(hs.encoding, hs.deflate);
+                if let Some(down) = down {
+                    stream_writer.encoder_mut().set_cipher(down);
+                }
+                info!("handshake ok, from device(sn {}), version {}, encoding {}, secure {}", hs.sn, hs.version, hs.encoding, secure);
+                (hs.sn, hs.version, challenge, secure)
+            } else {
+                error!("echo pong msg failed");
+                return;
+            }
+        }
+        Ok(Err((code, msg))) => {
+            error!("invalid sn connection: {}", code);
+            let _ = reject(&mut stream_writer, code, &msg).await;
+            return;
+        }
+        Err(_) => {
+            error!("handshake timeout");
             return;
         }
-    } else {
-        error!("invalid sn connection");
-        return;
     };
 
-    // 创建设备
+    // 设备应答challenge后才激活，应答错误或超时都算一次认证失败
+    let authed = challenge.is_some();
+    if let Some(challenge) = challenge {
+        let passed = matches!(
+            timeout(Duration::from_secs(10), authenticate(&mut stream_reader, auth, &sn, &challenge)).await,
+            Ok(true)
+        );
+        if !passed {
+            auth.record_failure(&mut redis_pool.get(), &sn, &peer.ip).await;
+            let _ = echo_error(&mut stream_writer, version, ErrorCode::AuthFailed, "auth failed").await;
+            return;
+        }
+        auth.record_success(&mut redis_pool.get(), &sn).await;
+        info!("auth ok: dev {}", sn);
+    }
+
+    // psk_nonce任何人都能构造，密钥在第一帧解密成功后才得到证明；在此之前不激活、不顶替已在线的连接。
+    // 通过challenge认证时auth帧已经解密成功，不再等待
+    let mut pending = None;
+    if secure && !authed {
+        match timeout(Duration::from_secs(cfg.heartbeat_interval.unwrap_or(120)), readline(&mut stream_reader)).await {
+            Ok(Ok(frame @ Frame::Line(_))) => pending = Some(frame),
+            Ok(Ok(_)) => {
+                warn!("first frame of dev {} from {} not decrypted, close", sn, peer.ip);
+                if auth.enabled() {
+                    auth.record_failure(&mut redis_pool.get(), &sn, &peer.ip).await;
+                }
+                let _ = echo_error(&mut stream_writer, version, ErrorCode::DecryptFailed, ErrorCode::DecryptFailed.reason()).await;
+                return;
+            }
+            _ => {
+                warn!("no frame from dev {} after secure handshake", sn);
+                return;
+            }
+        }
+    }
+
+    // 创建设备，如果没有配置心跳，默认120s
     let mut dev = device::Device::new(sn.clone());
-    dev.set_heartbeat_period(Duration::from_secs(hb_interval));
+    dev.set_heartbeat_period(Duration::from_secs(cfg.heartbeat_interval.unwrap_or(120)));
+    let mut limiter = cfg.max_frames_per_minute.map(RateLimiter::per_minute);
+    let mut rate_limited = false;
 
-    // 创建映射到redis的设备
-    let mut dev2redis: map2redis::Device2redis;
+    // 创建映射到redis的设备，共用连接池中的连接
+    let mut dev2redis = map2redis::Device2redis::new(dev, redis_pool.get());
 
-    if let (Some(ip), Some(port)) = (&redis_cfg.ip, &redis_cfg.port) {
-        if let Ok(v) = map2redis::Device2redis::new(dev, ip, port).await {
-            dev2redis = v;
-        } else {
-            error!("create dev2redis instance fail, redis is running ?");
-            return;
-        }
-    } else {
-        error!("have no redis config info");
+    // 先登记会话再激活，服务关闭时`drain`等待的会话包含正在激活的连接，不会在激活完成前退出；
+    // 同一sn在本服务上的旧连接收到通知后关闭，不再与本连接争抢下行消息，其他节点上的旧连接依赖纪元关闭
+    info!("device: {:?}", dev2redis.dev.sn);
+    let registered = sessions.register(&dev2redis.dev.sn, peer.ip);
+    let mut session = registered.session;
+    if let Some(old_ip) = registered.replaced {
+        info!("close local connection of dev {} from {}", dev2redis.dev.sn, old_ip);
+    }
+    // 登记时服务已在关闭或已被更新的连接顶替，不再激活
+    if let Ok(evict) = session.evicted.try_recv() {
+        warn!("dev {} closed before activation: {:?}", dev2redis.dev.sn, evict);
+        sessions.unregister(&session);
         return;
     }
 
-    // 激活设备，包括向redis添加设备上线信息
-    info!("device: {:?}", dev2redis.dev.sn);
-    if !dev2redis.activate().await {
-        error!("activated device({}) fail", dev2redis.dev.sn);
-        if !dev2redis.deactivate().await {
-            error!("deactivated device({}) fail", dev2redis.dev.sn);
+    // 激活设备，包括向redis添加设备上线信息；激活在redis脚本中原子执行，失败时只需注销会话
+    let activation = match dev2redis.activate(node.id(), &peer.ip, sessions.window()).await {
+        Some(activation) => activation,
+        None => {
+            error!("activated device({}) fail", dev2redis.dev.sn);
+            sessions.unregister(&session);
+            return;
         }
-        return;
+    };
+
+    // 上线记录由激活脚本返回，所有节点共享，设备连到不同节点时同样能检测到克隆
+    let history: Vec<(f64, IpAddr)> = activation.history.iter()
+        .filter_map(|(stamp, addr)| addr.parse().ok().map(|ip| (*stamp, ip)))
+        .collect();
+    let now = history.last().map(|(stamp, _)| *stamp).unwrap_or(0.0);
+    if let Some(suspect) = sessions.detect_clone(&history, now) {
+        dev2redis.flag_clone(&suspect, sessions.window()).await;
     }
 
-    // 循环处理设备消息
+    // 订阅下行消息通知
+    let mut downlink = notifier.subscribe(&dev2redis.dev.sn);
+
+    // 循环处理设备消息，退出时记录离线原因
+    let mut offline_reason = map2redis::OFFLINE_HEARTBEAT_TIMEOUT;
     loop {
         tokio::select! {
-            read_up = readline(&mut stream_reader) => {
-                if let Ok(msg) = read_up {
-                    dev2redis.update_online_status().await; // 收到消息，就更新redis中在线状态
+            read_up = next_frame(&mut pending, &mut stream_reader) => {
+                if let Ok(frame) = read_up {
+                    // 收到消息，就更新redis中在线状态；设备已在新连接上线时关闭旧连接
+                    if let Ok(false) = dev2redis.update_online_status().await {
+                        warn!("dev {} superseded by a newer connection, epoch {:?}", dev2redis.dev.sn, dev2redis.epoch());
+                        offline_reason = map2redis::OFFLINE_SUPERSEDED;
+                        break;
+                    }
                     dev2redis.dev.update_last_heartbeat_time_now(); // 收到消息，就更新本地心跳超时计时
-                    match parse_msg_type(&msg) {
-                        MESTYPE::HEARTBEAT => {
-                            if echo_pong(&mut stream_writer).await.is_err() {
+
+                    // 超出频率的帧丢弃，每次进入限流只回复一次error
+                    if let Some(limiter) = limiter.as_mut() {
+                        if !limiter.check() {
+                            if !rate_limited {
+                                warn!("rate limited: dev {}", dev2redis.dev.sn);
+                                let _ = echo_error(&mut stream_writer, version, ErrorCode::RateLimited, "too many frames").await;
+                                rate_limited = true;
+                            }
+                            continue;
+                        }
+                        rate_limited = false;
+                    }
+
+                    // 超长、非utf-8、无法解码与解密失败的帧已被丢弃，只告知设备
+                    let msg = match frame {
+                        Frame::Line(msg) => msg,
+                        Frame::Oversize(len) => {
+                            warn!("drop oversize frame: dev {}, {} bytes", dev2redis.dev.sn, len);
+                            let reason = format!("frame exceeds {} bytes", max_frame_size);
+                            let _ = echo_error(&mut stream_writer, version, ErrorCode::FrameTooLarge, &reason).await;
+                            continue;
+                        }
+                        Frame::InvalidUtf8 => {
+                            warn!("drop invalid utf-8 frame: dev {}", dev2redis.dev.sn);
+                            let _ = echo_error(&mut stream_writer, version, ErrorCode::InvalidUtf8, ErrorCode::InvalidUtf8.reason()).await;
+                            continue;
+                        }
+                        Frame::InvalidPayload => {
+                            warn!("drop undecodable binary frame: dev {}", dev2redis.dev.sn);
+                            let _ = echo_error(&mut stream_writer, version, ErrorCode::InvalidPayload, ErrorCode::InvalidPayload.reason()).await;
+                            continue;
+                        }
+                        Frame::DecryptFailed => {
+                            warn!("drop undecryptable frame: dev {}", dev2redis.dev.sn);
+                            let _ = echo_error(&mut stream_writer, version, ErrorCode::DecryptFailed, ErrorCode::DecryptFailed.reason()).await;
+                            continue;
+                        }
+                    };
+
+                    match parse_frame(&msg) {
+                        Some(CsodMessage::Ping { v, .. }) => {
+                            if v != version {
+                                warn!("ping version {} differs from negotiated {}: dev {}", v, version, dev2redis.dev.sn);
+                            }
+                            if echo_pong(&mut stream_writer, version).await.is_err() {
                                 warn!("pong to heartbeat failed: dev {}, msg {}", dev2redis.dev.sn, msg);
                             }
                         }
-                        MESTYPE::RAWDATA => {
-                            info!("push event: {:?}", &msg);
-                            if dev2redis.notify_event(&msg).await.is_err() {
-                                warn!("push event failed: dev {}, msg {}", dev2redis.dev.sn, msg);
+                        Some(event @ CsodMessage::Event(_)) => {
+                            info!("push event: {:?}", msg);
+                            if let Err(e) = event.validate_units() {
+                                warn!("reject event: dev {}, {}", dev2redis.dev.sn, e);
+                                let _ = echo_error(&mut stream_writer, version, ErrorCode::InvalidUnitStatus, &e.to_string()).await;
+                            } else {
+                                match dev2redis.notify_event(&event).await {
+                                    Ok(queued) => {
+                                        if !queued {
+                                            info!("drop duplicate event: dev {}, seq {:?}", dev2redis.dev.sn, event.seq());
+                                        }
+                                        // 带序号的event入队(或已入队)后回复eventack，设备收到后不再重发
+                                        if let (Some(seq), Some(sn)) = (event.seq(), event.sn()) {
+                                            let ack = CsodMessage::event_ack(version, sn.clone(), seq);
+                                            if writeline(&mut stream_writer, &ack).await.is_err() {
+                                                warn!("eventack failed: dev {}, seq {}", dev2redis.dev.sn, seq);
+                                            }
+                                        }
+                                    }
+                                    Err(_) => warn!("push event failed: dev {}, msg {}", dev2redis.dev.sn, msg),
+                                }
                             }
                         }
-                        MESTYPE::ACK => {
-                            info!("ack type {}", &msg);
-                            if dev2redis.write_uplink(&msg).await.is_err() {
+                        Some(ack) if ack.is_ack() => {
+                            info!("ack type {}", msg);
+                            if let Err(e) = ack.validate_units() {
+                                warn!("reject ack: dev {}, {}", dev2redis.dev.sn, e);
+                                let _ = echo_error(&mut stream_writer, version, ErrorCode::InvalidUnitStatus, &e.to_string()).await;
+                            } else if dev2redis.write_uplink(&ack).await.is_err() {
                                 warn!("write uplink stream failed: dev {}, msg {}", dev2redis.dev.sn, msg);
                             }
                         }
-                        MESTYPE::INVALID => {
-                            warn!("invalid data: dev {}", dev2redis.dev.sn);
+                        Some(_) => {
+                            warn!("unexpected message from device: dev {}, msg {}", dev2redis.dev.sn, msg);
+                            let _ = echo_error(&mut stream_writer, version, ErrorCode::UnknownType, "unexpected message type").await;
+                        }
+                        None => {
+                            let code = ErrorCode::classify(&msg);
+                            warn!("invalid data: dev {}, {}", dev2redis.dev.sn, code);
+                            let _ = echo_error(&mut stream_writer, version, code, code.reason()).await;
                         }
                     }
 
+                } else {
+                    warn!("connection broken: dev {}", dev2redis.dev.sn);
+                    offline_reason = map2redis::OFFLINE_CONNECTION_BROKEN;
+                    break;
+                }
+
+            }
+
+            notified = downlink.recv() => {
+                if notified.is_none() {
+                    warn!("downlink subscription of dev {} taken by a newer connection", dev2redis.dev.sn);
+                    offline_reason = map2redis::OFFLINE_REPLACED;
+                    break;
+                }
+                // 一次通知可能对应多条下行消息，按顺序全部取出；连接断开时放回未下发的消息并下线
+                let mut broken = false;
+                while let Ok(Some(pending)) = dev2redis.pop_downlink().await {
+                    // 下行消息按协商的版本下发
+                    let mut msg = pending.msg.clone();
+                    msg.set_version(version);
+                    info!("down link msg: {:?}", msg);
+                    match writeline(&mut stream_writer, &msg).await {
+                        Ok(_) => {
+                            info!("send ok: {:?}", msg);
+                            dev2redis.delivered(&pending).await;
+                        }
+                        Err(ServerError::INVALID_DATA) => warn!("drop undeliverable downlink: dev {}", dev2redis.dev.sn),
+                        Err(ServerError::Broken) => {
+                            warn!("send failed, requeue downlink: dev {}", dev2redis.dev.sn);
+                            dev2redis.requeue(pending).await;
+                            broken = true;
+                            break;
+                        }
+                    }
+                }
+                if broken {
+                    offline_reason = map2redis::OFFLINE_CONNECTION_BROKEN;
+                    break;
                 }
+            }
 
+            evict = &mut session.evicted => {
+                match evict {
+                    Ok(Evict::Replaced(ip)) => {
+                        warn!("dev {} replaced by a newer connection from {}, close connection from {}", dev2redis.dev.sn, ip, peer.ip);
+                        let _ = echo_error(&mut stream_writer, version, ErrorCode::SessionReplaced, "replaced by a newer connection").await;
+                        offline_reason = map2redis::OFFLINE_REPLACED;
+                    }
+                    _ => {
+                        // 服务关闭，提示设备稍后重连，设备不响应时不等待
+                        info!("server shutdown, close dev {}", dev2redis.dev.sn);
+                        if cfg.shutdown_reconnect_hint.unwrap_or(true) {
+                            let _ = timeout(Duration::from_secs(1), echo_error(&mut stream_writer, version, ErrorCode::ServerShutdown, "server shutdown, reconnect later")).await;
+                        }
+                        offline_reason = map2redis::OFFLINE_SHUTDOWN;
+                    }
+                }
+                break;
             }
 
-           result = dev2redis.readline_downlink() => {
-               if let Ok(msg) = result {
-                   info!("down link msg: {:?}", msg);
-                   if let Ok(_) = writeline(&mut stream_writer, msg.clone()).await {
-                       info!("send ok: {}", msg);
-                   } else {
-                       warn!("send failed")
-                   }
-               }
-           }
+            _ = tokio::time::delay_for(dev2redis.dev.time_to_expire()) => {}
         }
 
         // 判断是否过期, 过期则设备离线
         if !dev2redis.dev.is_alive_update() {
-            dev2redis.deactivate().await;
-            return;
+            break;
         }
-        tokio::time::delay_for(Duration::from_millis(100)).await;
     }
+
+    // 离线后才注销会话，服务关闭时据此等待所有设备离线
+    notifier.unsubscribe(&downlink);
+    dev2redis.deactivate(offline_reason).await;
+    sessions.unregister(&session);
 }
 
 /// 监听端口，派发连接
-fn coroutines_start(ip: String, port: String, hb_interval: u64, redis_cfg: RedisCfg) -> Result<(), Box<dyn std::error::Error>> {
+fn coroutines_start(cfg: Arc<PerceptCfg>, redis_cfg: RedisCfg, verifier: Arc<SnVerifier>) -> Result<(), Box<dyn std::error::Error>> {
 
     // 创建调度器
     let mut rt = runtime::Builder::new()
@@ -231,9 +600,107 @@ fn coroutines_start(ip: String, port: String, hb_interval: u64, redis_cfg: Redis
         .enable_all()
         .build()?;
 
-    let addr = format!("{}:{}", ip, port);
+    let addr = format!("{}:{}", cfg.ip.clone().unwrap(), cfg.port.clone().unwrap());
+
+    // 按产品启用的安全层
+    let psk = match PskPolicy::from_cfg(&cfg) {
+        Ok(psk) => psk,
+        Err(e) => panic!("load psk config error: {}", e),
+    };
+
+    // 握手challenge认证
+    let auth = match Authenticator::from_cfg(&cfg) {
+        Ok(auth) => Arc::new(auth),
+        Err(e) => panic!("load auth config error: {}", e),
+    };
+
+    // 配置了证书时使用TLS监听
+    let tls_acceptor = match tls::acceptor(&cfg) {
+        Ok(acceptor) => acceptor,
+        Err(e) => panic!("load tls config error: {}", e),
+    };
 
     rt.block_on(async move {
+        let (redis_ip, redis_port) = match (redis_cfg.ip.clone(), redis_cfg.port.clone()) {
+            (Some(redis_ip), Some(redis_port)) => (redis_ip, redis_port),
+            _ => panic!("have no redis config info"),
+        };
+
+        // 所有设备共用的redis连接池
+        let pool_size = redis_cfg.pool_size.unwrap_or(4);
+        let redis_pool = match RedisPool::new(&redis_ip, &redis_port, pool_size).await {
+            Ok(pool) => Arc::new(pool),
+            Err(e) => {
+                panic!("create redis pool error: {:?}, redis is running ?", e);
+            }
+        };
+        info!("redis pool ready, size {}", redis_pool.size());
+
+        // 所有连接共享的分帧统计与认证失败次数，定期输出到日志
+        let frame_stats = Arc::new(FrameStats::new());
+        let frame_stats_move = frame_stats.clone();
+        let auth_move = auth.clone();
+        let verifier_move = verifier.clone();
+        let sessions = Arc::new(SessionRegistry::from_cfg(&cfg));
+        let sessions_move = sessions.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::delay_for(Duration::from_secs(60)).await;
+                info!("frame stats: {:?}", frame_stats_move.snapshot());
+                info!("sessions: {}", sessions_move.active());
+                let rejected = verifier_move.rejected();
+                if !rejected.is_empty() {
+                    info!("sn rejected: {:?}", rejected);
+                }
+                if auth_move.enabled() {
+                    info!("auth failures: {}", auth_move.total_failures());
+                }
+            }
+        });
+
+        // 登记本节点并定期心跳，设备上线时记录所在节点
+        let node = Arc::new(Node::from_cfg(&cfg));
+        if node.join(&mut redis_pool.get(), &sessions).await.is_err() {
+            panic!("register node {} error, redis is running ?", node.id());
+        }
+        let node_move = node.clone();
+        let sessions_move = sessions.clone();
+        let redis_conn = redis_pool.get();
+        tokio::spawn(async move {
+            node_move.run(redis_conn, sessions_move).await;
+        });
+
+        // 清理异常退出的进程残留的在线记录，并回收宕机的节点
+        let sweeper = Sweeper::from_cfg(&cfg, &node).map(Arc::new);
+        if let Some(sweeper) = &sweeper {
+            let sweeper_move = sweeper.clone();
+            let redis_conn = redis_pool.get();
+            tokio::spawn(async move {
+                sweeper_move.run(redis_conn).await;
+            });
+        }
+
+        // 下行消息通知订阅，只订阅本节点的频道
+        let notifier = Arc::new(DownlinkNotifier::new());
+        let notifier_move = notifier.clone();
+        let channel = node.downlink_channel();
+        tokio::spawn(async move {
+            notifier_move.run(redis_ip, redis_port, channel).await;
+        });
+
+        let ctx = Arc::new(Context {
+            cfg: cfg.clone(),
+            redis_pool: redis_pool.clone(),
+            verifier,
+            psk,
+            auth,
+            notifier,
+            sessions: sessions.clone(),
+            frame_stats,
+            node: node.clone(),
+            tls_acceptor,
+        });
+
         let mut listener = match TcpListener::bind(&addr).await {
             Ok(listener) => listener,
             Err(e) => {
@@ -242,46 +709,123 @@ fn coroutines_start(ip: String, port: String, hb_interval: u64, redis_cfg: Redis
         };
 
         // Create listener.
-        info!("open the addr to listen {}", addr);
+        info!("open the addr to listen {}, tls {}", addr, ctx.tls_acceptor.is_some());
         let mut incoming = listener.incoming();
+        let shutdown = shutdown_signal();
+        tokio::pin!(shutdown);
 
         // 监听端口，或许第二种loop方法更容错?
         //while let Some(stream) = incoming.next().await {
         loop {
-            match incoming.next().await {
+            let stream = tokio::select! {
+                _ = &mut shutdown => break,
+                stream = incoming.next() => stream,
+            };
+            match stream {
                 Some(Ok(stream)) => {
-                    info!("coming a connection");
-                    let redis_cfg_move = redis_cfg.clone();
+                    let ip = stream.peer_addr().map(|addr| addr.ip()).unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+                    info!("coming a connection from {}", ip);
+                    let ctx_move = ctx.clone();
                     tokio::spawn(async move {
-                        handler(stream, hb_interval, redis_cfg_move).await;
+                        match ctx_move.tls_acceptor.clone() {
+                            // TLS握手在连接自己的任务中进行，不阻塞监听
+                            Some(acceptor) => match timeout(Duration::from_millis(40000), acceptor.accept(stream)).await {
+                                Ok(Ok(tls_stream)) => {
+                                    let peer = Peer { ip, cn: tls::peer_cn(&tls_stream) };
+                                    handler(Box::new(tls_stream), peer, ctx_move).await;
+                                }
+                                Ok(Err(e)) => warn!("tls handshake failed: {}", e),
+                                Err(_) => warn!("tls handshake timeout"),
+                            },
+                            None => {
+                                let peer = Peer { ip, cn: None };
+                                handler(Box::new(stream), peer, ctx_move).await;
+                            }
+                        }
                     });
                 }
                 e => error!("{:?}", e),
             }
         }
+
+        // 先停止监听，再通知所有连接下线
+        drop(incoming);
+        drop(listener);
+        drain(&sessions, Duration::from_secs(cfg.shutdown_deadline_secs.unwrap_or(DEFAULT_SHUTDOWN_DEADLINE_SECS))).await;
+        node.leave(redis_pool.get()).await;
+        if let Some(sweeper) = &sweeper {
+            sweeper.release(redis_pool.get()).await;
+        }
     });
 
+    // 超过期限仍未退出的连接任务直接丢弃
+    rt.shutdown_timeout(Duration::from_secs(1));
     Ok(())
 }
 
+/// 等待SIGTERM或SIGINT
+async fn shutdown_signal() {
+    let mut term = match signal(SignalKind::terminate()) {
+        Ok(term) => term,
+        Err(e) => {
+            error!("listen SIGTERM failed: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => warn!("received SIGINT"),
+        _ = term.recv() => warn!("received SIGTERM"),
+    }
+}
+
+/// 通知所有连接下线，等待它们在redis中标记为离线，超过`deadline`不再等待
+async fn drain(sessions: &SessionRegistry, deadline: Duration) {
+    let closing = sessions.close_all();
+    warn!("shutting down, closing {} sessions", closing);
+    let start = Instant::now();
+    while sessions.active() > 0 {
+        if start.elapsed() >= deadline {
+            error!("shutdown deadline exceeded, {} sessions not offline", sessions.active());
+            return;
+        }
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+    }
+    warn!("all sessions closed in {:?}", start.elapsed());
+}
+
 /// 启动设备连接服务
 pub fn start(perceptioncfg: PerceptCfg, rediscfg: RedisCfg) -> Result<(), ()> {
     info!("{:?}", perceptioncfg);
     println!("Device Connection Start with config {:?}", perceptioncfg);
 
-    let ip = perceptioncfg.ip.clone().unwrap();
-    let port = perceptioncfg.port.clone().unwrap();
-
-    // 如果没有配置心跳，默认120s
-    let heartbeat_interval = perceptioncfg.heartbeat_interval.unwrap_or(120);
+    let verifier = Arc::new(SnVerifier::new(perceptioncfg.sn_salt.clone()));
 
-    match coroutines_start(ip, port, heartbeat_interval, rediscfg) {
+    match coroutines_start(Arc::new(perceptioncfg), rediscfg, verifier) {
         Ok(()) => {
-            info!("start perception service success");
+            warn!("perception service stopped");
             Ok(())
         }
         Err(_) => {
             panic!("start perception service failed")
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod connection_test {
+    use crate::middleware_wrapper::json_wrapper::{CsodMessage, ErrorCode, Version};
+
+    use super::error_frame;
+
+    #[test]
+    fn test_error_frame_version() {
+        // v0设备在连接建立后不发送error帧，包括会话被顶替、服务关闭
+        assert_eq!(error_frame(Version(0), ErrorCode::SessionReplaced, "replaced"), None);
+        assert_eq!(error_frame(Version(0), ErrorCode::InvalidPayload, "invalid"), None);
+        assert_eq!(
+            error_frame(Version(1), ErrorCode::ServerShutdown, "shutdown"),
+            Some(CsodMessage::error(Version(1), ErrorCode::ServerShutdown, "shutdown"))
+        );
+    }
+}
@@ -64,6 +64,14 @@ impl Device {
         self.alive
     }
 
+    /// 距离心跳超时的剩余时间
+    pub fn time_to_expire(&self) -> Duration {
+        match self.last_heartbeat_time.elapsed() {
+            Ok(elapsed) => self.heartbeat_period.checked_sub(elapsed).unwrap_or_default(),
+            Err(_) => self.heartbeat_period,
+        }
+    }
+
     pub fn is_alive_const(&self) -> bool {
         self.alive
     }
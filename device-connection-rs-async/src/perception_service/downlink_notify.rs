@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use futures::StreamExt;
+#[allow(unused_imports)]
+use log::{
+    error,
+    info,
+    warn,
+};
+use tokio::sync::mpsc;
+use tokio::time::{delay_for, Duration};
+
+/// 下行消息通知分发
+///
+/// 整个服务只用一个redis订阅连接监听本节点的频道`csod/downlink_notify/{node}`，消息内容为sn，
+/// 收到通知后唤醒对应设备的handler去取下行队列，空闲设备不产生任何redis请求
+pub struct DownlinkNotifier {
+    next_id: AtomicU64,
+    subscribers: Mutex<HashMap<String, (u64, mpsc::Sender<()>)>>,
+}
+
+/// 设备handler持有的订阅，`recv`返回即表示下行队列可能有新消息；
+/// 返回None表示同一sn已被新的连接重新订阅，本连接不应再读取下行队列
+pub struct Subscription {
+    pub sn: String,
+    id: u64,
+    rx: mpsc::Receiver<()>,
+}
+
+impl Subscription {
+    pub async fn recv(&mut self) -> Option<()> {
+        self.rx.recv().await
+    }
+}
+
+impl DownlinkNotifier {
+    pub fn new() -> DownlinkNotifier {
+        DownlinkNotifier {
+            next_id: AtomicU64::new(0),
+            subscribers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 注册设备，注册后立即触发一次通知，以取出设备上线前已入队的消息
+    pub fn subscribe(&self, sn: &str) -> Subscription {
+        // 容量为1，未处理的通知合并为一次
+        let (mut tx, rx) = mpsc::channel(1);
+        let _ = tx.try_send(());
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.subscribers.lock().unwrap().insert(sn.to_string(), (id, tx));
+        Subscription { sn: sn.to_string(), id, rx }
+    }
+
+    /// 注销设备，同一sn已被新的连接重新注册时不做处理
+    pub fn unsubscribe(&self, sub: &Subscription) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some((id, _)) = subscribers.get(&sub.sn) {
+            if *id == sub.id {
+                subscribers.remove(&sub.sn);
+            }
+        }
+    }
+
+    pub fn notify(&self, sn: &str) {
+        if let Some((_, tx)) = self.subscribers.lock().unwrap().get_mut(sn) {
+            let _ = tx.try_send(());
+        }
+    }
+
+    fn notify_all(&self) {
+        for (_, tx) in self.subscribers.lock().unwrap().values_mut() {
+            let _ = tx.try_send(());
+        }
+    }
+
+    /// 订阅redis通知频道，连接断开后重连，并唤醒所有设备以防漏掉断线期间的消息
+    pub async fn run(&self, ip: String, port: String, channel: String) {
+        let addr = format!("redis://{}:{}/", ip, port);
+        loop {
+            match self.subscribe_redis(&addr, &channel).await {
+                Ok(_) => warn!("downlink notify subscription closed, reconnecting"),
+                Err(e) => error!("downlink notify subscription failed: {:?}", e),
+            }
+            delay_for(Duration::from_secs(1)).await;
+            self.notify_all();
+        }
+    }
+
+    async fn subscribe_redis(&self, addr: &str, channel: &str) -> redis::RedisResult<()> {
+        let client = redis::Client::open(addr)?;
+        let mut pubsub = client.get_async_connection().await?.into_pubsub();
+        pubsub.subscribe(channel).await?;
+        info!("subscribe {} ok", channel);
+        // 订阅建立前可能已有消息入队
+        self.notify_all();
+
+        let mut messages = pubsub.on_message();
+        while let Some(msg) = messages.next().await {
+            match msg.get_payload::<String>() {
+                Ok(sn) => self.notify(&sn),
+                Err(e) => warn!("invalid downlink notify payload: {:?}", e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod downlink_notify_test {
+    use futures::executor::block_on;
+
+    use super::DownlinkNotifier;
+
+    #[test]
+    fn test_subscribe_notify() {
+        let notifier = DownlinkNotifier::new();
+        let mut sub = notifier.subscribe("sn_a");
+        // 注册后有一次初始通知
+        assert_eq!(block_on(sub.recv()), Some(()));
+
+        // 多次通知合并
+        notifier.notify("sn_a");
+        notifier.notify("sn_a");
+        notifier.notify("sn_b");
+        assert_eq!(block_on(sub.recv()), Some(()));
+        assert!(sub.rx.try_recv().is_err());
+
+        // 新连接重新注册后，旧连接注销不影响新连接
+        let mut new_sub = notifier.subscribe("sn_a");
+        // 旧订阅的发送端已被替换，recv返回None
+        assert_eq!(block_on(sub.recv()), None);
+        notifier.unsubscribe(&sub);
+        notifier.notify("sn_a");
+        assert_eq!(block_on(new_sub.recv()), Some(()));
+        notifier.unsubscribe(&new_sub);
+        assert!(notifier.subscribers.lock().unwrap().is_empty());
+    }
+}
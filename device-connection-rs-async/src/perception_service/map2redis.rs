@@ -1,3 +1,6 @@
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 #[allow(unused_imports)]
 use log::{
     error,
@@ -5,140 +8,299 @@ use log::{
     warn,
 };
 
+use crate::middleware_wrapper::json_wrapper::CsodMessage;
 use crate::middleware_wrapper::redis_wrapper::{
+    Activation,
+    NAMESPACE_DEVICE_COMMAND,
+    NAMESPACE_DEVICE_DOWNLINK,
+    NAMESPACE_DEVICE_OFFLINE,
     NAMESPACE_DEVICE_STATUS,
-    NAMESPACE_DEVICES_BORN,
+    NAMESPACE_DEVICE_UPLINK,
     NAMESPACE_DEVICES_COMMON_EVENT_NOTIFY,
 };
 
 use super::device::Device;
+use super::session::CloneSuspect;
+
+/// ack在redis中的保留时间，http服务超时未取走则自动过期
+const UPLINK_TTL_SECS: usize = 60;
+/// event序号去重窗口，比已入队序号小得多时视为设备计数器重置
+const EVENT_SEQ_WINDOW: u64 = 1024;
+
+/// 取出的下行消息，连接断开未能下发时由`requeue`放回原队列
+pub struct Downlink {
+    pub msg: CsodMessage,
+    source: DownlinkSource,
+}
+
+enum DownlinkSource {
+    /// 离线暂存的命令，值为消息ID
+    Stored(String),
+    /// 下行队列中的原始数据
+    Queued(String),
+}
+
+/// 离线原因，记入status的`offline_reason`
+pub const OFFLINE_HEARTBEAT_TIMEOUT: &str = "heartbeat timeout";
+pub const OFFLINE_CONNECTION_BROKEN: &str = "connection broken";
+pub const OFFLINE_SUPERSEDED: &str = "superseded";
+pub const OFFLINE_REPLACED: &str = "replaced";
+pub const OFFLINE_SHUTDOWN: &str = "server shutdown";
+/// 由清理任务标记，见`sweeper`
+pub const OFFLINE_PRESENCE_EXPIRED: &str = "presence expired";
+/// 所在节点心跳超时，由其他节点标记，见`node`
+pub const OFFLINE_NODE_DOWN: &str = "node down";
 use super::super::middleware_wrapper::redis_wrapper::RedisConn;
 
 pub struct Device2redis {
     pub dev: Device,
     pub redis_conn: RedisConn,
+    /// 本次连接的纪元，激活后才有，每次上线递增
+    epoch: Option<u64>,
 }
 
 impl Device2redis {
-    pub async fn new(dev: Device, ip: &str, port: &str) -> Result<Device2redis, ()> {
-        if let Ok(conn) = RedisConn::new(ip, port).await {
-            Ok(Device2redis { dev: dev, redis_conn: conn })
-        } else {
-            error!("connection redis fail, ");
-            Err(())
-        }
+    /// `redis_conn`取自共享连接池，设备本身不持有独立的redis连接
+    pub fn new(dev: Device, redis_conn: RedisConn) -> Device2redis {
+        Device2redis { dev, redis_conn, epoch: None }
+    }
+
+    pub fn epoch(&self) -> Option<u64> {
+        self.epoch
     }
 
     /// 更新设备在线状态，用于每次收到消息，就更新设备在线, 主要更新status online字段和添加online
-    pub async fn update_online_status(&mut self) -> bool {
-        info!("update 'online' failed: sn {}", self.dev.sn);
-        if self.redis_conn.hset(&format!("{}/{}", NAMESPACE_DEVICE_STATUS, self.dev.sn), "online", "true").await.is_err() {
+    ///
+    /// 设备已在新的连接上线(纪元已不是最新)时不做修改，返回Ok(false)，该连接应当关闭
+    pub async fn update_online_status(&mut self) -> Result<bool, ()> {
+        let epoch = match self.epoch {
+            Some(epoch) => epoch,
+            None => return Ok(false),
+        };
+        self.redis_conn.touch_device(&self.dev.sn, epoch).await.map_err(|_| {
             error!("update 'online' failed: sn {}", self.dev.sn);
-            return false;
-        }
+        })
+    }
 
-        if let Err(_) = self.redis_conn.zadd_device_alive_with_timestamp(&self.dev.sn).await {
-            error!("set redis fail");
-            return false;
+    /// 激活设备: 在一个redis脚本中递增纪元、设置在线状态、所在节点`node`与来源ip并登记born集合，
+    /// 同时记录对旧连接的顶替与上线记录，上线记录保留`history`时长
+    ///
+    /// 上行ack按消息ID存放并自动过期，无需清理；下行队列与离线期间暂存的命令都保留，上线后随下行通知一起取出下发
+    pub async fn activate(&mut self, node: &str, addr: &IpAddr, history: Duration) -> Option<Activation> {
+        match self.redis_conn.activate_device(&self.dev.sn, node, &addr.to_string(), history.as_secs()).await {
+            Ok(activation) => {
+                info!("device online: sn {}, epoch {}", self.dev.sn, activation.epoch);
+                if let Some((prev_node, prev_addr)) = &activation.replaced {
+                    warn!("device {} taken over: {} on node {} -> {} on node {}", self.dev.sn, prev_addr, prev_node, addr, node);
+                }
+                self.epoch = Some(activation.epoch);
+                Some(activation)
+            }
+            Err(_) => {
+                error!("set redis fail");
+                None
+            }
         }
-        true
     }
 
-    pub async fn activate(&mut self) -> bool {
-
-        // 添加到在线设备集合中
-        if let Err(_) = self.redis_conn.zadd_device_alive_with_timestamp(&self.dev.sn).await {
-            error!("set redis fail");
-            return false;
+    /// 设备离线，`reason`记入status的`offline_reason`
+    ///
+    /// 纪元不是最新的时不修改状态，避免旧连接把已在新连接上线的设备标记为离线
+    pub async fn deactivate(&mut self, reason: &str) -> bool {
+        let epoch = match self.epoch.take() {
+            Some(epoch) => epoch,
+            None => return false,
+        };
+        match self.redis_conn.deactivate_device(&self.dev.sn, epoch, reason).await {
+            Ok(true) => {
+                warn!("device offline: sn {}, epoch {}, {}", self.dev.sn, epoch, reason);
+                true
+            }
+            Ok(false) => {
+                warn!("device {} reconnected, skip offline of stale epoch {}", self.dev.sn, epoch);
+                false
+            }
+            Err(_) => {
+                error!("set redis fail");
+                false
+            }
         }
+    }
 
-        // 设置status在线状态
-        if let Err(_) = self.redis_conn.hset_online_with_time(&format!("{}/{}", NAMESPACE_DEVICE_STATUS, self.dev.sn), "true").await {
-            let _ = self.redis_conn.zrem_devices_alive(&self.dev.sn).await;
-            error!("set redis fail");
-            return false;
+    /// 标记本设备为疑似克隆，并向告警队列"csod/mq/alarm"推入告警，`window`内已被标记过(可能由其他节点标记)时不再告警
+    pub async fn flag_clone(&mut self, suspect: &CloneSuspect, window: Duration) {
+        let addrs: Vec<String> = suspect.addrs.iter().map(|ip| ip.to_string()).collect();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let alarm = serde_json::json!({
+            "type": "clone_suspected",
+            "sn": self.dev.sn,
+            "addrs": addrs,
+            "switches": suspect.switches,
+            "window": window.as_secs(),
+            "time": now,
+        });
+        match self.redis_conn.flag_clone(&self.dev.sn, &addrs.join(","), suspect.switches, &alarm.to_string(), window.as_secs()).await {
+            Ok(0) => info!("device {} already flagged as suspected clone within {:?}", self.dev.sn, window),
+            Ok(count) => error!("suspected cloned device {}: {} switches between {:?}, flagged {} times", self.dev.sn, suspect.switches, addrs, count),
+            Err(_) => error!("flag clone failed: sn {}", self.dev.sn),
         }
+    }
+
+    fn downlink_key(&self) -> String {
+        format!("{}/{}", NAMESPACE_DEVICE_DOWNLINK, self.dev.sn)
+    }
 
-        // 清理上行链路和下行链路缓存
-        let _ = self.redis_conn.hdel(&*format!("{}/{}", NAMESPACE_DEVICE_STATUS, self.dev.sn), "uplink").await;
-        let _ = self.redis_conn.hdel(&*format!("{}{}", NAMESPACE_DEVICE_STATUS, self.dev.sn), "downlink").await;
-
-        // 如果第一次激活的设备，添加到born有序集合中
-        match self.redis_conn.zrank(NAMESPACE_DEVICES_BORN, &self.dev.sn).await {
-            Ok(e) => {
-                if let None = e {
-                    if let Err(_) = self.redis_conn.zadd_device_born_with_timestamp(&self.dev.sn).await {
-                        warn!("add the born set fail");
-                        false
-                    } else {
-                        true
-                    }
-                } else {
-                    true
+    fn offline_key(&self) -> String {
+        format!("{}/{}", NAMESPACE_DEVICE_OFFLINE, self.dev.sn)
+    }
+
+    fn command_key(&self, id: &str) -> String {
+        format!("{}/{}/{}", NAMESPACE_DEVICE_COMMAND, self.dev.sn, id)
+    }
+
+    /// 取出一条离线期间暂存的命令，取出与读取记录在redis内原子执行，已过期的命令标记为expired后跳过；
+    /// 下发成功后由`delivered`标记为delivered
+    pub async fn pop_stored_command(&mut self) -> Result<Option<Downlink>, ()> {
+        let offline_key = self.offline_key();
+        loop {
+            let id = match self.redis_conn.peek_list_tail(&offline_key).await? {
+                Some(id) => id,
+                None => return Ok(None),
+            };
+            let key = self.command_key(&id);
+            let line = match self.redis_conn.pop_command(&offline_key, &key, &id).await? {
+                Some((result, Some(line))) if result == "ok" => line,
+                Some((result, Some(line))) if result == "expired" => {
+                    warn!("stored command expired: dev {}, id {}, msg {}", self.dev.sn, id, line);
+                    continue;
                 }
-            }
-            Err(_) => {
-                false
+                Some(_) => {
+                    warn!("stored command record missing: dev {}, id {}", self.dev.sn, id);
+                    continue;
+                }
+                // 已被其他连接取走，重新查看
+                None => continue,
+            };
+            match CsodMessage::parse(&line) {
+                Ok(msg) if msg.is_downlink() => return Ok(Some(Downlink { msg, source: DownlinkSource::Stored(id) })),
+                _ => warn!("drop invalid stored command: dev {}, msg {}", self.dev.sn, line),
             }
         }
     }
 
-    pub async fn deactivate(&mut self) -> bool {
-        warn!("device offline");
-        // 设置在线状态为false
-        let v1 = match self.redis_conn.hset_online_with_time(&format!("{}/{}", NAMESPACE_DEVICE_STATUS, self.dev.sn), "false").await {
-            Ok(_) => true,
-            Err(_) => {
-                error!("set redis fail");
-                false
+    /// 下发成功后调用，离线暂存的命令标记为delivered
+    pub async fn delivered(&mut self, downlink: &Downlink) {
+        if let DownlinkSource::Stored(id) = &downlink.source {
+            let key = self.command_key(id);
+            if self.redis_conn.hset_if_exists(&key, &[("status", "delivered")]).await.is_err() {
+                error!("mark stored command delivered failed: dev {}, id {}", self.dev.sn, id);
             }
-        };
+        }
+    }
 
-        // 从在线设备集合中删除
-        let v2 = match self.redis_conn.zrem_devices_alive(&self.dev.sn).await {
-            Ok(_) => true,
-            Err(_) => {
-                error!("set redis fail");
-                false
+    /// 取出一条下行消息，先取离线暂存的命令，再取下行队列，无法解析为CSoD下行消息的数据直接丢弃，队列为空返回None
+    pub async fn pop_downlink(&mut self) -> Result<Option<Downlink>, ()> {
+        if let Some(downlink) = self.pop_stored_command().await? {
+            return Ok(Some(downlink));
+        }
+
+        let key = self.downlink_key();
+        loop {
+            let v = match self.redis_conn.pop_from_list(&key).await? {
+                Some(v) => v,
+                None => return Ok(None),
+            };
+            match CsodMessage::parse(&v) {
+                Ok(msg) if msg.is_downlink() && msg.validate_units().is_ok() => return Ok(Some(Downlink { msg, source: DownlinkSource::Queued(v) })),
+                _ => warn!("drop invalid downlink msg: dev {}, msg {}", self.dev.sn, v),
             }
-        };
-        v1 && v2
+        }
     }
 
-    pub async fn readline_downlink(&mut self) -> Result<String, ()> {
-        if let Ok(v) = self.redis_conn.hget(&*format!("{}/{}", NAMESPACE_DEVICE_STATUS, self.dev.sn), "downlink").await {
-            // 读完值就从redis删除掉
-            let _ = self.redis_conn.hdel(&*format!("{}/{}", NAMESPACE_DEVICE_STATUS, self.dev.sn), "downlink").await;
-            Ok(v)
-        } else {
-            Err(())
+    /// 未能下发的消息放回原队列的取出端，保持下发顺序，设备重连后重新下发
+    pub async fn requeue(&mut self, downlink: Downlink) {
+        let (key, v) = match downlink.source {
+            DownlinkSource::Stored(id) => (self.offline_key(), id),
+            DownlinkSource::Queued(v) => (self.downlink_key(), v),
+        };
+        if self.redis_conn.push_back_to_list(&key, &v).await.is_err() {
+            error!("requeue downlink failed: dev {}, msg {}", self.dev.sn, v);
         }
     }
 
-    // 写上行ack消息
-    pub async fn write_uplink(&mut self, msg: &str) -> Result<Option<usize>, ()> {
-        info!("push msg to uplink {}", msg);
-        self.redis_conn.hset(&*format!("{}/{}", NAMESPACE_DEVICE_STATUS, self.dev.sn), "uplink", msg).await
+    /// 写上行ack消息，按ack中的消息ID存放，供发起请求的http调用取走，没有消息ID的ack无法关联请求，直接丢弃
+    pub async fn write_uplink(&mut self, msg: &CsodMessage) -> Result<(), ()> {
+        let id = match msg.id() {
+            Some(id) => id,
+            None => {
+                warn!("drop ack without id: dev {}, msg {}", self.dev.sn, msg.to_line());
+                return Err(());
+            }
+        };
+        let line = msg.to_line();
+        info!("push msg to uplink {}", line);
+        let key = format!("{}/{}/{}", NAMESPACE_DEVICE_UPLINK, self.dev.sn, id);
+        self.redis_conn.set_ex(&key, &line, UPLINK_TTL_SECS).await?;
+
+        // 响应的是离线暂存的命令时，将结果记入命令记录
+        let command_key = self.command_key(id.as_str());
+        self.redis_conn.hset_if_exists(&command_key, &[("status", "acked"), ("ack", &line)]).await.map(|_| ())
     }
 
-    /// 所有设备的event消息都推入相同的key为"csod/mq/p5"队列
-    pub async fn notify_event(&mut self, msg: &str) -> Result<Option<usize>, ()> {
-        self.redis_conn.push_to_list(NAMESPACE_DEVICES_COMMON_EVENT_NOTIFY, msg).await
+    /// 所有设备的event消息都推入相同的key为"csod/mq/p5"队列，返回是否入队
+    ///
+    /// 带序号的event按序号去重，重连后重发的重复event不再入队，返回false
+    pub async fn notify_event(&mut self, msg: &CsodMessage) -> Result<bool, ()> {
+        match msg.seq() {
+            Some(seq) => {
+                let status_key = format!("{}/{}", NAMESPACE_DEVICE_STATUS, self.dev.sn);
+                self.redis_conn.push_event_dedup(&status_key, NAMESPACE_DEVICES_COMMON_EVENT_NOTIFY, &msg.to_line(), seq, EVENT_SEQ_WINDOW).await
+            }
+            None => self.redis_conn.push_to_list(NAMESPACE_DEVICES_COMMON_EVENT_NOTIFY, &msg.to_line()).await.map(|_| true),
+        }
     }
 }
 
 /// redis基本测试
 #[cfg(test)]
 mod test_redis_conn {
-    use futures::executor::block_on;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+
+    use tokio::runtime::Runtime;
+
+    use crate::middleware_wrapper::redis_wrapper::{NAMESPACE_DEVICES_ALIVE, RedisPool};
 
     use super::Device;
     use super::Device2redis;
 
     #[test]
     fn test_deactivate() {
-        let d2r = block_on(Device2redis::new(Device::new("test".to_string()), "127.0.0.1", "6379"));
-        block_on(d2r.unwrap().activate());
+        let mut rt = Runtime::new().unwrap();
+        let pool = rt.block_on(RedisPool::new("127.0.0.1", "6379", 1)).unwrap();
+        let mut d2r = Device2redis::new(Device::new("test".to_string()), pool.get());
+        rt.block_on(d2r.activate("test", &IpAddr::V4(Ipv4Addr::LOCALHOST), Duration::from_secs(60)));
         //assert_eq!(Ok(_), block_on(d2r.unwrap().activate()));
     }
+
+    #[test]
+    fn test_epoch() {
+        let mut rt = Runtime::new().unwrap();
+        let pool = rt.block_on(RedisPool::new("127.0.0.1", "6379", 1)).unwrap();
+        let mut old = Device2redis::new(Device::new("test_epoch".to_string()), pool.get());
+        let mut new = Device2redis::new(Device::new("test_epoch".to_string()), pool.get());
+        let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        rt.block_on(async {
+            assert!(old.activate("node-a", &addr, Duration::from_secs(60)).await.is_some());
+            assert_eq!(old.update_online_status().await, Ok(true));
+            // 设备重连后，旧连接不能刷新或离线新连接的状态
+            assert!(new.activate("node-b", &addr, Duration::from_secs(60)).await.is_some());
+            assert!(new.epoch() > old.epoch());
+            assert_eq!(old.update_online_status().await, Ok(false));
+            assert!(!old.deactivate(super::OFFLINE_SUPERSEDED).await);
+            assert_eq!(pool.get().zrank(NAMESPACE_DEVICES_ALIVE, "test_epoch").await.map(|r| r.is_some()), Ok(true));
+            assert!(new.deactivate(super::OFFLINE_CONNECTION_BROKEN).await);
+            assert_eq!(pool.get().zrank(NAMESPACE_DEVICES_ALIVE, "test_epoch").await, Ok(None));
+        });
+    }
 }
\ No newline at end of file
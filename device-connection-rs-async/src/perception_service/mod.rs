@@ -3,6 +3,16 @@
 //! 感知层服务对接系统感知层设备，为感知层设备提供稳定可靠TCP连接、redis数据转发
 //!
 
+pub mod auth;
+pub mod codec;
 pub mod connection;
 pub mod device;
-pub mod map2redis;
\ No newline at end of file
+pub mod downlink_notify;
+pub mod map2redis;
+pub mod node;
+pub mod rate_limit;
+pub mod secure;
+pub mod session;
+pub mod sn_verifier;
+pub mod sweeper;
+pub mod tls;
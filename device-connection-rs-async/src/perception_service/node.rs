@@ -0,0 +1,151 @@
+//!
+//! # 连接服务节点
+//! 多个连接服务进程(节点)部署在负载均衡之后，每个节点在redis中登记自己并定期心跳:
+//!
+//! - `csod/nodes`: 有序集合，成员为节点id，score为最近心跳时间
+//! - `csod/node/{id}`: hash，字段addr, pid, started_at, heartbeat, ttl, connections
+//! - `csod/node_devices/{id}`: 集合，在该节点上线的sn
+//!
+//! 设备上线时`csod/device_status/{sn}`的`node`字段记录所在节点，http服务只向该节点的下行通知频道`csod/downlink_notify/{id}`发布。
+//! 心跳超过`node_expire_secs`的节点由持有清理锁的节点回收(见`sweeper`)，仍属于它的设备被标记为离线。
+//! 只是暂时失联的节点恢复心跳时重新登记，其上的连接因纪元过期而关闭，设备重连后重新记录所在节点
+//!
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[allow(unused_imports)]
+use log::{
+    error,
+    info,
+    warn,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::common::config::PerceptionServiceConfig as PerceptCfg;
+use crate::middleware_wrapper::redis_wrapper::{NAMESPACE_DOWNLINK_NOTIFY, RedisConn};
+use crate::perception_service::map2redis::OFFLINE_SHUTDOWN;
+use crate::perception_service::session::SessionRegistry;
+
+/// 未配置时的心跳周期
+const DEFAULT_NODE_HEARTBEAT_SECS: u64 = 10;
+
+pub struct Node {
+    id: String,
+    /// 对外的监听地址，只用于展示
+    addr: String,
+    heartbeat: Duration,
+    /// 超过该时长没有心跳的节点视为宕机
+    expire: Duration,
+    /// 进程启动时间，unix时间戳秒，重新登记时不变
+    started_at: u64,
+}
+
+impl Node {
+    /// 没有配置`node_id`时随机生成，重启后为新的节点
+    pub fn from_cfg(cfg: &PerceptCfg) -> Node {
+        let id = match &cfg.node_id {
+            Some(id) => id.clone(),
+            None => {
+                let mut nonce = [0u8; 4];
+                let _ = SystemRandom::new().fill(&mut nonce);
+                format!("{}-{}", std::process::id(), nonce.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+            }
+        };
+        let addr = format!("{}:{}", cfg.ip.clone().unwrap_or_default(), cfg.port.clone().unwrap_or_default());
+        let heartbeat = cfg.node_heartbeat_secs.unwrap_or(DEFAULT_NODE_HEARTBEAT_SECS).max(1);
+        let expire = cfg.node_expire_secs.unwrap_or(heartbeat * 3).max(heartbeat * 2);
+        let started_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        Node { id, addr, heartbeat: Duration::from_secs(heartbeat), expire: Duration::from_secs(expire), started_at }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn expire(&self) -> Duration {
+        self.expire
+    }
+
+    /// 本节点的下行消息通知频道
+    pub fn downlink_channel(&self) -> String {
+        downlink_channel(&self.id)
+    }
+
+    /// 登记节点，失败时无法接收下行消息通知，由调用者决定是否启动
+    pub async fn join(&self, redis_conn: &mut RedisConn, sessions: &SessionRegistry) -> Result<(), ()> {
+        let started_at = self.started_at.to_string();
+        let pid = std::process::id().to_string();
+        let ttl = self.expire.as_secs().to_string();
+        let connections = sessions.active().to_string();
+        redis_conn.node_heartbeat(&self.id, &[
+            ("addr", &self.addr),
+            ("pid", &pid),
+            ("started_at", &started_at),
+            ("ttl", &ttl),
+            ("connections", &connections),
+        ]).await?;
+        info!("node {} joined, addr {}, heartbeat {:?}, expire {:?}", self.id, self.addr, self.heartbeat, self.expire);
+        Ok(())
+    }
+
+    /// 定期心跳，同时上报本节点的连接数；节点记录不存在(心跳中断期间被其他节点回收)时重新登记
+    pub async fn run(&self, mut redis_conn: RedisConn, sessions: Arc<SessionRegistry>) {
+        loop {
+            tokio::time::delay_for(self.heartbeat).await;
+            let connections = sessions.active().to_string();
+            match redis_conn.node_heartbeat(&self.id, &[("connections", &connections)]).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!("node {} was reaped, register again", self.id);
+                    if self.join(&mut redis_conn, &sessions).await.is_err() {
+                        error!("node {} register again failed", self.id);
+                    }
+                }
+                Err(_) => error!("node {} heartbeat failed", self.id),
+            }
+        }
+    }
+
+    /// 正常退出时注销节点，未能离线的设备一并标记为离线
+    pub async fn leave(&self, mut redis_conn: RedisConn) {
+        let before = redis_conn.get_unix_timestamp() + 1.0;
+        match redis_conn.reap_node(&self.id, before, OFFLINE_SHUTDOWN).await {
+            Ok(Some(0)) => info!("node {} left", self.id),
+            Ok(Some(n)) => warn!("node {} left, {} devices marked offline", self.id, n),
+            _ => error!("node {} leave failed", self.id),
+        }
+    }
+}
+
+/// 节点`id`的下行消息通知频道
+pub fn downlink_channel(id: &str) -> String {
+    format!("{}/{}", NAMESPACE_DOWNLINK_NOTIFY, id)
+}
+
+#[cfg(test)]
+mod node_test {
+    use std::time::Duration;
+
+    use crate::common::config::PerceptionServiceConfig as PerceptCfg;
+
+    use super::Node;
+
+    #[test]
+    fn test_from_cfg() {
+        let cfg: PerceptCfg = toml::from_str("ip = \"0.0.0.0\"\nport = \"8900\"\nnode_id = \"node-a\"\nnode_heartbeat_secs = 5").unwrap();
+        let node = Node::from_cfg(&cfg);
+        assert_eq!(node.id(), "node-a");
+        assert_eq!(node.addr, "0.0.0.0:8900");
+        assert_eq!(node.expire(), Duration::from_secs(15));
+        assert_eq!(node.downlink_channel(), "csod/downlink_notify/node-a");
+
+        // 过期时长至少两个心跳周期
+        let cfg: PerceptCfg = toml::from_str("node_heartbeat_secs = 5\nnode_expire_secs = 6").unwrap();
+        assert_eq!(Node::from_cfg(&cfg).expire(), Duration::from_secs(10));
+
+        // 未配置时每个进程不同
+        let cfg: PerceptCfg = toml::from_str("").unwrap();
+        assert_ne!(Node::from_cfg(&cfg).id(), Node::from_cfg(&cfg).id());
+    }
+}
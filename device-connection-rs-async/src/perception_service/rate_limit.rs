@@ -0,0 +1,70 @@
+use std::time::{Duration, Instant};
+
+/// 令牌桶限流，每个连接一个，用于限制设备上行帧的频率
+pub struct RateLimiter {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    /// 每`period`最多`max`个，允许瞬间突发`max`个
+    pub fn new(max: u32, period: Duration) -> RateLimiter {
+        let capacity = max.max(1) as f64;
+        RateLimiter {
+            capacity,
+            tokens: capacity,
+            refill_per_sec: capacity / period.as_secs_f64().max(0.001),
+            last: Instant::now(),
+        }
+    }
+
+    /// 每分钟最多`max`个
+    pub fn per_minute(max: u32) -> RateLimiter {
+        RateLimiter::new(max, Duration::from_secs(60))
+    }
+
+    /// 取一个令牌，超限返回false
+    pub fn check(&mut self) -> bool {
+        self.check_at(Instant::now())
+    }
+
+    fn check_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod rate_limit_test {
+    use std::time::{Duration, Instant};
+
+    use super::RateLimiter;
+
+    #[test]
+    fn test_check() {
+        let mut limiter = RateLimiter::new(3, Duration::from_secs(3));
+        let start = Instant::now();
+        assert!(limiter.check_at(start));
+        assert!(limiter.check_at(start));
+        assert!(limiter.check_at(start));
+        assert!(!limiter.check_at(start));
+        // 每秒恢复一个
+        assert!(limiter.check_at(start + Duration::from_millis(1000)));
+        assert!(!limiter.check_at(start + Duration::from_millis(1500)));
+        // 恢复不超过容量
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.check_at(later));
+        }
+        assert!(!limiter.check_at(later));
+    }
+}
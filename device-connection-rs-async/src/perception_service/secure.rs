@@ -0,0 +1,214 @@
+//!
+//! # 安全层
+//! 供不能使用TLS的设备: 握手时由预共享密钥派生会话密钥，之后每一帧用ChaCha20-Poly1305加密
+//!
+//! - 设备密钥 = HMAC-SHA256(psk_master_key, sn)，sn为大写，出厂时写入设备
+//! - 会话密钥 = HKDF-SHA256(salt = 设备nonce || 服务端nonce, ikm = 设备密钥)，
+//!   上行、下行分别以info `csod up`、`csod down`派生，两个方向互不影响
+//! - 认证密钥 = HKDF-SHA256(salt为空, ikm = 设备密钥)，info为`csod auth`，与会话密钥互不相关，见`auth`
+//! - 加密帧 = 8字节大端序号 || 密文 || 16字节tag，AEAD nonce为4字节0 || 序号，
+//!   序号每帧递增，收到不大于上一帧序号的帧视为重放并丢弃
+//!
+
+use std::str::FromStr;
+
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use csod::sn::{self, Product};
+
+use crate::common::config::PerceptionServiceConfig as PerceptCfg;
+
+/// 握手中双方nonce的字节数
+pub const NONCE_LEN: usize = 16;
+const SEQ_LEN: usize = 8;
+/// ChaCha20-Poly1305的tag字节数
+const TAG_LEN: usize = 16;
+/// 加密后每帧增加的字节数: 序号与tag
+pub const SEAL_OVERHEAD: usize = SEQ_LEN + TAG_LEN;
+
+/// 设备密钥，`sn`由调用方统一转为大写
+pub fn device_key(master_key: &[u8], sn: &str) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, master_key);
+    hmac::sign(&key, sn.as_bytes()).as_ref().to_vec()
+}
+
+fn derive(device_key: &[u8], salt: &[u8], info: &[u8]) -> LessSafeKey {
+    let prk = Salt::new(HKDF_SHA256, salt).extract(device_key);
+    let info = [info];
+    let okm = prk.expand(&info, &CHACHA20_POLY1305).expect("chacha20 key length is valid for hkdf");
+    LessSafeKey::new(UnboundKey::from(okm))
+}
+
+/// 握手challenge应答使用的HMAC密钥，以独立的info派生，不直接使用设备密钥
+pub fn auth_key(device_key: &[u8]) -> hmac::Key {
+    let prk = Salt::new(HKDF_SHA256, &[]).extract(device_key);
+    let info = [&b"csod auth"[..]];
+    hmac::Key::from(prk.expand(&info, hmac::HMAC_SHA256).expect("hmac key length is valid for hkdf"))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CipherError {
+    /// 序号不递增
+    Replay,
+    /// 认证失败，密钥不对或帧被篡改
+    Auth,
+}
+
+/// 一个方向的帧加解密，发送方递增序号，接收方检查序号
+pub struct FrameCipher {
+    key: LessSafeKey,
+    /// 发送方为下一帧的序号，接收方为期望的最小序号
+    seq: u64,
+}
+
+impl FrameCipher {
+    fn new(key: LessSafeKey) -> FrameCipher {
+        FrameCipher { key, seq: 0 }
+    }
+
+    fn nonce(seq: u64) -> Nonce {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        nonce[4..].copy_from_slice(&seq.to_be_bytes());
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    pub fn seal(&mut self, plain: &[u8]) -> Vec<u8> {
+        let seq = self.seq;
+        self.seq += 1;
+        let mut out = Vec::with_capacity(SEQ_LEN + plain.len() + CHACHA20_POLY1305.tag_len());
+        out.extend_from_slice(&seq.to_be_bytes());
+        let mut data = plain.to_vec();
+        self.key
+            .seal_in_place_append_tag(FrameCipher::nonce(seq), Aad::empty(), &mut data)
+            .expect("chacha20 seal never fails for frame sized data");
+        out.extend_from_slice(&data);
+        out
+    }
+
+    pub fn open(&mut self, frame: &[u8]) -> Result<Vec<u8>, CipherError> {
+        if frame.len() < SEQ_LEN + CHACHA20_POLY1305.tag_len() {
+            return Err(CipherError::Auth);
+        }
+        let mut seq = [0u8; SEQ_LEN];
+        seq.copy_from_slice(&frame[..SEQ_LEN]);
+        let seq = u64::from_be_bytes(seq);
+        if seq < self.seq {
+            return Err(CipherError::Replay);
+        }
+        let mut data = frame[SEQ_LEN..].to_vec();
+        let len = self
+            .key
+            .open_in_place(FrameCipher::nonce(seq), Aad::empty(), &mut data)
+            .map_err(|_| CipherError::Auth)?
+            .len();
+        // 认证通过后才推进序号，伪造的帧不影响后续的帧
+        self.seq = seq + 1;
+        data.truncate(len);
+        Ok(data)
+    }
+}
+
+/// 握手建立的安全会话
+pub struct SecureSession {
+    /// 服务端nonce，base64，在pong中下发
+    pub server_nonce: String,
+    /// 解密上行帧
+    pub up: FrameCipher,
+    /// 加密下行帧
+    pub down: FrameCipher,
+}
+
+impl SecureSession {
+    pub fn derive(device_key: &[u8], device_nonce: &[u8], server_nonce: &[u8]) -> SecureSession {
+        let salt = [device_nonce, server_nonce].concat();
+        SecureSession {
+            server_nonce: base64::encode(server_nonce),
+            up: FrameCipher::new(derive(device_key, &salt, b"csod up")),
+            down: FrameCipher::new(derive(device_key, &salt, b"csod down")),
+        }
+    }
+}
+
+/// 按产品决定是否使用安全层
+pub struct PskPolicy {
+    master_key: Option<Vec<u8>>,
+    required: Vec<Product>,
+    rng: SystemRandom,
+}
+
+impl PskPolicy {
+    pub fn from_cfg(cfg: &PerceptCfg) -> Result<PskPolicy, String> {
+        let mut required = vec![];
+        for product in cfg.psk_products.clone().unwrap_or_default() {
+            required.push(Product::from_str(&product).map_err(|e| e.to_string())?);
+        }
+        let master_key = cfg.psk_master_key.as_ref().map(|k| k.as_bytes().to_vec());
+        if !required.is_empty() && master_key.is_none() {
+            return Err("psk_products configured without psk_master_key".to_string());
+        }
+        Ok(PskPolicy { master_key, required, rng: SystemRandom::new() })
+    }
+
+    /// 该sn的产品是否必须加密
+    pub fn requires(&self, sn: &str) -> bool {
+        match sn::parse(sn) {
+            Ok(parsed) => self.required.contains(&parsed.product),
+            Err(_) => false,
+        }
+    }
+
+    /// 设备在ping中带了nonce时建立安全会话；没有配置主密钥时返回None，设备按明文通信
+    pub fn accept(&self, sn: &str, device_nonce: &str) -> Result<Option<SecureSession>, String> {
+        let master_key = match &self.master_key {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let device_nonce = match base64::decode(device_nonce) {
+            Ok(nonce) if nonce.len() == NONCE_LEN => nonce,
+            _ => return Err(format!("psk_nonce must be {} bytes in base64", NONCE_LEN)),
+        };
+        let mut server_nonce = [0u8; NONCE_LEN];
+        self.rng.fill(&mut server_nonce).map_err(|_| "generate nonce failed".to_string())?;
+        Ok(Some(SecureSession::derive(&device_key(master_key, sn), &device_nonce, &server_nonce)))
+    }
+}
+
+#[cfg(test)]
+mod secure_test {
+    use super::{device_key, CipherError, SecureSession};
+
+    #[test]
+    fn test_seal_open() {
+        let key = device_key(b"master", "A0AA1B0001F3E");
+        assert_ne!(key, device_key(b"master", "A0AA1B0002F3E"));
+        let mut server = SecureSession::derive(&key, &[1; 16], &[2; 16]);
+        let mut device = SecureSession::derive(&key, &[1; 16], &[2; 16]);
+
+        // 设备用上行密钥加密，服务端用上行密钥解密
+        let first = device.up.seal(br#"{"type":"ping"}"#);
+        let second = device.up.seal(b"second");
+        assert_eq!(server.up.open(&first).unwrap(), br#"{"type":"ping"}"#.to_vec());
+        assert_eq!(server.up.open(&second).unwrap(), b"second".to_vec());
+        // 重放
+        assert_eq!(server.up.open(&first), Err(CipherError::Replay));
+        // 两个方向的密钥不同
+        let down = server.down.seal(b"down");
+        assert_eq!(SecureSession::derive(&key, &[1; 16], &[2; 16]).up.open(&down), Err(CipherError::Auth));
+        assert_eq!(device.down.open(&down).unwrap(), b"down".to_vec());
+
+        // 篡改，且伪造的帧不推进序号
+        let mut third = device.up.seal(b"third");
+        let last = third.len() - 1;
+        third[last] ^= 1;
+        assert_eq!(server.up.open(&third), Err(CipherError::Auth));
+        third[last] ^= 1;
+        assert_eq!(server.up.open(&third).unwrap(), b"third".to_vec());
+
+        // 不同的nonce得到不同的会话密钥
+        let mut other = SecureSession::derive(&key, &[1; 16], &[3; 16]);
+        assert_eq!(other.up.open(&device.up.seal(b"x")), Err(CipherError::Auth));
+    }
+}
@@ -0,0 +1,251 @@
+//!
+//! # 设备会话
+//! 记录本服务上每个sn当前的连接。同一sn在新的连接上线时通知旧连接关闭，避免两个连接同时读取下行队列；
+//! 同一sn在时间窗口内反复在不同来源ip之间切换时，判定为疑似克隆设备(多台设备烧写了相同的sn)，
+//! 上线记录由激活脚本记在redis中，连到不同节点的克隆设备同样能被检测到。
+//! 服务关闭时通过`close_all`通知所有连接下线
+//!
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+
+use crate::common::config::PerceptionServiceConfig as PerceptCfg;
+
+/// 未配置时的检测窗口与切换次数
+const DEFAULT_CLONE_WINDOW_SECS: u64 = 600;
+const DEFAULT_CLONE_MIN_SWITCHES: usize = 4;
+
+/// 会话被关闭的原因
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Evict {
+    /// 同一sn在新的连接上线，值为新连接的来源ip
+    Replaced(IpAddr),
+    /// 服务关闭
+    Shutdown,
+}
+
+/// 一个连接的会话，需要关闭时`evicted`收到原因
+pub struct Session {
+    pub sn: String,
+    id: u64,
+    pub evicted: oneshot::Receiver<Evict>,
+}
+
+/// 疑似克隆: 窗口内上线的来源ip与切换次数
+#[derive(Debug, Clone, PartialEq)]
+pub struct CloneSuspect {
+    pub addrs: Vec<IpAddr>,
+    pub switches: usize,
+}
+
+/// 登记会话的结果
+pub struct Registered {
+    pub session: Session,
+    /// 被顶替的本服务上旧连接的来源ip
+    pub replaced: Option<IpAddr>,
+}
+
+struct Entry {
+    /// 会话id、关闭通知(已通知过为None)、来源ip
+    current: Option<(u64, Option<oneshot::Sender<Evict>>, IpAddr)>,
+}
+
+pub struct SessionRegistry {
+    next_id: AtomicU64,
+    window: Duration,
+    /// 为0时不检测克隆
+    min_switches: usize,
+    entries: Mutex<HashMap<String, Entry>>,
+    /// 服务关闭后登记的会话立即被通知关闭
+    closing: AtomicBool,
+}
+
+impl SessionRegistry {
+    pub fn from_cfg(cfg: &PerceptCfg) -> SessionRegistry {
+        SessionRegistry::new(
+            Duration::from_secs(cfg.clone_window_secs.unwrap_or(DEFAULT_CLONE_WINDOW_SECS)),
+            cfg.clone_min_switches.map(|n| n as usize).unwrap_or(DEFAULT_CLONE_MIN_SWITCHES),
+        )
+    }
+
+    fn new(window: Duration, min_switches: usize) -> SessionRegistry {
+        SessionRegistry {
+            next_id: AtomicU64::new(0),
+            window,
+            min_switches,
+            entries: Mutex::new(HashMap::new()),
+            closing: AtomicBool::new(false),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// 登记sn的新连接，通知该sn的旧连接关闭
+    pub fn register(&self, sn: &str, ip: IpAddr) -> Registered {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(sn.to_string()).or_insert(Entry { current: None });
+
+        // 握手期间服务开始关闭的连接，登记后立即收到通知，不再激活
+        let tx = if self.closing.load(Ordering::SeqCst) {
+            let _ = tx.send(Evict::Shutdown);
+            None
+        } else {
+            Some(tx)
+        };
+        // 旧连接可能已经断开，发送失败不影响
+        let replaced = entry.current.replace((id, tx, ip)).map(|(_, old, old_ip)| {
+            if let Some(old) = old {
+                let _ = old.send(Evict::Replaced(ip));
+            }
+            old_ip
+        });
+
+        Registered {
+            session: Session { sn: sn.to_string(), id, evicted: rx },
+            replaced,
+        }
+    }
+
+    /// 按上线记录(时间戳, 来源ip，按时间先后)检测克隆，只看截至`now`的检测窗口内的记录；
+    /// 同一窗口内只标记一次由redis保证，见`RedisConn::flag_clone`
+    pub fn detect_clone(&self, history: &[(f64, IpAddr)], now: f64) -> Option<CloneSuspect> {
+        if self.min_switches == 0 {
+            return None;
+        }
+        let since = now - self.window.as_secs_f64();
+        SessionRegistry::detect(history.iter().filter(|(t, _)| *t > since).map(|(_, ip)| *ip), self.min_switches)
+    }
+
+    /// 来源ip切换达到次数，且切回过之前的ip(设备换网络不算)
+    fn detect(history: impl Iterator<Item = IpAddr>, min_switches: usize) -> Option<CloneSuspect> {
+        let mut addrs: Vec<IpAddr> = vec![];
+        let mut switches = 0;
+        let mut last: Option<IpAddr> = None;
+        for ip in history {
+            if last.is_some_and(|last| last != ip) {
+                switches += 1;
+            }
+            if !addrs.contains(&ip) {
+                addrs.push(ip);
+            }
+            last = Some(ip);
+        }
+        if switches >= min_switches && addrs.len() <= switches {
+            Some(CloneSuspect { addrs, switches })
+        } else {
+            None
+        }
+    }
+
+    /// 连接关闭时注销，已被新连接顶替的会话不影响新连接
+    pub fn unregister(&self, session: &Session) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.get(&session.sn).is_some_and(|e| e.current.as_ref().is_some_and(|(id, _, _)| *id == session.id)) {
+            entries.remove(&session.sn);
+        }
+    }
+
+    /// 服务关闭: 通知所有会话下线，之后登记的会话也会立即收到通知，返回通知的会话数
+    pub fn close_all(&self) -> usize {
+        self.closing.store(true, Ordering::SeqCst);
+        let mut entries = self.entries.lock().unwrap();
+        let mut closed = 0;
+        // 会话保留到连接下线后注销，`active`据此等待所有连接下线
+        for (_, tx, _) in entries.values_mut().filter_map(|e| e.current.as_mut()) {
+            if let Some(tx) = tx.take() {
+                let _ = tx.send(Evict::Shutdown);
+                closed += 1;
+            }
+        }
+        closed
+    }
+
+    /// 当前有连接的sn数量
+    pub fn active(&self) -> usize {
+        self.entries.lock().unwrap().values().filter(|e| e.current.is_some()).count()
+    }
+}
+
+#[cfg(test)]
+mod session_test {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+
+    use super::{Evict, SessionRegistry};
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, last))
+    }
+
+    #[test]
+    fn test_takeover() {
+        let registry = SessionRegistry::new(Duration::from_secs(600), 4);
+        let mut old = registry.register("sn1", ip(1));
+        assert_eq!(old.replaced, None);
+        assert!(old.session.evicted.try_recv().is_err());
+
+        let new = registry.register("sn1", ip(2));
+        assert_eq!(new.replaced, Some(ip(1)));
+        assert_eq!(old.session.evicted.try_recv().unwrap(), Evict::Replaced(ip(2)));
+
+        // 旧连接注销不影响新连接
+        registry.unregister(&old.session);
+        assert_eq!(registry.active(), 1);
+        registry.unregister(&new.session);
+        assert_eq!(registry.active(), 0);
+        assert_eq!(registry.register("sn1", ip(1)).replaced, None);
+    }
+
+    #[test]
+    fn test_close_all() {
+        let registry = SessionRegistry::new(Duration::from_secs(600), 4);
+        let mut a = registry.register("sn1", ip(1));
+        let mut b = registry.register("sn2", ip(2));
+        assert_eq!(registry.close_all(), 2);
+        assert_eq!(a.session.evicted.try_recv().unwrap(), Evict::Shutdown);
+        assert_eq!(b.session.evicted.try_recv().unwrap(), Evict::Shutdown);
+        // 连接下线注销前仍算在内
+        assert_eq!(registry.active(), 2);
+
+        // 关闭后登记的会话立即收到通知，注销前同样算在内，drain不会在它下线前结束
+        let mut c = registry.register("sn3", ip(3));
+        assert_eq!(c.session.evicted.try_recv().unwrap(), Evict::Shutdown);
+        assert_eq!(registry.active(), 3);
+        registry.unregister(&a.session);
+        registry.unregister(&b.session);
+        registry.unregister(&c.session);
+        assert_eq!(registry.active(), 0);
+    }
+
+    #[test]
+    fn test_clone_suspect() {
+        let registry = SessionRegistry::new(Duration::from_secs(600), 4);
+        let history = |ips: &[u8], step: f64| -> Vec<(f64, IpAddr)> {
+            ips.iter().enumerate().map(|(i, last)| (1000.0 + i as f64 * step, ip(*last))).collect()
+        };
+
+        // 换了4次网络，没有切回，不是克隆
+        assert_eq!(registry.detect_clone(&history(&[1, 2, 3, 4, 5], 1.0), 1005.0), None);
+
+        // 两个ip来回切换，上线记录可能来自不同节点
+        let suspect = registry.detect_clone(&history(&[1, 2, 1, 2, 1], 1.0), 1005.0).unwrap();
+        assert_eq!(suspect.addrs, vec![ip(1), ip(2)]);
+        assert_eq!(suspect.switches, 4);
+
+        // 窗口外的上线记录不计入
+        assert_eq!(registry.detect_clone(&history(&[1, 2, 1, 2, 1], 400.0), 2600.0), None);
+
+        // 切换次数配置为0时不检测
+        let quiet = SessionRegistry::new(Duration::from_secs(600), 0);
+        assert_eq!(quiet.detect_clone(&history(&[1, 2, 1, 2, 1, 2], 1.0), 1006.0), None);
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[allow(unused_imports)]
+use log::{
+    error,
+    info,
+    warn,
+};
+
+use csod::sn::{self, SnError};
+
+/// 握手阶段的SN校验，按失败原因计数，计数随分帧统计定期输出到日志
+pub struct SnVerifier {
+    salt: Option<String>,
+    rejected: Mutex<HashMap<&'static str, u64>>,
+}
+
+impl SnVerifier {
+    /// 没有配置salt时不校验，兼容不符合SN规范的老设备
+    pub fn new(salt: Option<String>) -> SnVerifier {
+        if salt.is_none() {
+            warn!("sn_salt not configured, sn will not be verified");
+        }
+        SnVerifier {
+            salt,
+            rejected: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn verify(&self, sn: &str) -> Result<(), SnError> {
+        let result = match &self.salt {
+            Some(salt) => sn::verify(sn, salt).map(|_| ()),
+            None => return Ok(()),
+        };
+        if let Err(e) = &result {
+            let total = {
+                let mut rejected = self.rejected.lock().unwrap();
+                let counter = rejected.entry(e.reason()).or_insert(0);
+                *counter += 1;
+                *counter
+            };
+            warn!("reject sn {:?}: {} ({} rejected for {})", sn, e, total, e.reason());
+        }
+        result
+    }
+
+    /// 各失败原因的累计次数
+    pub fn rejected(&self) -> HashMap<&'static str, u64> {
+        self.rejected.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod sn_verifier_test {
+    use chrono::NaiveDate;
+    use csod::sn::{self, Product};
+
+    use super::SnVerifier;
+
+    #[test]
+    fn test_verify_count() {
+        let verifier = SnVerifier::new(Some("anbwscx".to_string()));
+        let date = NaiveDate::from_ymd_opt(2020, 11, 12).unwrap();
+        let good = sn::generate(Product::A0B, date, 7, "anbwscx").unwrap();
+        assert!(verifier.verify(&good).is_ok());
+        assert!(verifier.verify("A0AABC000A000").is_err());
+        assert!(verifier.verify("A0AABC000A001").is_err());
+        assert!(verifier.verify("B0AABC000A001").is_err());
+        assert!(verifier.verify("short").is_err());
+
+        let rejected = verifier.rejected();
+        assert_eq!(rejected.get("checksum"), Some(&2));
+        assert_eq!(rejected.get("unknown_product"), Some(&1));
+        assert_eq!(rejected.get("length"), Some(&1));
+    }
+
+    #[test]
+    fn test_no_salt() {
+        // 没有配置salt时老设备的sn照常上线
+        let verifier = SnVerifier::new(None);
+        assert!(verifier.verify("abc0").is_ok());
+        assert!(verifier.verify("A0AABC000A000").is_ok());
+        assert!(verifier.rejected().is_empty());
+    }
+}
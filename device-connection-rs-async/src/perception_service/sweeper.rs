@@ -0,0 +1,149 @@
+//!
+//! # 在线记录清理
+//! 连接服务异常退出后，`csod/devices_alive`中会残留已不在线的设备。清理任务定期将最近刷新时间
+//! 超过`presence_expire_factor`个心跳周期的设备标记为离线，并向"csod/mq/presence"推入offline事件。
+//! 多个连接服务进程通过`csod/sweeper_lock`保证同时只有一个执行清理，持有者退出后锁在两个清理周期内过期。
+//! 持有锁的节点同时回收心跳超时的节点，见`node`
+//!
+
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{
+    error,
+    info,
+    warn,
+};
+
+use crate::common::config::PerceptionServiceConfig as PerceptCfg;
+use crate::middleware_wrapper::redis_wrapper::{NAMESPACE_NODES, NAMESPACE_SWEEPER_LOCK, RedisConn};
+use crate::perception_service::map2redis::{OFFLINE_NODE_DOWN, OFFLINE_PRESENCE_EXPIRED};
+use crate::perception_service::node::Node;
+
+/// 未配置时的清理周期与过期倍数
+const DEFAULT_SWEEP_INTERVAL_SECS: u64 = 60;
+const DEFAULT_EXPIRE_FACTOR: u64 = 3;
+/// 单次脚本最多清理的设备数，避免长时间阻塞redis
+const SWEEP_BATCH: usize = 512;
+
+pub struct Sweeper {
+    /// 锁的持有者标识，即本节点id
+    owner: String,
+    interval: Duration,
+    /// 最近刷新时间超过该时长的在线记录视为过期
+    max_idle: Duration,
+    /// 超过该时长没有心跳的节点被回收
+    node_expire: Duration,
+}
+
+impl Sweeper {
+    /// 清理周期配置为0时不清理，返回None
+    pub fn from_cfg(cfg: &PerceptCfg, node: &Node) -> Option<Sweeper> {
+        let interval = cfg.presence_sweep_interval_secs.unwrap_or(DEFAULT_SWEEP_INTERVAL_SECS);
+        if interval == 0 {
+            return None;
+        }
+        let heartbeat = cfg.heartbeat_interval.unwrap_or(120);
+        let factor = cfg.presence_expire_factor.unwrap_or(DEFAULT_EXPIRE_FACTOR).max(1);
+        Some(Sweeper::new(node.id().to_string(), Duration::from_secs(interval), Duration::from_secs(heartbeat * factor), node.expire()))
+    }
+
+    fn new(owner: String, interval: Duration, max_idle: Duration, node_expire: Duration) -> Sweeper {
+        Sweeper { owner, interval, max_idle, node_expire }
+    }
+
+    /// 锁的有效期为两个清理周期，持有者每个周期续期一次
+    fn lock_ttl_ms(&self) -> u64 {
+        (self.interval.as_millis() as u64 * 2).max(1000)
+    }
+
+    /// 过期的截止时间戳
+    fn stale_before(&self, now: f64) -> f64 {
+        now - self.max_idle.as_secs_f64()
+    }
+
+    /// 回收心跳超时的节点，返回离线的设备数
+    async fn reap_nodes(&self, redis_conn: &mut RedisConn) -> Result<usize, ()> {
+        let before = redis_conn.get_unix_timestamp() - self.node_expire.as_secs_f64();
+        let mut reaped = 0;
+        for node in redis_conn.zrangebyscore_below(NAMESPACE_NODES, before).await? {
+            if node == self.owner {
+                continue;
+            }
+            if let Some(n) = redis_conn.reap_node(&node, before, OFFLINE_NODE_DOWN).await? {
+                error!("node {} down, {} devices marked offline", node, n);
+                reaped += n as usize;
+            }
+        }
+        Ok(reaped)
+    }
+
+    /// 持有锁时清理一次，返回清理的设备数，未持有锁返回None
+    async fn sweep_once(&self, redis_conn: &mut RedisConn) -> Result<Option<usize>, ()> {
+        if !redis_conn.acquire_lock(NAMESPACE_SWEEPER_LOCK, &self.owner, self.lock_ttl_ms()).await? {
+            return Ok(None);
+        }
+        let before = self.stale_before(redis_conn.get_unix_timestamp());
+        let mut swept = self.reap_nodes(redis_conn).await?;
+        loop {
+            let sns = redis_conn.sweep_alive(before, SWEEP_BATCH, OFFLINE_PRESENCE_EXPIRED).await?;
+            for sn in sns.iter() {
+                warn!("sweep stale device {}", sn);
+            }
+            swept += sns.len();
+            if sns.len() < SWEEP_BATCH {
+                return Ok(Some(swept));
+            }
+        }
+    }
+
+    pub async fn run(&self, mut redis_conn: RedisConn) {
+        info!("presence sweeper {} started, interval {:?}, max idle {:?}", self.owner, self.interval, self.max_idle);
+        loop {
+            tokio::time::delay_for(self.interval).await;
+            match self.sweep_once(&mut redis_conn).await {
+                Ok(Some(0)) | Ok(None) => {}
+                Ok(Some(n)) => warn!("swept {} stale devices", n),
+                Err(_) => error!("sweep stale devices failed"),
+            }
+        }
+    }
+
+    /// 退出时释放锁，其他进程不必等锁过期
+    pub async fn release(&self, mut redis_conn: RedisConn) {
+        if let Ok(true) = redis_conn.release_lock(NAMESPACE_SWEEPER_LOCK, &self.owner).await {
+            info!("presence sweeper {} released lock", self.owner);
+        }
+    }
+}
+
+#[cfg(test)]
+mod sweeper_test {
+    use std::time::Duration;
+
+    use crate::common::config::PerceptionServiceConfig as PerceptCfg;
+    use crate::perception_service::node::Node;
+
+    use super::Sweeper;
+
+    #[test]
+    fn test_from_cfg() {
+        let cfg: PerceptCfg = toml::from_str("heartbeat_interval = 30\npresence_expire_factor = 4\nnode_id = \"node-a\"").unwrap();
+        let node = Node::from_cfg(&cfg);
+        let sweeper = Sweeper::from_cfg(&cfg, &node).unwrap();
+        assert_eq!(sweeper.owner, "node-a");
+        assert_eq!(sweeper.max_idle, Duration::from_secs(120));
+        assert_eq!(sweeper.node_expire, Duration::from_secs(30));
+        assert_eq!(sweeper.lock_ttl_ms(), 120_000);
+        assert_eq!(sweeper.stale_before(1000.5), 880.5);
+
+        let cfg: PerceptCfg = toml::from_str("presence_sweep_interval_secs = 0").unwrap();
+        assert!(Sweeper::from_cfg(&cfg, &node).is_none());
+    }
+
+    #[test]
+    fn test_lock_ttl() {
+        let sweeper = Sweeper::new("test".to_string(), Duration::from_millis(100), Duration::from_secs(1), Duration::from_secs(30));
+        assert_eq!(sweeper.lock_ttl_ms(), 1000);
+    }
+}
@@ -0,0 +1,194 @@
+//!
+//! # TLS监听
+//! 配置了`tls_cert`与`tls_key`时，设备连接先完成TLS握手再进入CSoD握手；
+//! 再配置`tls_client_ca`则要求设备出示该CA签发的证书(mTLS)，证书CN必须与ping中的sn一致
+//!
+
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{
+    internal::pemfile,
+    AllowAnyAuthenticatedClient,
+    Certificate,
+    NoClientAuth,
+    PrivateKey,
+    RootCertStore,
+    ServerConfig,
+    Session,
+};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::common::config::PerceptionServiceConfig as PerceptCfg;
+
+fn open(path: &str) -> Result<BufReader<File>, String> {
+    File::open(path).map(BufReader::new).map_err(|e| format!("open {} failed: {}", path, e))
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>, String> {
+    match pemfile::certs(&mut open(path)?) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => Err(format!("no certificate in {}", path)),
+    }
+}
+
+/// 私钥支持PKCS#8与PKCS#1(RSA)格式
+fn load_key(path: &str) -> Result<PrivateKey, String> {
+    if let Ok(mut keys) = pemfile::pkcs8_private_keys(&mut open(path)?) {
+        if !keys.is_empty() {
+            return Ok(keys.remove(0));
+        }
+    }
+    if let Ok(mut keys) = pemfile::rsa_private_keys(&mut open(path)?) {
+        if !keys.is_empty() {
+            return Ok(keys.remove(0));
+        }
+    }
+    Err(format!("no private key in {}", path))
+}
+
+/// 按配置创建TLS acceptor，没有配置证书时返回None，使用明文TCP
+pub fn acceptor(cfg: &PerceptCfg) -> Result<Option<TlsAcceptor>, String> {
+    let (cert, key) = match (&cfg.tls_cert, &cfg.tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) => return Ok(None),
+        _ => return Err("tls_cert and tls_key must be configured together".to_string()),
+    };
+
+    let verifier = match &cfg.tls_client_ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            match roots.add_pem_file(&mut open(ca)?) {
+                Ok((valid, _)) if valid > 0 => AllowAnyAuthenticatedClient::new(roots),
+                _ => return Err(format!("no valid ca certificate in {}", ca)),
+            }
+        }
+        None => NoClientAuth::new(),
+    };
+    let mut config = ServerConfig::new(verifier);
+    config
+        .set_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(|e| format!("invalid certificate or key: {}", e))?;
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+/// 证书subject中的CN
+pub fn cert_cn(der: &[u8]) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+    let cn = cert.subject().iter_common_name().next()?.as_str().ok()?.to_string();
+    Some(cn)
+}
+
+/// 设备出示的证书的CN，没有出示证书时返回None
+pub fn peer_cn(stream: &TlsStream<TcpStream>) -> Option<String> {
+    let (_, session) = stream.get_ref();
+    let certs = session.get_peer_certificates()?;
+    cert_cn(&certs.first()?.0)
+}
+
+#[cfg(test)]
+mod tls_test {
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::runtime::Runtime;
+    use tokio_rustls::rustls::{internal::pemfile, ClientConfig};
+    use tokio_rustls::TlsConnector;
+
+    use crate::common::config::PerceptionServiceConfig as PerceptCfg;
+
+    use super::{acceptor, peer_cn};
+
+    fn issue(cn: &str, ca: Option<&Certificate>) -> (Certificate, String) {
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]);
+        params.distinguished_name.push(DnType::CommonName, cn);
+        if ca.is_none() {
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        }
+        let cert = Certificate::from_params(params).unwrap();
+        let pem = match ca {
+            Some(ca) => cert.serialize_pem_with_signer(ca).unwrap(),
+            None => cert.serialize_pem().unwrap(),
+        };
+        (cert, pem)
+    }
+
+    fn write(dir: &Path, name: &str, content: &str) -> String {
+        let path = dir.join(name);
+        fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn cfg(dir: &Path, client_ca: bool) -> PerceptCfg {
+        let (ca, ca_pem) = issue("csod test ca", None);
+        let (server, server_pem) = issue("localhost", Some(&ca));
+        let (device, device_pem) = issue("A0AA1B0001F3E", Some(&ca));
+        write(dir, "ca.pem", &ca_pem);
+        write(dir, "device.pem", &device_pem);
+        write(dir, "device.key", &device.serialize_private_key_pem());
+        let mut cfg: PerceptCfg = toml::from_str("").unwrap();
+        cfg.tls_cert = Some(write(dir, "server.pem", &server_pem));
+        cfg.tls_key = Some(write(dir, "server.key", &server.serialize_private_key_pem()));
+        if client_ca {
+            cfg.tls_client_ca = Some(dir.join("ca.pem").to_str().unwrap().to_string());
+        }
+        cfg
+    }
+
+    /// 建立一次TLS连接，返回服务端看到的证书CN，握手失败返回Err
+    fn connect(dir: &Path, cfg: &PerceptCfg, with_cert: bool) -> Result<Option<String>, ()> {
+        let acceptor = acceptor(cfg).unwrap().unwrap();
+        let mut client = ClientConfig::new();
+        let ca = fs::read(dir.join("ca.pem")).unwrap();
+        client.root_store.add_pem_file(&mut &ca[..]).unwrap();
+        if with_cert {
+            let cert = pemfile::certs(&mut &fs::read(dir.join("device.pem")).unwrap()[..]).unwrap();
+            let mut key = pemfile::pkcs8_private_keys(&mut &fs::read(dir.join("device.key")).unwrap()[..]).unwrap();
+            client.set_single_client_cert(cert, key.remove(0)).unwrap();
+        }
+        let connector = TlsConnector::from(Arc::new(client));
+
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = tokio::spawn(async move {
+                let stream = TcpStream::connect(addr).await.unwrap();
+                let domain = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+                connector.connect(domain, stream).await.is_ok()
+            });
+            let (stream, _) = listener.accept().await.unwrap();
+            let server = acceptor.accept(stream).await.map(|s| peer_cn(&s)).map_err(|_| ());
+            let _ = client.await;
+            server
+        })
+    }
+
+    #[test]
+    fn test_tls_client_cert() {
+        let dir = std::env::temp_dir().join(format!("csod_tls_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // 只有服务端证书
+        let server_only = cfg(&dir, false);
+        assert_eq!(connect(&dir, &server_only, false), Ok(None));
+
+        // mTLS: 服务端取得设备证书CN，不出示证书的设备握手失败
+        let mtls = cfg(&dir, true);
+        assert_eq!(connect(&dir, &mtls, true), Ok(Some("A0AA1B0001F3E".to_string())));
+        assert!(connect(&dir, &mtls, false).is_err());
+
+        // 证书与私钥必须同时配置
+        let mut half = cfg(&dir, false);
+        half.tls_key = None;
+        assert!(acceptor(&half).is_err());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,7 +1,6 @@
 #![allow(dead_code)]
+use csod::message::CsodMessage;
 use log::{error, info, warn};
-use serde_json;
-use serde_json::Value;
 use std::net::{TcpListener, TcpStream};
 use std::sync::{Arc, RwLock};
 use std::thread;
@@ -10,145 +9,6 @@ use std::time::Duration;
 use crate::device;
 use crate::messagequeue;
 
-#[derive(Debug, PartialEq)]
-pub enum MESTYPE {
-    HEARTBEAT,
-    RAWDATA,
-    INVAILD,
-}
-
-/// If the msg is a heartbeat ping type, return the sn
-///
-/// # Examples
-/// ```
-/// let ok_str = r#"{"type": "ping","sn": "123"}"#;
-/// assert_eq!(carte::is_heartbeat(&ok_str.to_string()), Some("123".to_string()));
-/// let err_str = r#"{"type": "ping","what": "error"}"#;
-/// assert_ne!(carte::is_heartbeat(&err_str.to_string()), Some("123".to_string()));
-/// ```
-fn is_heartbeat(msg: &String) -> Option<String> {
-    let sn: Option<String> = if let Ok(data) = serde_json::from_str(msg) {
-        match data {
-            Value::Object(t) => {
-                let msg_type: Option<String> = if t.contains_key("type") {
-                    match t.get("type").unwrap() {
-                        Value::String(v) => Some(v.to_string()),
-                        _ => None,
-                    }
-                } else {
-                    None
-                };
-
-                match msg_type {
-                    Some(s) if s == "ping" => {
-                        if t.contains_key("sn") {
-                            match t.get("sn").unwrap() {
-                                Value::String(v) => Some(v.to_string()),
-                                _ => None,
-                            }
-                        } else {
-                            None
-                        }
-                    }
-                    _ => None,
-                }
-            }
-            _ => None,
-        }
-    } else {
-        None
-    };
-    sn
-}
-
-#[test]
-fn is_heartbeat_test() {
-    let ok_str = r#"{"type": "ping","sn": "123"}"#;
-    assert_eq!(is_heartbeat(&ok_str.to_string()), Some("123".to_string()));
-    let err_str = r#"{"type": "ping","what": "error"}"#;
-    assert_ne!(is_heartbeat(&err_str.to_string()), Some("123".to_string()));
-}
-
-pub fn parse_sn(msg: &String) -> Option<String> {
-    let sn: Option<String> = if let Ok(data) = serde_json::from_str(msg) {
-        match data {
-            Value::Object(t) => {
-                if t.contains_key("sn") {
-                    match t.get("sn").unwrap() {
-                        Value::String(v) => Some(v.to_string()),
-                        _ => None,
-                    }
-                } else {
-                    None
-                }
-            }
-            _ => None,
-        }
-    } else {
-        None
-    };
-    sn
-}
-
-#[test]
-fn parse_sn_test() {
-    let ok_ping = r#"{"type": "ping","sn": "123"}"#;
-    assert_eq!(parse_sn(&ok_ping.to_string()), Some("123".to_string()));
-    let ok_rawdata = r#"{"type": "rawdata","sn": "145623"}"#;
-    assert_eq!(
-        parse_sn(&ok_rawdata.to_string()),
-        Some("145623".to_string())
-    );
-    let err0_sn = r#"{"type": "rawdata","snfuck": "145623"}"#;
-    assert_eq!(parse_sn(&err0_sn.to_string()), None);
-    let err1_sn = r#"{"what": "error"}"#;
-    assert_eq!(parse_sn(&err1_sn.to_string()), None);
-    let err2_sn = r#"{"what": "err"#;
-    assert_eq!(parse_sn(&err2_sn.to_string()), None);
-}
-
-///
-/// When the msg is a valid json and include the `type` field,
-/// but not the heartbeat type, it is a raw data.
-pub fn is_rawdata(msg: &String) -> bool {
-    if let Ok(data) = serde_json::from_str(msg) {
-        match data {
-            Value::Object(t) => {
-                if t.contains_key("type") {
-                    true
-                } else {
-                    false
-                }
-            }
-            _ => false,
-        }
-    } else {
-        false
-    }
-}
-
-pub fn parse_msg_type(msg: &String) -> MESTYPE {
-    if let Some(_) = is_heartbeat(msg) {
-        return MESTYPE::HEARTBEAT;
-    }
-    if is_rawdata(msg) {
-        return MESTYPE::RAWDATA;
-    }
-    MESTYPE::INVAILD
-}
-
-#[test]
-fn parse_msg_type_test() {
-    let ok_ping = r#"{"type": "ping","sn": "123"}"#;
-    assert_eq!(parse_msg_type(&ok_ping.to_string()), MESTYPE::HEARTBEAT);
-    let ok_rawdata = r#"{"type": "rawdata","sn": "123"}"#;
-    assert_eq!(parse_msg_type(&ok_rawdata.to_string()), MESTYPE::RAWDATA);
-    let err1_rawdata = r#"{"what": "error"}"#;
-    assert_eq!(parse_msg_type(&err1_rawdata.to_string()), MESTYPE::INVAILD);
-    let err2_rawdata = r#"{"what": "err"#;
-    assert_eq!(parse_msg_type(&err2_rawdata.to_string()), MESTYPE::INVAILD);
-}
-
 fn handle_client(stream: TcpStream, dsp: Arc<RwLock<device::DevicePool>>) {
     let mut mq = if let Ok(mq) = messagequeue::MQ::new("redis://127.0.0.1") {
         mq
@@ -159,9 +19,9 @@ fn handle_client(stream: TcpStream, dsp: Arc<RwLock<device::DevicePool>>) {
 
     for poll in 0..4 {
         if let Ok(msg) = device::read_line(&stream) {
-            if let Some(sn) = is_heartbeat(&msg) {
+            if let Ok(CsodMessage::Ping { sn, .. }) = CsodMessage::parse(&msg) {
                 info!("sn {}", sn);
-                pinsn = sn.clone().trim().to_string();
+                pinsn = sn.as_str().to_string();
                 break;
             }
         } else if poll == 3 {
@@ -224,9 +84,8 @@ fn handle_client(stream: TcpStream, dsp: Arc<RwLock<device::DevicePool>>) {
                 );
                 let msg_trim = msg.trim().to_string();
                 // Heartbeat message, update the ping timestamp.
-                let msg_t = parse_msg_type(&msg_trim);
-                match msg_t {
-                    MESTYPE::HEARTBEAT => {
+                match CsodMessage::parse(&msg_trim) {
+                    Ok(CsodMessage::Ping { .. }) => {
                         info!("heartbeat type");
                         let mut device_lock = device.write().unwrap();
                         if let Err(_) = device_lock.echo_pong() {
@@ -235,7 +94,7 @@ fn handle_client(stream: TcpStream, dsp: Arc<RwLock<device::DevicePool>>) {
                             device_lock.update_heartbeat_timestamp_auto();
                         }
                     }
-                    MESTYPE::RAWDATA => {
+                    Ok(_) => {
                         info!("rawdata type");
                         info!("push message to mq: {}", msg);
                         device.write().unwrap().update_heartbeat_timestamp_auto();
@@ -243,7 +102,7 @@ fn handle_client(stream: TcpStream, dsp: Arc<RwLock<device::DevicePool>>) {
                             error!("push MQ fail: {}", msg);
                         }
                     }
-                    MESTYPE::INVAILD => {
+                    Err(_) => {
                         warn!(
                             "invalid message from device({})",
                             device.read().unwrap().get_sn()
@@ -39,7 +39,7 @@
 //!     );
 //! ```
 #![allow(dead_code)]
-use crate::connection;
+use csod::message::CsodMessage;
 use crate::messagequeue::MQ;
 use log::{error, info, warn};
 use std::borrow::Borrow;
@@ -378,18 +378,18 @@ impl Device {
         // try to read every 0.5s
         for _ in 0..(timeout * 2) {
             match self.readline() {
-                Ok(msg) => match connection::parse_msg_type(&msg) {
-                    connection::MESTYPE::HEARTBEAT => {
+                Ok(msg) => match CsodMessage::parse(&msg) {
+                    Ok(CsodMessage::Ping { .. }) => {
                         if let Ok(_) = self.echo_pong() {
                             info!("get the heartbeat data: {}", msg);
                             self.update_heartbeat_timestamp_auto();
                         }
                     }
-                    connection::MESTYPE::RAWDATA => {
+                    Ok(_) => {
                         info!("get the rawdata: {}", msg);
                         return Ok(msg);
                     }
-                    connection::MESTYPE::INVAILD => {
+                    Err(_) => {
                         warn!("get the invalid data: {}", msg);
                     }
                 },
@@ -1,4 +1,4 @@
-use crate::connection;
+use csod::message::CsodMessage;
 use crate::device;
 use actix_web::{error, get, post, web, App, Error, HttpResponse, HttpServer};
 use bytes::BytesMut;
@@ -82,7 +82,10 @@ async fn push_get(
     match String::from_utf8(body.to_vec()) {
         Ok(body_string) => {
             println!("data: {}", body_string);
-            let sn = if let Some(sn) = connection::parse_sn(&body_string) {
+            let sn = if let Some(sn) = CsodMessage::parse(&body_string)
+                .ok()
+                .and_then(|m| m.sn().map(|sn| sn.to_string()))
+            {
                 sn
             } else {
                 warn!("invaild request, have no sn field");
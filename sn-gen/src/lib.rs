@@ -0,0 +1,265 @@
+//!
+//! # SN批量生成
+//! 按SN规范(见`csod::sn`)生成加盐SN批次，已发放的流水号区间记录在台账文件中，防止重复发放
+//!
+//! 台账文件每行一个批次: `产品ID,日期,起始流水号,截止流水号`
+//! ```text
+//! A0A,2020-11-12,1,100
+//! ```
+//!
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::NaiveDate;
+use csod::sn::{self, Product, SnError};
+use serde_json::json;
+
+/// 一个批次: 同一产品、同一日期下的流水号闭区间
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batch {
+    pub product: Product,
+    pub date: NaiveDate,
+    pub start: u16,
+    pub end: u16,
+}
+
+impl Batch {
+    pub fn new(product: Product, date: NaiveDate, start: u16, end: u16) -> Result<Batch, GenError> {
+        if start > end {
+            return Err(GenError::Args(format!("serial start {} > end {}", start, end)));
+        }
+        if sn::date_mark(date).is_none() {
+            return Err(GenError::Sn(SnError::InvalidDate(date.to_string())));
+        }
+        Ok(Batch { product, date, start, end })
+    }
+
+    pub fn overlaps(&self, other: &Batch) -> bool {
+        self.product == other.product && self.date == other.date
+            && self.start <= other.end && other.start <= self.end
+    }
+
+    pub fn count(&self) -> usize {
+        (self.end - self.start) as usize + 1
+    }
+}
+
+impl fmt::Display for Batch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{},{}", self.product, self.date, self.start, self.end)
+    }
+}
+
+impl FromStr for Batch {
+    type Err = GenError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.trim().split(',').map(|f| f.trim()).collect();
+        if fields.len() != 4 {
+            return Err(GenError::Ledger(format!("invalid ledger line {:?}", s)));
+        }
+        let product = fields[0].parse().map_err(GenError::Sn)?;
+        let date = parse_date(fields[1])?;
+        let start = parse_serial(fields[2])?;
+        let end = parse_serial(fields[3])?;
+        Batch::new(product, date, start, end)
+    }
+}
+
+#[derive(Debug)]
+pub enum GenError {
+    Args(String),
+    Sn(SnError),
+    Ledger(String),
+    /// 与台账中已发放的批次重叠
+    Overlap(Batch),
+    Io(io::Error),
+}
+
+impl fmt::Display for GenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GenError::Args(e) => write!(f, "{}", e),
+            GenError::Sn(e) => write!(f, "{}", e),
+            GenError::Ledger(e) => write!(f, "{}", e),
+            GenError::Overlap(b) => write!(f, "serial range overlaps issued batch {}", b),
+            GenError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for GenError {}
+
+impl From<io::Error> for GenError {
+    fn from(e: io::Error) -> Self {
+        GenError::Io(e)
+    }
+}
+
+/// 解析`YYYY-MM-DD`，校验大小月与闰年
+pub fn parse_date(s: &str) -> Result<NaiveDate, GenError> {
+    NaiveDate::parse_from_str(s.trim(), "%Y-%m-%d")
+        .map_err(|_| GenError::Sn(SnError::InvalidDate(s.to_string())))
+}
+
+/// 流水号支持十进制和`0x`前缀的十六进制
+pub fn parse_serial(s: &str) -> Result<u16, GenError> {
+    let s = s.trim();
+    let v = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u16::from_str_radix(hex, 16)
+    } else {
+        s.parse::<u16>()
+    };
+    v.map_err(|_| GenError::Args(format!("invalid serial {:?}, expect 0-65535", s)))
+}
+
+/// 生成一个批次的全部SN
+pub fn generate_batch(batch: &Batch, salt: &str) -> Result<Vec<String>, GenError> {
+    (batch.start..=batch.end)
+        .map(|serial| sn::generate(batch.product, batch.date, serial, salt).map_err(GenError::Sn))
+        .collect()
+}
+
+/// 已发放批次台账
+pub struct Ledger {
+    path: PathBuf,
+    batches: Vec<Batch>,
+}
+
+impl Ledger {
+    /// 台账文件不存在时视为空台账
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Ledger, GenError> {
+        let path = path.as_ref().to_path_buf();
+        let mut batches = vec![];
+        match File::open(&path) {
+            Ok(f) => {
+                for line in BufReader::new(f).lines() {
+                    let line = line?;
+                    if line.trim().is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    batches.push(line.parse()?);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(Ledger { path, batches })
+    }
+
+    pub fn batches(&self) -> &[Batch] {
+        &self.batches
+    }
+
+    pub fn check(&self, batch: &Batch) -> Result<(), GenError> {
+        match self.batches.iter().find(|b| b.overlaps(batch)) {
+            Some(b) => Err(GenError::Overlap(b.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// 校验无重叠后追加到台账文件
+    pub fn record(&mut self, batch: &Batch) -> Result<(), GenError> {
+        self.check(batch)?;
+        let mut f = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(f, "{}", batch)?;
+        self.batches.push(batch.clone());
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// 每行一个SN，与generate-salt-sn.py输出一致，可直接给mxft.py烧录使用
+    Csv,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = GenError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            _ => Err(GenError::Args(format!("unknown format {:?}, expect csv or json", s))),
+        }
+    }
+}
+
+pub fn render(batch: &Batch, sns: &[String], format: Format) -> String {
+    match format {
+        Format::Csv => sns.iter().map(|s| format!("{}\n", s)).collect(),
+        Format::Json => {
+            let items: Vec<_> = sns.iter().zip(batch.start..=batch.end).map(|(s, serial)| json!({
+                "sn": s,
+                "product": batch.product.as_str(),
+                "date": batch.date.to_string(),
+                "serial": serial,
+            })).collect();
+            format!("{}\n", serde_json::to_string_pretty(&items).unwrap_or_default())
+        }
+    }
+}
+
+#[cfg(test)]
+mod sn_gen_test {
+    use std::fs;
+
+    use csod::sn;
+
+    use super::{generate_batch, parse_date, parse_serial, render, Batch, Format, GenError, Ledger};
+
+    fn batch(s: &str) -> Batch {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(parse_date("2021-02-29").is_err());
+        assert!(parse_date("2020-02-29").is_ok());
+        assert!(parse_date("2020-13-01").is_err());
+        assert_eq!(parse_serial("0x00ff").unwrap(), 255);
+        assert!(parse_serial("65536").is_err());
+        assert!("A0A,2020-11-12,10,1".parse::<Batch>().is_err());
+        assert!("A9A,2020-11-12,1,10".parse::<Batch>().is_err());
+        assert!("A0A,2019-11-12,1,10".parse::<Batch>().is_err());
+    }
+
+    #[test]
+    fn test_generate_batch() {
+        let b = batch("A0A,2020-11-12,9,11");
+        let sns = generate_batch(&b, "anbwscx").unwrap();
+        assert_eq!(sns.len(), b.count());
+        assert_eq!(sns[1], "A0AABC000ACD4");
+        for s in &sns {
+            assert!(sn::verify(s, "anbwscx").is_ok());
+        }
+        assert_eq!(render(&b, &sns, Format::Csv).lines().count(), 3);
+        let json: serde_json::Value = serde_json::from_str(&render(&b, &sns, Format::Json)).unwrap();
+        assert_eq!(json[1]["serial"], 10);
+    }
+
+    #[test]
+    fn test_ledger_overlap() {
+        let path = std::env::temp_dir().join(format!("sn-gen-ledger-{}.csv", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut ledger = Ledger::load(&path).unwrap();
+        ledger.record(&batch("A0A,2020-11-12,1,100")).unwrap();
+        // 不同产品或不同日期不冲突
+        ledger.record(&batch("A0B,2020-11-12,1,100")).unwrap();
+        ledger.record(&batch("A0A,2020-11-13,1,100")).unwrap();
+
+        let ledger = Ledger::load(&path).unwrap();
+        assert_eq!(ledger.batches().len(), 3);
+        assert!(matches!(ledger.check(&batch("A0A,2020-11-12,100,200")), Err(GenError::Overlap(_))));
+        assert!(ledger.check(&batch("A0A,2020-11-12,101,200")).is_ok());
+
+        let _ = fs::remove_file(&path);
+    }
+}
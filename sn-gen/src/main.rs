@@ -0,0 +1,102 @@
+//!
+//! # sn-gen
+//! 批量生成加盐SN，替代doc/generate-salt-sn.py
+//!
+//! ```sh
+//! sn-gen --product A0A --date 2020-11-12 --serial 1-100 --salt ${salt} --format csv --output sn.csv
+//! ```
+//!
+
+use std::env;
+use std::fs;
+use std::process;
+
+use sn_gen::{generate_batch, parse_date, parse_serial, render, Batch, Format, GenError, Ledger};
+
+const USAGE: &str = "\
+usage: sn-gen --product <ID> --date <YYYY-MM-DD> --serial <START-END> [options]
+
+    --product <ID>        产品ID: A0A, A0B, A1C, A1D, A2E
+    --date <YYYY-MM-DD>   生产日期, 2020-01-01 ~ 2045-12-31
+    --serial <START-END>  流水号闭区间, 0-65535, 支持0x前缀
+    --salt <SALT>         加盐MD5的salt, 默认读取环境变量SN_SALT
+    --format <csv|json>   输出格式, 默认csv
+    --ledger <PATH>       已发放批次台账, 默认sn-ledger.csv
+    --output <PATH>       输出文件, 默认输出到stdout";
+
+struct Args {
+    batch: Batch,
+    salt: String,
+    format: Format,
+    ledger: String,
+    output: Option<String>,
+}
+
+fn parse_args(argv: &[String]) -> Result<Args, GenError> {
+    let mut product = None;
+    let mut date = None;
+    let mut serial = None;
+    let mut salt = env::var("SN_SALT").ok();
+    let mut format = Format::Csv;
+    let mut ledger = "sn-ledger.csv".to_string();
+    let mut output = None;
+
+    let mut it = argv.iter();
+    while let Some(flag) = it.next() {
+        let mut value = || it.next().cloned().ok_or_else(|| GenError::Args(format!("{} needs a value", flag)));
+        match flag.as_str() {
+            "--product" => product = Some(value()?.parse().map_err(GenError::Sn)?),
+            "--date" => date = Some(parse_date(&value()?)?),
+            "--serial" => {
+                let v = value()?;
+                let (start, end) = match v.find('-') {
+                    Some(i) => (parse_serial(&v[..i])?, parse_serial(&v[i + 1..])?),
+                    None => (parse_serial(&v)?, parse_serial(&v)?),
+                };
+                serial = Some((start, end));
+            }
+            "--salt" => salt = Some(value()?),
+            "--format" => format = value()?.parse()?,
+            "--ledger" => ledger = value()?,
+            "--output" => output = Some(value()?),
+            _ => return Err(GenError::Args(format!("unknown argument {:?}", flag))),
+        }
+    }
+
+    let missing = |name: &str| GenError::Args(format!("missing {}", name));
+    let (start, end) = serial.ok_or_else(|| missing("--serial"))?;
+    let batch = Batch::new(product.ok_or_else(|| missing("--product"))?, date.ok_or_else(|| missing("--date"))?, start, end)?;
+    let salt = salt.ok_or_else(|| missing("--salt or SN_SALT"))?;
+    Ok(Args { batch, salt, format, ledger, output })
+}
+
+fn run(args: Args) -> Result<(), GenError> {
+    let mut ledger = Ledger::load(&args.ledger)?;
+    ledger.check(&args.batch)?;
+
+    let sns = generate_batch(&args.batch, &args.salt)?;
+    let out = render(&args.batch, &sns, args.format);
+    match &args.output {
+        Some(path) => fs::write(path, out)?,
+        None => print!("{}", out),
+    }
+
+    // 输出成功后才记入台账
+    ledger.record(&args.batch)?;
+    eprintln!("generated {} sn ({}), recorded in {}", sns.len(), args.batch, args.ledger);
+    Ok(())
+}
+
+fn main() {
+    let argv: Vec<String> = env::args().skip(1).collect();
+    if argv.is_empty() || argv.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let result = parse_args(&argv).and_then(run);
+    if let Err(e) = result {
+        eprintln!("sn-gen: {}\n\n{}", e, USAGE);
+        process::exit(1);
+    }
+}
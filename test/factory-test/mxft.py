@@ -1,6 +1,7 @@
 #-*- coding: utf-8 -*-
 
 import os
+import datetime
 import serial
 
 input_csv = input("输入sn文件# ")
@@ -30,9 +31,10 @@ def del_first_sn(fp: str):
 
 
 
-def dump_sn(fp: str, sn) -> str:
+def dump_sn(fp: str, sn, mac: str = '') -> str:
+	# 每行sn,mac,烧写日期，用于导入出厂登记(device-appliction-interface import)
 	with open(fp, 'a') as f:
-		f.write(sn+'\n')
+		f.write(f'{sn},{mac},{datetime.date.today().isoformat()}\n')
 
 def wirte_device(sn: str):
 	return 